@@ -0,0 +1,248 @@
+//! The queues under the early packet and listener packet channels.
+//!
+//! `UdpListener` takes the queue by its `ChannelBackend` parameter:
+//! `FuturesChannel` by default, `TokioChannel` with the `tokio` feature,
+//! or `StdChannel` for threads without an async executor.
+//! Other queues plug in by implementing `ChannelBackend` on them.
+//!
+//! Only `TokioChannel` is behind a feature, as the only backend pulling a dependency of its own:
+//! `FuturesChannel` is the default of the type parameter and `futures` is needed anyway,
+//! and `StdChannel` is `std` alone, so gating either would not shrink the build.
+
+use std::{
+    pin::Pin,
+    sync::{
+        mpsc::{self as std_mpsc, RecvTimeoutError, TryRecvError, TrySendError},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{channel::mpsc, Stream};
+
+use super::{blocking::recv_timeout, SendRes};
+
+/// Sending half of a bounded queue.
+pub trait PktSender<T>: Send + Sync {
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>>;
+}
+
+/// Receiving half of a bounded queue.
+pub trait PktReceiver<T>: Send + Sync {
+    fn try_recv(&mut self) -> Result<T, TryRecvError>;
+
+    /// Block the current thread until an item arrives or `timeout` passes; `None` waits forever.
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<T, RecvTimeoutError>;
+
+    /// Refuse further items while keeping the queued ones.
+    ///
+    /// `std::sync::mpsc` only closes on drop and does nothing here.
+    fn close(&mut self);
+}
+
+/// Makes the bounded queues of a listener and its connections.
+pub trait ChannelBackend: Send + Sync + 'static {
+    type Sender<T: Send + 'static>: PktSender<T> + Clone;
+    type Receiver<T: Send + 'static>: PktReceiver<T>;
+
+    /// A queue of at least `buffer + 1` items.
+    fn channel<T: Send + 'static>(buffer: usize) -> (Self::Sender<T>, Self::Receiver<T>);
+}
+
+/// `futures::channel::mpsc`, whose receivers are `Stream`s.
+///
+/// Each sender gets one slot on top of the buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuturesChannel;
+impl ChannelBackend for FuturesChannel {
+    type Sender<T: Send + 'static> = mpsc::Sender<T>;
+    type Receiver<T: Send + 'static> = mpsc::Receiver<T>;
+
+    fn channel<T: Send + 'static>(buffer: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        mpsc::channel(buffer)
+    }
+}
+
+/// `std::sync::mpsc::sync_channel`, received by blocking the current thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdChannel;
+impl ChannelBackend for StdChannel {
+    type Sender<T: Send + 'static> = std_mpsc::SyncSender<T>;
+    type Receiver<T: Send + 'static> = StdReceiver<T>;
+
+    fn channel<T: Send + 'static>(buffer: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        // The slot a futures sender adds, so that a buffer of 0 is not a rendezvous channel.
+        let (sender, receiver) = std_mpsc::sync_channel(buffer + 1);
+        (sender, StdReceiver(Mutex::new(receiver)))
+    }
+}
+
+/// `tokio::sync::mpsc`, whose receivers integrate with the tokio runtime by `recv().await`.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioChannel;
+#[cfg(feature = "tokio")]
+impl ChannelBackend for TokioChannel {
+    type Sender<T: Send + 'static> = tokio::sync::mpsc::Sender<T>;
+    type Receiver<T: Send + 'static> = tokio::sync::mpsc::Receiver<T>;
+
+    fn channel<T: Send + 'static>(buffer: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        // Like `StdChannel`; tokio also rejects a buffer of 0.
+        tokio::sync::mpsc::channel(buffer + 1)
+    }
+}
+
+/// `std::sync::mpsc::Receiver` made `Sync` so that a listener can still be shared between threads.
+///
+/// Every receive takes `&mut self`, so the lock is never contended.
+#[derive(Debug)]
+pub struct StdReceiver<T>(Mutex<std_mpsc::Receiver<T>>);
+impl<T> StdReceiver<T> {
+    pub fn get_mut(&mut self) -> &mut std_mpsc::Receiver<T> {
+        // Never locked, so never poisoned.
+        self.0.get_mut().unwrap()
+    }
+
+    pub fn into_inner(self) -> std_mpsc::Receiver<T> {
+        self.0.into_inner().unwrap()
+    }
+}
+
+/// Map the outcome of `PktSender::try_send` to `SendRes`, taking the packet back out of `item`.
+pub fn send_res<T>(
+    res: Result<(), TrySendError<T>>,
+    into_buf: impl FnOnce(T) -> Vec<u8>,
+) -> SendRes {
+    match res {
+        Ok(()) => SendRes::Ok,
+        Err(TrySendError::Full(item)) => SendRes::Full(into_buf(item)),
+        Err(TrySendError::Disconnected(item)) => SendRes::NotExist(into_buf(item)),
+    }
+}
+
+impl<T: Send> PktSender<T> for mpsc::Sender<T> {
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        mpsc::Sender::try_send(self, item).map_err(|e| match e.is_full() {
+            true => TrySendError::Full(e.into_inner()),
+            // The only other failure of a bounded channel.
+            false => TrySendError::Disconnected(e.into_inner()),
+        })
+    }
+}
+impl<T: Send> PktReceiver<T> for mpsc::Receiver<T> {
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        mpsc::Receiver::try_recv(self).map_err(|e| match e {
+            mpsc::TryRecvError::Empty => TryRecvError::Empty,
+            mpsc::TryRecvError::Closed => TryRecvError::Disconnected,
+        })
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<T, RecvTimeoutError> {
+        recv_timeout(|cx| Pin::new(&mut *self).poll_next(cx), timeout)
+    }
+
+    fn close(&mut self) {
+        mpsc::Receiver::close(self);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Send> PktSender<T> for tokio::sync::mpsc::Sender<T> {
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        use tokio::sync::mpsc::error::TrySendError as TokioTrySendError;
+        tokio::sync::mpsc::Sender::try_send(self, item).map_err(|e| match e {
+            TokioTrySendError::Full(item) => TrySendError::Full(item),
+            TokioTrySendError::Closed(item) => TrySendError::Disconnected(item),
+        })
+    }
+}
+#[cfg(feature = "tokio")]
+impl<T: Send> PktReceiver<T> for tokio::sync::mpsc::Receiver<T> {
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        use tokio::sync::mpsc::error::TryRecvError as TokioTryRecvError;
+        tokio::sync::mpsc::Receiver::try_recv(self).map_err(|e| match e {
+            TokioTryRecvError::Empty => TryRecvError::Empty,
+            TokioTryRecvError::Disconnected => TryRecvError::Disconnected,
+        })
+    }
+
+    /// Parks the thread rather than `blocking_recv`, which panics within a runtime.
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<T, RecvTimeoutError> {
+        recv_timeout(|cx| self.poll_recv(cx), timeout)
+    }
+
+    fn close(&mut self) {
+        tokio::sync::mpsc::Receiver::close(self);
+    }
+}
+
+impl<T: Send> PktSender<T> for std_mpsc::SyncSender<T> {
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        std_mpsc::SyncSender::try_send(self, item)
+    }
+}
+impl<T: Send> PktReceiver<T> for StdReceiver<T> {
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.get_mut().try_recv()
+    }
+
+    fn recv_timeout(&mut self, timeout: Option<Duration>) -> Result<T, RecvTimeoutError> {
+        match timeout {
+            Some(timeout) => self.get_mut().recv_timeout(timeout),
+            None => self
+                .get_mut()
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        }
+    }
+
+    fn close(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One packet fits, the next is refused, and dropping the receiver disconnects the sender.
+    fn check<B: ChannelBackend>() {
+        let (mut sender, mut receiver) = B::channel::<Vec<u8>>(0);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert!(matches!(
+            send_res(sender.try_send(vec![1]), |buf| buf),
+            SendRes::Ok
+        ));
+        assert!(matches!(
+            send_res(sender.try_send(vec![2]), |buf| buf),
+            SendRes::Full(buf) if buf == [2]
+        ));
+        assert_eq!(
+            receiver.recv_timeout(Some(Duration::from_millis(10))),
+            Ok(vec![1])
+        );
+        assert_eq!(
+            receiver.recv_timeout(Some(Duration::from_millis(10))),
+            Err(RecvTimeoutError::Timeout)
+        );
+        drop(receiver);
+        assert!(matches!(
+            send_res(sender.try_send(vec![3]), |buf| buf),
+            SendRes::NotExist(buf) if buf == [3]
+        ));
+    }
+
+    #[test]
+    fn test_futures_backend() {
+        check::<FuturesChannel>();
+    }
+
+    #[test]
+    fn test_std_backend() {
+        check::<StdChannel>();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_backend() {
+        check::<TokioChannel>();
+    }
+}
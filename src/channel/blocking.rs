@@ -0,0 +1,52 @@
+//! Blocking receive from the queues of `FuturesChannel` and `TokioChannel` by polling them on a parked thread.
+//!
+//! `StdChannel` needs none of this: its queues block in `std::sync::mpsc` itself.
+
+use std::{
+    sync::{mpsc::RecvTimeoutError, Arc},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// Unparks the thread blocked in `recv_timeout`.
+struct ThreadWaker(Thread);
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Take the next item by `poll_next`, parking the current thread until it arrives or `timeout` passes.
+///
+/// `None` waits forever.
+pub fn recv_timeout<T>(
+    mut poll_next: impl FnMut(&mut Context<'_>) -> Poll<Option<T>>,
+    timeout: Option<Duration>,
+) -> Result<T, RecvTimeoutError> {
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match poll_next(&mut cx) {
+            Poll::Ready(Some(item)) => return Ok(item),
+            Poll::Ready(None) => return Err(RecvTimeoutError::Disconnected),
+            Poll::Pending => (),
+        }
+        // A spurious wake-up only polls again.
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                thread::park_timeout(deadline - now);
+            }
+            None => thread::park(),
+        }
+    }
+}
@@ -1,110 +1,549 @@
-use std::sync::{Arc, RwLock, Weak};
+use std::{
+    mem,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{RecvError, RecvTimeoutError, TryRecvError},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use futures::channel::mpsc;
+use futures::Stream;
 
-use crate::recv::FourTuple;
+use crate::{demux::Demux, recv::FourTuple};
 
-use super::early_pkt_map::EarlyPktMap;
+pub use super::early_pkt_map::{
+    ConnEntry, EarlyPktSender, EarlyPktStore, EarlyPktStoreFactory, InsertErr, RekeyErr,
+};
+use super::{
+    backend::{send_res, ChannelBackend, FuturesChannel, PktReceiver, PktSender},
+    conn_closed::{
+        conn_closed_channel, ConnClosedEvent, ConnClosedEvents, ConnClosedReason, ConnClosedSender,
+    },
+    early_pkt_map::EarlyPktMap,
+    recently_closed::RecentlyClosed,
+};
 
-pub struct ConnChan {
-    early_pkt_map: Weak<RwLock<EarlyPktMap>>,
+pub struct ConnChan<B: ChannelBackend = FuturesChannel> {
+    early_pkt_map: Weak<EarlyPktMap>,
     early_pkt_key: FourTuple,
-    early_pkt_recv: mpsc::Receiver<Vec<u8>>,
-    listener_pkt_send: mpsc::Sender<(FourTuple, Vec<u8>)>,
+    early_pkt_generation: u64,
+    early_pkt_recv: EarlyPktRecv<B>,
+    listener_pkt_send: B::Sender<(FourTuple, Vec<u8>)>,
+    budget: EarlyPktBudget,
+    conn_closed_send: Option<ConnClosedSender>,
+    recently_closed: Option<Arc<RecentlyClosed>>,
+    demux: Option<Arc<Demux>>,
+    /// Whether this channel owns the entry in the early packet map, i.e. is not a clone.
+    is_owner: bool,
+    /// Whether `early_pkt_recv` was moved out by `take_early_pkt_recv`.
+    early_pkt_recv_taken: bool,
 }
-impl ConnChan {
+impl<B: ChannelBackend> ConnChan<B> {
+    /// A channel to the same listener that neither receives early packets nor owns the entry in the early packet map.
+    ///
+    /// Dropping it leaves the entry to this channel.
+    pub fn clone_detached(&self) -> Self {
+        Self {
+            early_pkt_map: Weak::clone(&self.early_pkt_map),
+            early_pkt_key: self.early_pkt_key,
+            early_pkt_generation: self.early_pkt_generation,
+            early_pkt_recv: self.early_pkt_recv.detached(),
+            listener_pkt_send: self.listener_pkt_send.clone(),
+            budget: self.budget.clone(),
+            conn_closed_send: None,
+            recently_closed: None,
+            demux: None,
+            is_owner: false,
+            early_pkt_recv_taken: false,
+        }
+    }
+
     pub fn remove(&self) {
+        if !self.is_owner {
+            return;
+        }
         let Some(map) = self.early_pkt_map.upgrade() else {
             return;
         };
-        map.write().unwrap().remove(&self.early_pkt_key);
+        // A newer connection under the same four-tuple owns the entry if this one was evicted.
+        let removed = map.remove_generation(&self.early_pkt_key, self.early_pkt_generation);
+        if !removed {
+            return;
+        }
+        if let Some(recently_closed) = &self.recently_closed {
+            recently_closed.insert(self.early_pkt_key);
+        }
+        if let Some(demux) = &self.demux {
+            demux.forget(&self.early_pkt_key);
+        }
+        if let Some(conn_closed_send) = &self.conn_closed_send {
+            conn_closed_send.send(ConnClosedEvent {
+                four_tuple: self.early_pkt_key,
+                reason: ConnClosedReason::Dropped,
+            });
+        }
+    }
+
+    /// Route early packets of `four_tuple` to this channel instead of the current four-tuple.
+    ///
+    /// A channel from `clone_detached` owns no entry and returns `RekeyErr::NotExist`.
+    pub fn rekey(&mut self, four_tuple: FourTuple) -> Result<(), RekeyErr> {
+        if !self.is_owner {
+            return Err(RekeyErr::NotExist);
+        }
+        if let Some(map) = self.early_pkt_map.upgrade() {
+            map.rekey(&self.early_pkt_key, self.early_pkt_generation, four_tuple)?;
+        }
+        if let Some(demux) = &self.demux {
+            demux.rekey(&self.early_pkt_key, four_tuple);
+        }
+        self.early_pkt_key = four_tuple;
+        Ok(())
     }
 
-    pub fn recv_early_pkt(&self) -> &mpsc::Receiver<Vec<u8>> {
+    /// Whether the listener that created this channel still exists.
+    pub fn is_listener_alive(&self) -> bool {
+        self.early_pkt_map.strong_count() > 0
+    }
+
+    pub fn recv_early_pkt(&self) -> &EarlyPktRecv<B> {
         &self.early_pkt_recv
     }
 
-    pub fn send_listener_pkt(&mut self, four_tuple: FourTuple, buf: Vec<u8>) -> SendRes {
-        match self.listener_pkt_send.try_send((four_tuple, buf)) {
-            Ok(()) => SendRes::Ok,
-            Err(e) => {
-                if e.is_full() {
-                    SendRes::Full(e.into_inner().1)
-                } else if e.is_disconnected() {
-                    SendRes::NotExist(e.into_inner().1)
-                } else {
-                    unreachable!()
-                }
-            }
+    pub fn recv_early_pkt_mut(&mut self) -> &mut EarlyPktRecv<B> {
+        &mut self.early_pkt_recv
+    }
+
+    /// Move the receiver of early packets out, leaving one that never receives.
+    ///
+    /// `None` if it was taken before or this channel is from `clone_detached`.
+    pub fn take_early_pkt_recv(&mut self) -> Option<EarlyPktRecv<B>> {
+        if !self.is_owner || self.early_pkt_recv_taken {
+            return None;
+        }
+        self.early_pkt_recv_taken = true;
+        let detached = self.early_pkt_recv.detached();
+        Some(mem::replace(&mut self.early_pkt_recv, detached))
+    }
+
+    /// Deliver a packet of `four_tuple` straight into the early packet channel of the connection owning it,
+    /// or into the listener packet channel if no connection does.
+    pub fn redirect(&mut self, four_tuple: FourTuple, buf: Vec<u8>) -> SendRes {
+        let Some(map) = self.early_pkt_map.upgrade() else {
+            return SendRes::NotExist(buf);
+        };
+        match send_early_pkt(&map, &self.budget, &four_tuple, buf) {
+            SendRes::NotExist(buf) => self.send_listener_pkt(four_tuple, buf),
+            res => res,
         }
     }
+
+    pub fn send_listener_pkt(&mut self, four_tuple: FourTuple, buf: Vec<u8>) -> SendRes {
+        let res = PktSender::try_send(&mut self.listener_pkt_send, (four_tuple, buf));
+        send_res(res, |(_, buf)| buf)
+    }
 }
-impl Drop for ConnChan {
+impl<B: ChannelBackend> Drop for ConnChan<B> {
     fn drop(&mut self) {
         self.remove();
     }
 }
 
-pub struct ListenerChan {
-    early_pkt_map: Arc<RwLock<EarlyPktMap>>,
-    listener_pkt_send: mpsc::Sender<(FourTuple, Vec<u8>)>,
-    listener_pkt_recv: mpsc::Receiver<(FourTuple, Vec<u8>)>,
+/// Receiver of early packets, releasing their bytes from the budget of the listener as they are taken.
+pub struct EarlyPktRecv<B: ChannelBackend = FuturesChannel> {
+    recv: B::Receiver<Vec<u8>>,
+    queued_bytes: Arc<AtomicUsize>,
+    /// Packets queued in `recv`; also held by the entry in the early packet map while it lives.
+    pending: Arc<AtomicUsize>,
+}
+impl<B: ChannelBackend> EarlyPktRecv<B> {
+    /// Whether an early packet is queued, without taking it.
+    pub fn has_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire) > 0
+    }
+
+    /// Whether no early packet will ever arrive: the entry in the early packet map is gone and every packet is taken.
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.pending) == 1 && !self.has_pending()
+    }
+
+    pub fn try_recv(&mut self) -> Result<Vec<u8>, TryRecvError> {
+        let pkt = self.recv.try_recv()?;
+        self.dequeued(&pkt);
+        Ok(pkt)
+    }
+
+    /// Block the current thread until an early packet arrives, without an async executor.
+    ///
+    /// Fails once the listener is gone and every packet is taken.
+    pub fn recv(&mut self) -> Result<Vec<u8>, RecvError> {
+        self.recv_within(None).map_err(|_| RecvError)
+    }
+
+    /// `recv` that gives up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        self.recv_within(Some(timeout))
+    }
+
+    fn recv_within(&mut self, timeout: Option<Duration>) -> Result<Vec<u8>, RecvTimeoutError> {
+        let pkt = self.recv.recv_timeout(timeout)?;
+        self.dequeued(&pkt);
+        Ok(pkt)
+    }
+
+    /// A receiver that never receives, sharing the budget of this one.
+    fn detached(&self) -> Self {
+        let (_, recv) = B::channel(0);
+        Self {
+            recv,
+            queued_bytes: Arc::clone(&self.queued_bytes),
+            pending: Arc::default(),
+        }
+    }
+
+    fn dequeued(&self, pkt: &[u8]) {
+        self.queued_bytes.fetch_sub(pkt.len(), Ordering::Relaxed);
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+impl Stream for EarlyPktRecv<FuturesChannel> {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.recv).poll_next(cx);
+        if let Poll::Ready(Some(pkt)) = &poll {
+            self.dequeued(pkt);
+        }
+        poll
+    }
+}
+impl<B: ChannelBackend> Drop for EarlyPktRecv<B> {
+    fn drop(&mut self) {
+        // Release the packets nobody will take.
+        PktReceiver::close(&mut self.recv);
+        while let Ok(pkt) = PktReceiver::try_recv(&mut self.recv) {
+            self.dequeued(&pkt);
+        }
+    }
+}
+
+pub struct ListenerChan<B: ChannelBackend = FuturesChannel> {
+    early_pkt_map: Arc<EarlyPktMap>,
+    listener_pkt_send: B::Sender<(FourTuple, Vec<u8>)>,
+    listener_pkt_recv: B::Receiver<(FourTuple, Vec<u8>)>,
+    conn_closed_send: Option<ConnClosedSender>,
+    conn_closed_recv: Option<ConnClosedEvents>,
+    recently_closed: Option<Arc<RecentlyClosed>>,
+    demux: Option<Arc<Demux>>,
+    early_pkt_buffer: usize,
+    budget: EarlyPktBudget,
 }
-impl ListenerChan {
-    pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(1);
+impl<B: ChannelBackend> ListenerChan<B> {
+    /// `max_conns` caps the number of early packet channels alive at the same time,
+    /// and `max_conns_per_ip` caps that per remote IP.
+    pub fn new(max_conns: Option<usize>, max_conns_per_ip: Option<usize>) -> Self {
+        let (sender, receiver) = B::channel(1);
         Self {
-            early_pkt_map: Arc::new(RwLock::new(EarlyPktMap::new())),
+            early_pkt_map: Arc::new(EarlyPktMap::new(max_conns, max_conns_per_ip)),
             listener_pkt_send: sender,
             listener_pkt_recv: receiver,
+            conn_closed_send: None,
+            conn_closed_recv: None,
+            recently_closed: None,
+            demux: None,
+            early_pkt_buffer: 1,
+            budget: EarlyPktBudget::default(),
         }
     }
 
-    pub fn create_early_pkt_chan(&self, four_tuple: FourTuple) -> ConnChan {
-        let (sender, receiver) = mpsc::channel(1);
-        self.early_pkt_map
-            .write()
-            .unwrap()
-            .insert(four_tuple, sender);
-        ConnChan {
+    /// Refuse early packets once the early packets queued in every channel sum up to more than `bytes`.
+    pub fn with_early_pkt_budget(mut self, bytes: usize) -> Self {
+        self.budget.limit = Some(bytes);
+        self
+    }
+
+    /// Buffer up to `buffer` packets in every early packet channel instead of one.
+    pub fn with_early_pkt_buffer(mut self, buffer: usize) -> Self {
+        self.early_pkt_buffer = buffer;
+        self
+    }
+
+    /// Remember the four-tuples of channels closed within the last `window`; see `is_recently_closed`.
+    pub fn with_recently_closed(mut self, window: Duration) -> Self {
+        self.recently_closed = Some(Arc::new(RecentlyClosed::new(window)));
+        self
+    }
+
+    /// Whether the channel of `four_tuple` was dropped or evicted within the window of `with_recently_closed`.
+    pub fn is_recently_closed(&self, four_tuple: &FourTuple) -> bool {
+        self.recently_closed
+            .as_ref()
+            .is_some_and(|recently_closed| recently_closed.contains(four_tuple))
+    }
+
+    /// Forget the key in `demux` of every channel as soon as it is dropped or evicted.
+    pub fn with_demux(mut self, demux: Arc<Demux>) -> Self {
+        self.demux = Some(demux);
+        self
+    }
+
+    /// Keep the early packet channels in stores made by `new_store`, one per shard, instead of `HashMap`s.
+    ///
+    /// Must be called before any early packet channel is created.
+    pub fn with_early_pkt_store(mut self, new_store: impl Fn() -> Box<dyn EarlyPktStore>) -> Self {
+        Arc::get_mut(&mut self.early_pkt_map)
+            .expect("no early packet channel is created yet")
+            .set_stores(new_store);
+        self
+    }
+
+    /// Report closed early packet channels on a queue holding up to `capacity` events.
+    pub fn with_conn_closed_events(mut self, capacity: usize) -> Self {
+        let (sender, receiver) = conn_closed_channel(capacity);
+        self.conn_closed_send = Some(sender);
+        self.conn_closed_recv = Some(receiver);
+        self
+    }
+
+    pub fn create_early_pkt_chan(&self, four_tuple: FourTuple) -> Result<ConnChan<B>, InsertErr> {
+        let (sender, receiver) = B::channel(self.early_pkt_buffer);
+        let pending = Arc::default();
+        let generation = self.early_pkt_map.try_insert(
+            four_tuple,
+            Box::new(sender) as EarlyPktSender,
+            Arc::clone(&pending),
+        )?;
+        Ok(ConnChan {
             early_pkt_map: Arc::downgrade(&self.early_pkt_map),
             early_pkt_key: four_tuple,
-            early_pkt_recv: receiver,
+            early_pkt_generation: generation,
+            early_pkt_recv: EarlyPktRecv {
+                recv: receiver,
+                queued_bytes: Arc::clone(&self.budget.queued_bytes),
+                pending,
+            },
             listener_pkt_send: self.listener_pkt_send.clone(),
+            budget: self.budget.clone(),
+            conn_closed_send: self.conn_closed_send.clone(),
+            recently_closed: self.recently_closed.clone(),
+            demux: self.demux.clone(),
+            is_owner: true,
+            early_pkt_recv_taken: false,
+        })
+    }
+
+    pub fn conn_count(&self) -> usize {
+        self.early_pkt_map.len()
+    }
+
+    /// Remove and close the early packet channel of `four_tuple`.
+    ///
+    /// Returns whether the channel existed.
+    pub fn evict(&self, four_tuple: &FourTuple) -> bool {
+        let removed = self.early_pkt_map.remove(four_tuple);
+        if removed {
+            self.send_evicted(*four_tuple);
         }
+        removed
     }
 
-    pub fn send_early_pkt(&self, four_tuple: &FourTuple, buf: Vec<u8>) -> SendRes {
-        let mut map = self.early_pkt_map.write().unwrap();
-        let Some(sender) = map.get_mut(four_tuple) else {
-            return SendRes::NotExist(buf);
-        };
-        match sender.try_send(buf) {
-            Ok(_) => SendRes::Ok,
-            Err(e) => {
-                if e.is_full() {
-                    SendRes::Full(e.into_inner())
-                } else if e.is_disconnected() {
-                    map.remove(four_tuple);
-                    SendRes::NotExist(e.into_inner())
-                } else {
-                    unreachable!()
-                }
-            }
+    pub fn contains(&self, four_tuple: &FourTuple) -> bool {
+        self.early_pkt_map.contains(four_tuple)
+    }
+
+    /// Four-tuples of the live early packet channels.
+    ///
+    /// Entries whose receiver has been dropped are cleaned up along the way.
+    pub fn active_tuples(&self) -> Vec<FourTuple> {
+        self.early_pkt_map.remove_closed();
+        self.early_pkt_map.four_tuples()
+    }
+
+    /// Whether no more early packet channels can be created.
+    pub fn is_full(&self) -> bool {
+        self.early_pkt_map.is_full()
+    }
+
+    /// Whether no more early packet channels can be created for the remote IP `ip`.
+    pub fn is_ip_full(&self, ip: &IpAddr) -> bool {
+        self.early_pkt_map.is_ip_full(ip)
+    }
+
+    /// Number of early packet channels of the remote IP `ip`.
+    pub fn ip_conn_count(&self, ip: &IpAddr) -> usize {
+        self.early_pkt_map.ip_count(ip)
+    }
+
+    /// Close the early packet channels that have not received packets for longer than `older_than`.
+    pub fn reap_idle(&self, older_than: Duration) -> usize {
+        let removed = self.early_pkt_map.remove_idle(older_than);
+        for four_tuple in &removed {
+            self.send_evicted(*four_tuple);
+        }
+        removed.len()
+    }
+
+    fn send_evicted(&self, four_tuple: FourTuple) {
+        if let Some(recently_closed) = &self.recently_closed {
+            recently_closed.insert(four_tuple);
+        }
+        if let Some(demux) = &self.demux {
+            demux.forget(&four_tuple);
+        }
+        if let Some(conn_closed_send) = &self.conn_closed_send {
+            conn_closed_send.send(ConnClosedEvent {
+                four_tuple,
+                reason: ConnClosedReason::Evicted,
+            });
         }
     }
 
-    pub fn recv_listener_pkt(&self) -> &mpsc::Receiver<(FourTuple, Vec<u8>)> {
+    pub fn send_early_pkt(&self, four_tuple: &FourTuple, buf: Vec<u8>) -> SendRes {
+        send_early_pkt(&self.early_pkt_map, &self.budget, four_tuple, buf)
+    }
+
+    /// Bytes of the early packets queued in every channel.
+    pub fn queued_bytes(&self) -> usize {
+        self.budget.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of early packets refused by the budget of `with_early_pkt_budget`.
+    pub fn over_budget_count(&self) -> u64 {
+        self.budget.over_budget.load(Ordering::Relaxed)
+    }
+
+    pub fn recv_listener_pkt(&self) -> &B::Receiver<(FourTuple, Vec<u8>)> {
         &self.listener_pkt_recv
     }
 
-    pub fn recv_listener_pkt_mut(&mut self) -> &mut mpsc::Receiver<(FourTuple, Vec<u8>)> {
+    /// Block the current thread until a connection hands a packet back, or `timeout` passes; `None` waits forever.
+    pub fn recv_listener_pkt_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<(FourTuple, Vec<u8>), RecvTimeoutError> {
+        self.listener_pkt_recv.recv_timeout(timeout)
+    }
+
+    pub fn recv_listener_pkt_mut(&mut self) -> &mut B::Receiver<(FourTuple, Vec<u8>)> {
         &mut self.listener_pkt_recv
     }
+
+    pub fn conn_closed_events(&self) -> Option<&ConnClosedEvents> {
+        self.conn_closed_recv.as_ref()
+    }
+
+    pub fn conn_closed_events_mut(&mut self) -> Option<&mut ConnClosedEvents> {
+        self.conn_closed_recv.as_mut()
+    }
+}
+impl<B: ChannelBackend> Default for ListenerChan<B> {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+fn send_early_pkt(
+    map: &EarlyPktMap,
+    budget: &EarlyPktBudget,
+    four_tuple: &FourTuple,
+    buf: Vec<u8>,
+) -> SendRes {
+    let mut buf = Some(buf);
+    let res = map.with_entry(four_tuple, |entry| {
+        let buf = buf.take().unwrap();
+        let len = buf.len();
+        // Counted before the receiver can take the packet.
+        let queued_bytes = budget.queued_bytes.fetch_add(len, Ordering::Relaxed);
+        if budget.limit.is_some_and(|limit| queued_bytes + len > limit) {
+            budget.queued_bytes.fetch_sub(len, Ordering::Relaxed);
+            budget.over_budget.fetch_add(1, Ordering::Relaxed);
+            return SendRes::Full(buf);
+        }
+        entry.pending().fetch_add(1, Ordering::AcqRel);
+        let res = send_res(entry.sender.try_send(buf), |buf| buf);
+        match res {
+            SendRes::Ok => entry.last_active = Instant::now(),
+            _ => {
+                budget.queued_bytes.fetch_sub(len, Ordering::Relaxed);
+                entry.pending().fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+        res
+    });
+    match res {
+        Some(SendRes::NotExist(buf)) => {
+            // A live entry inserted since the failed send is kept.
+            map.remove_if_closed(four_tuple);
+            SendRes::NotExist(buf)
+        }
+        Some(res) => res,
+        None => SendRes::NotExist(buf.take().unwrap()),
+    }
 }
 
+/// Bytes of the early packets queued in every channel of a listener, against its budget.
+#[derive(Clone, Default)]
+struct EarlyPktBudget {
+    queued_bytes: Arc<AtomicUsize>,
+    limit: Option<usize>,
+    /// Number of early packets refused by `limit`.
+    over_budget: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
 pub enum SendRes {
     Ok,
     Full(Vec<u8>),
     NotExist(Vec<u8>),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn test_parallel_send_early_pkt() {
+        const THREADS: u16 = 8;
+        const PKTS: usize = 1000;
+        let chan = ListenerChan::<FuturesChannel>::new(None, None).with_early_pkt_buffer(PKTS);
+        let four_tuples = (0..THREADS).map(|i| FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321 + i),
+        });
+        let mut conns = four_tuples
+            .map(|four_tuple| {
+                (
+                    four_tuple,
+                    chan.create_early_pkt_chan(four_tuple).ok().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        std::thread::scope(|s| {
+            for (four_tuple, _) in &conns {
+                let chan = &chan;
+                s.spawn(move || {
+                    for i in 0..PKTS {
+                        let res = chan.send_early_pkt(four_tuple, i.to_be_bytes().to_vec());
+                        assert!(matches!(res, SendRes::Ok));
+                    }
+                });
+            }
+        });
+
+        for (_, conn) in &mut conns {
+            for i in 0..PKTS {
+                let pkt = conn.recv_early_pkt_mut().try_recv().unwrap();
+                assert_eq!(pkt, i.to_be_bytes());
+            }
+        }
+        assert_eq!(chan.conn_count(), THREADS as usize);
+        assert_eq!(chan.queued_bytes(), 0);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{task::AtomicWaker, Stream};
+
+use crate::recv::FourTuple;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnClosedEvent {
+    pub four_tuple: FourTuple,
+    pub reason: ConnClosedReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnClosedReason {
+    /// The `UdpConn` was dropped.
+    Dropped,
+    /// The listener removed the connection by `evict` or `reap_idle`.
+    Evicted,
+}
+
+struct Queue {
+    events: Mutex<VecDeque<ConnClosedEvent>>,
+    capacity: usize,
+    waker: AtomicWaker,
+}
+
+/// Sending half of the connection-closed events.
+#[derive(Clone)]
+pub struct ConnClosedSender {
+    queue: Arc<Queue>,
+}
+impl ConnClosedSender {
+    /// Never blocks: the oldest event is dropped if the queue is full.
+    pub fn send(&self, event: ConnClosedEvent) {
+        {
+            let mut events = self.queue.events.lock().unwrap();
+            if events.len() >= self.queue.capacity {
+                events.pop_front();
+            }
+            events.push_back(event);
+        }
+        self.queue.waker.wake();
+    }
+}
+
+/// Receiving half of the connection-closed events.
+///
+/// The queue holds at most `capacity` events; when full, the oldest event is dropped to make room for the newest one.
+pub struct ConnClosedEvents {
+    queue: Arc<Queue>,
+}
+impl ConnClosedEvents {
+    pub fn try_recv(&self) -> Option<ConnClosedEvent> {
+        self.queue.events.lock().unwrap().pop_front()
+    }
+}
+impl Stream for ConnClosedEvents {
+    type Item = ConnClosedEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.queue.waker.register(cx.waker());
+        match self.try_recv() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub fn conn_closed_channel(capacity: usize) -> (ConnClosedSender, ConnClosedEvents) {
+    let queue = Arc::new(Queue {
+        events: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        waker: AtomicWaker::new(),
+    });
+    (
+        ConnClosedSender {
+            queue: Arc::clone(&queue),
+        },
+        ConnClosedEvents { queue },
+    )
+}
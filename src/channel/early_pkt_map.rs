@@ -1,28 +1,459 @@
-use std::collections::HashMap;
-
-use futures::channel::mpsc;
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, HashMap},
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{
+        atomic::{self, AtomicU64, AtomicUsize},
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
+};
 
+use super::backend::PktSender;
 use crate::recv::FourTuple;
 
+/// Number of shards, each behind its own lock, so packets of different four-tuples rarely contend.
+const SHARDS: usize = 16;
+
+type Shard = Box<dyn EarlyPktStore>;
+
+/// Storage of the entries of one shard of the early packet map, e.g. to keep them in a custom map.
+///
+/// Every call is made under the lock of the shard, so implementations need no synchronization of their own.
+/// The map keeps the counts and limits itself.
+pub trait EarlyPktStore: Send + Sync {
+    /// Returns the entry previously under `four_tuple`.
+    fn insert(&mut self, four_tuple: FourTuple, entry: ConnEntry) -> Option<ConnEntry>;
+    fn get(&self, four_tuple: &FourTuple) -> Option<&ConnEntry>;
+    fn get_mut(&mut self, four_tuple: &FourTuple) -> Option<&mut ConnEntry>;
+    fn remove(&mut self, four_tuple: &FourTuple) -> Option<ConnEntry>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn four_tuples(&self) -> Vec<FourTuple>;
+}
+impl EarlyPktStore for HashMap<FourTuple, ConnEntry> {
+    fn insert(&mut self, four_tuple: FourTuple, entry: ConnEntry) -> Option<ConnEntry> {
+        HashMap::insert(self, four_tuple, entry)
+    }
+
+    fn get(&self, four_tuple: &FourTuple) -> Option<&ConnEntry> {
+        HashMap::get(self, four_tuple)
+    }
+
+    fn get_mut(&mut self, four_tuple: &FourTuple) -> Option<&mut ConnEntry> {
+        HashMap::get_mut(self, four_tuple)
+    }
+
+    fn remove(&mut self, four_tuple: &FourTuple) -> Option<ConnEntry> {
+        HashMap::remove(self, four_tuple)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn four_tuples(&self) -> Vec<FourTuple> {
+        self.keys().copied().collect()
+    }
+}
+
+/// Makes the store of every shard; see `UdpListenerConfig::early_pkt_store`.
+pub type EarlyPktStoreFactory = Box<dyn Fn() -> Box<dyn EarlyPktStore> + Send + Sync>;
+
+/// The sending half of an early packet channel, of whichever `ChannelBackend` the listener runs on.
+pub type EarlyPktSender = Box<dyn PktSender<Vec<u8>>>;
+
+pub struct ConnEntry {
+    pub sender: EarlyPktSender,
+    /// Last time an early packet was delivered to the connection.
+    pub last_active: Instant,
+    /// Distinguishes this entry from earlier ones under the same four-tuple.
+    generation: u64,
+    /// Early packets sent and not yet taken, shared with the receiver.
+    pending: Arc<AtomicUsize>,
+}
+impl ConnEntry {
+    fn new(sender: EarlyPktSender, pending: Arc<AtomicUsize>, generation: u64) -> Self {
+        Self {
+            sender,
+            last_active: Instant::now(),
+            generation,
+            pending,
+        }
+    }
+
+    pub(crate) fn pending(&self) -> &AtomicUsize {
+        &self.pending
+    }
+
+    /// Whether the receiver has been dropped; the entry is then the only holder of `pending`.
+    ///
+    /// Not every `ChannelBackend` can tell that from the sender.
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.pending) == 1
+    }
+}
+
+/// Entries sharded by the hash of their four-tuple.
+///
+/// The counts shared by every shard are locked after the shard, never before.
 pub struct EarlyPktMap {
-    map: HashMap<FourTuple, mpsc::Sender<Vec<u8>>>,
+    shards: Box<[RwLock<Shard>]>,
+    hasher: RandomState,
+    counts: Mutex<Counts>,
+    max_len: Option<usize>,
+    max_len_per_ip: Option<usize>,
+    next_generation: AtomicU64,
 }
 impl EarlyPktMap {
-    pub fn new() -> Self {
+    pub fn new(max_len: Option<usize>, max_len_per_ip: Option<usize>) -> Self {
         Self {
-            map: HashMap::new(),
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(Box::new(HashMap::new()) as Shard))
+                .collect(),
+            hasher: RandomState::new(),
+            counts: Mutex::new(Counts::default()),
+            max_len,
+            max_len_per_ip,
+            next_generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep the entries in stores made by `new_store`, one per shard.
+    ///
+    /// Entries inserted so far are dropped uncounted, so this is meant for a new map.
+    pub fn set_stores(&mut self, new_store: impl Fn() -> Shard) {
+        self.shards = (0..SHARDS).map(|_| RwLock::new(new_store())).collect();
+        *self.counts.get_mut().unwrap() = Counts::default();
+    }
+
+    fn shard_index(&self, four_tuple: &FourTuple) -> usize {
+        self.hasher.hash_one(four_tuple) as usize % self.shards.len()
+    }
+
+    fn shard(&self, four_tuple: &FourTuple) -> &RwLock<Shard> {
+        &self.shards[self.shard_index(four_tuple)]
+    }
+
+    /// Insert `sender`, counting its packets in `pending`, only if no live sender is registered under `four_tuple` and neither the map nor the remote IP is full.
+    ///
+    /// Returns the generation of the new entry.
+    pub fn try_insert(
+        &self,
+        four_tuple: FourTuple,
+        sender: EarlyPktSender,
+        pending: Arc<AtomicUsize>,
+    ) -> Result<u64, InsertErr> {
+        let mut shard = self.shard(&four_tuple).write().unwrap();
+        let generation = self.next_generation.fetch_add(1, atomic::Ordering::Relaxed);
+        match shard.get(&four_tuple) {
+            Some(entry) => {
+                if !entry.is_closed() {
+                    return Err(InsertErr::AlreadyExists);
+                }
+                // The previous connection is gone; take over its slot.
+                shard.insert(four_tuple, ConnEntry::new(sender, pending, generation));
+            }
+            None => {
+                let mut counts = self.counts.lock().unwrap();
+                if counts.is_full(self.max_len) {
+                    return Err(InsertErr::Full);
+                }
+                if counts.is_ip_full(&four_tuple.remote_addr.ip(), self.max_len_per_ip) {
+                    return Err(InsertErr::IpFull);
+                }
+                shard.insert(four_tuple, ConnEntry::new(sender, pending, generation));
+                counts.count(four_tuple.remote_addr.ip());
+            }
         }
+        Ok(generation)
     }
 
-    pub fn insert(&mut self, four_tuple: FourTuple, sender: mpsc::Sender<Vec<u8>>) {
-        self.map.insert(four_tuple, sender);
+    /// Run `f` on the entry under `four_tuple` with only its shard locked.
+    pub fn with_entry<R>(
+        &self,
+        four_tuple: &FourTuple,
+        f: impl FnOnce(&mut ConnEntry) -> R,
+    ) -> Option<R> {
+        self.shard(four_tuple)
+            .write()
+            .unwrap()
+            .get_mut(four_tuple)
+            .map(f)
     }
 
-    pub fn get_mut(&mut self, four_tuple: &FourTuple) -> Option<&mut mpsc::Sender<Vec<u8>>> {
-        self.map.get_mut(four_tuple)
+    /// Returns whether an entry existed.
+    pub fn remove(&self, four_tuple: &FourTuple) -> bool {
+        self.remove_if(four_tuple, |_| true)
+    }
+
+    /// Remove the entry under `four_tuple` only if it is still of `generation`.
+    ///
+    /// Returns whether the entry was removed.
+    pub fn remove_generation(&self, four_tuple: &FourTuple, generation: u64) -> bool {
+        self.remove_if(four_tuple, |entry| entry.generation == generation)
+    }
+
+    /// Remove the entry under `four_tuple` only if its receiver has been dropped.
+    ///
+    /// Returns whether the entry was removed.
+    pub fn remove_if_closed(&self, four_tuple: &FourTuple) -> bool {
+        self.remove_if(four_tuple, |entry| entry.is_closed())
+    }
+
+    fn remove_if(&self, four_tuple: &FourTuple, f: impl FnOnce(&ConnEntry) -> bool) -> bool {
+        let mut shard = self.shard(four_tuple).write().unwrap();
+        if !shard.get(four_tuple).is_some_and(f) {
+            return false;
+        }
+        shard.remove(four_tuple);
+        self.counts
+            .lock()
+            .unwrap()
+            .uncount(&four_tuple.remote_addr.ip());
+        true
+    }
+
+    /// Move the entry of `generation` from `old` to `new`, keeping its sender.
+    pub fn rekey(&self, old: &FourTuple, generation: u64, new: FourTuple) -> Result<(), RekeyErr> {
+        let (old_index, new_index) = (self.shard_index(old), self.shard_index(&new));
+        // Shards are locked in the order of their indexes.
+        let (mut old_shard, mut new_shard) = match old_index.cmp(&new_index) {
+            Ordering::Equal => (self.shards[old_index].write().unwrap(), None),
+            Ordering::Less => {
+                let old_shard = self.shards[old_index].write().unwrap();
+                (old_shard, Some(self.shards[new_index].write().unwrap()))
+            }
+            Ordering::Greater => {
+                let new_shard = self.shards[new_index].write().unwrap();
+                (self.shards[old_index].write().unwrap(), Some(new_shard))
+            }
+        };
+        if old_shard
+            .get(old)
+            .is_none_or(|entry| entry.generation != generation)
+        {
+            return Err(RekeyErr::NotExist);
+        }
+        let is_new_live = new_shard
+            .as_deref()
+            .unwrap_or(&old_shard)
+            .get(&new)
+            .is_some_and(|entry| !entry.is_closed());
+        if is_new_live {
+            return Err(RekeyErr::AlreadyExists);
+        }
+        let mut counts = self.counts.lock().unwrap();
+        let is_same_ip = old.remote_addr.ip() == new.remote_addr.ip();
+        if !is_same_ip && counts.is_ip_full(&new.remote_addr.ip(), self.max_len_per_ip) {
+            return Err(RekeyErr::IpFull);
+        }
+        let entry = old_shard.remove(old).unwrap();
+        counts.uncount(&old.remote_addr.ip());
+        let new_shard: &mut RwLockWriteGuard<'_, Shard> = match &mut new_shard {
+            Some(new_shard) => new_shard,
+            None => &mut old_shard,
+        };
+        // A closed entry under `new` is replaced.
+        if new_shard.insert(new, entry).is_none() {
+            counts.count(new.remote_addr.ip());
+        }
+        Ok(())
     }
 
-    pub fn remove(&mut self, four_tuple: &FourTuple) {
-        self.map.remove(four_tuple);
+    pub fn len(&self) -> usize {
+        self.counts.lock().unwrap().len
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.counts.lock().unwrap().is_full(self.max_len)
+    }
+
+    /// Whether no more entries are allowed for the remote IP `ip`.
+    pub fn is_ip_full(&self, ip: &IpAddr) -> bool {
+        self.counts
+            .lock()
+            .unwrap()
+            .is_ip_full(ip, self.max_len_per_ip)
+    }
+
+    pub fn ip_count(&self, ip: &IpAddr) -> usize {
+        self.counts.lock().unwrap().ip_count(ip)
+    }
+
+    /// Whether a live sender is registered under `four_tuple`.
+    pub fn contains(&self, four_tuple: &FourTuple) -> bool {
+        self.shard(four_tuple)
+            .read()
+            .unwrap()
+            .get(four_tuple)
+            .is_some_and(|entry| !entry.is_closed())
+    }
+
+    /// Remove entries whose receiver has been dropped.
+    pub fn remove_closed(&self) {
+        self.remove_where(|entry| entry.is_closed());
+    }
+
+    pub fn four_tuples(&self) -> Vec<FourTuple> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().four_tuples())
+            .collect()
+    }
+
+    /// Remove entries idle for longer than `older_than`.
+    ///
+    /// Returns the four-tuples of the removed entries.
+    pub fn remove_idle(&self, older_than: Duration) -> Vec<FourTuple> {
+        self.remove_where(|entry| entry.last_active.elapsed() > older_than)
+    }
+
+    fn remove_where(&self, mut f: impl FnMut(&ConnEntry) -> bool) -> Vec<FourTuple> {
+        let mut removed = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let start = removed.len();
+            for four_tuple in shard.four_tuples() {
+                if shard.get(&four_tuple).is_some_and(&mut f) {
+                    shard.remove(&four_tuple);
+                    removed.push(four_tuple);
+                }
+            }
+            // Uncounted before the shard is unlocked, so the counts never lag behind.
+            let mut counts = self.counts.lock().unwrap();
+            for four_tuple in &removed[start..] {
+                counts.uncount(&four_tuple.remote_addr.ip());
+            }
+        }
+        removed
+    }
+}
+
+/// Counts across every shard.
+#[derive(Default)]
+struct Counts {
+    len: usize,
+    /// Number of entries per remote IP.
+    per_ip: HashMap<IpAddr, usize>,
+}
+impl Counts {
+    fn is_full(&self, max_len: Option<usize>) -> bool {
+        max_len.is_some_and(|max_len| self.len >= max_len)
+    }
+
+    fn is_ip_full(&self, ip: &IpAddr, max_len_per_ip: Option<usize>) -> bool {
+        max_len_per_ip.is_some_and(|max_len_per_ip| self.ip_count(ip) >= max_len_per_ip)
+    }
+
+    fn ip_count(&self, ip: &IpAddr) -> usize {
+        self.per_ip.get(ip).copied().unwrap_or_default()
+    }
+
+    fn count(&mut self, ip: IpAddr) {
+        self.len += 1;
+        *self.per_ip.entry(ip).or_default() += 1;
+    }
+
+    fn uncount(&mut self, ip: &IpAddr) {
+        self.len -= 1;
+        let Entry::Occupied(mut count) = self.per_ip.entry(*ip) else {
+            unreachable!("every entry is counted under its remote IP");
+        };
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+            count.remove();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InsertErr {
+    AlreadyExists,
+    Full,
+    /// The remote IP reached its limit of entries.
+    IpFull,
+}
+
+#[derive(Debug)]
+pub enum RekeyErr {
+    /// The entry has been removed, e.g. by eviction.
+    NotExist,
+    /// A live entry is registered under the new four-tuple.
+    AlreadyExists,
+    /// The new remote IP reached its limit of entries.
+    IpFull,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use futures::channel::mpsc;
+
+    use super::*;
+
+    /// A sender and the receiver with its share of the pending count, which the entry is closed without.
+    fn channel() -> (EarlyPktSender, (mpsc::Receiver<Vec<u8>>, Arc<AtomicUsize>)) {
+        let (sender, receiver) = mpsc::channel(1);
+        (Box::new(sender), (receiver, Arc::default()))
+    }
+
+    fn four_tuple() -> FourTuple {
+        FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        }
+    }
+
+    #[test]
+    fn test_insert_same_four_tuple_twice() {
+        let map = EarlyPktMap::new(None, None);
+        let (sender_1, (mut receiver_1, pending_1)) = channel();
+        let (sender_2, (_receiver_2, pending_2)) = channel();
+
+        let generation = map
+            .try_insert(four_tuple(), sender_1, Arc::clone(&pending_1))
+            .ok()
+            .unwrap();
+        let res = map.try_insert(four_tuple(), sender_2, Arc::clone(&pending_2));
+        assert!(matches!(res, Err(InsertErr::AlreadyExists)));
+        assert_eq!(map.len(), 1);
+
+        // The first sender is kept.
+        map.with_entry(&four_tuple(), |entry| {
+            assert_eq!(entry.generation, generation);
+            entry.sender.try_send(b"hello world".to_vec()).unwrap();
+        })
+        .unwrap();
+        assert_eq!(receiver_1.try_recv().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_insert_over_closed_entry() {
+        let map = EarlyPktMap::new(None, None);
+        let (sender_1, receiver_1) = channel();
+        let (sender_2, (_receiver_2, pending_2)) = channel();
+
+        let generation_1 = map
+            .try_insert(four_tuple(), sender_1, Arc::clone(&receiver_1.1))
+            .ok()
+            .unwrap();
+        drop(receiver_1);
+        let generation_2 = map
+            .try_insert(four_tuple(), sender_2, Arc::clone(&pending_2))
+            .ok()
+            .unwrap();
+        assert_ne!(generation_1, generation_2);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.ip_count(&four_tuple().remote_addr.ip()), 1);
+
+        // The stale generation cannot remove the new entry.
+        assert!(!map.remove_generation(&four_tuple(), generation_1));
+        assert!(map.remove_generation(&four_tuple(), generation_2));
+        assert_eq!(map.len(), 0);
     }
 }
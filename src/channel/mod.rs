@@ -1,4 +1,15 @@
+mod backend;
+mod blocking;
+#[allow(clippy::module_inception)]
 mod channel;
+mod conn_closed;
 mod early_pkt_map;
+mod recently_closed;
 
+#[cfg(feature = "tokio")]
+pub use backend::TokioChannel;
+pub use backend::{
+    ChannelBackend, FuturesChannel, PktReceiver, PktSender, StdChannel, StdReceiver,
+};
 pub use channel::*;
+pub use conn_closed::{ConnClosedEvent, ConnClosedEvents, ConnClosedReason};
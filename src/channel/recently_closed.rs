@@ -0,0 +1,57 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::recv::FourTuple;
+
+/// Four-tuples whose connection closed within the last `window`, like TCP's `TIME_WAIT`.
+pub struct RecentlyClosed {
+    window: Duration,
+    closed: Mutex<Closed>,
+}
+impl RecentlyClosed {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            closed: Mutex::new(Closed::default()),
+        }
+    }
+
+    pub fn insert(&self, four_tuple: FourTuple) {
+        let now = Instant::now();
+        let mut closed = self.closed.lock().unwrap();
+        closed.expire(now, self.window);
+        closed.closed_at.insert(four_tuple, now);
+        closed.order.push_back((now, four_tuple));
+    }
+
+    pub fn contains(&self, four_tuple: &FourTuple) -> bool {
+        let mut closed = self.closed.lock().unwrap();
+        closed.expire(Instant::now(), self.window);
+        closed.closed_at.contains_key(four_tuple)
+    }
+}
+
+#[derive(Default)]
+struct Closed {
+    /// Last close of every four-tuple.
+    closed_at: HashMap<FourTuple, Instant>,
+    /// Every close, oldest first.
+    order: VecDeque<(Instant, FourTuple)>,
+}
+impl Closed {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(&(at, four_tuple)) = self.order.front() {
+            if now.duration_since(at) <= window {
+                break;
+            }
+            self.order.pop_front();
+            // A later close of the same four-tuple keeps it.
+            if self.closed_at.get(&four_tuple) == Some(&at) {
+                self.closed_at.remove(&four_tuple);
+            }
+        }
+    }
+}
@@ -1,22 +1,183 @@
-use std::{io, os::fd::AsRawFd};
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{RecvError, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{
-    channel::{ConnChan, SendRes},
-    recv::{recv_from_to, FourTuple},
+    channel::{ChannelBackend, ConnChan, EarlyPktRecv, FuturesChannel, RekeyErr, SendRes},
+    recv::{peek_len, recv_from_to_cmsg_buf, socket_fd, FourTuple, RecvMeta, RecvOptions},
+    send::send_from_to,
+};
+#[cfg(target_os = "linux")]
+use crate::{
+    recv::{recv_err_queue, SockError},
+    send::send_batch,
 };
 
-pub struct UdpConn {
+#[derive(Debug, Clone, Default)]
+pub struct UdpConnConfig {
+    pub recv_mode: ConnRecvMode,
+    /// Bind the connection socket without connecting it, for peers behind symmetric NATs whose source port changes between packets.
+    ///
+    /// `UdpConn::recv` then classifies every packet regardless of `recv_mode`, following the peer to a new port of the same remote IP;
+    /// `send` sends to the latest remote address. The kernel reports no ICMP errors to the socket.
+    pub unconnected: bool,
+    /// Report `ECONNREFUSED`/`ECONNRESET` from `recv` as `RecvRes::PeerUnreachable` instead of an error.
+    ///
+    /// The connection socket is connected, so the kernel turns ICMP errors from the peer into these errors.
+    pub soft_peer_unreachable: bool,
+    /// Enable `IP_RECVERR`/`IPV6_RECVERR` on the connection socket to read ICMP errors by `UdpConn::take_error_queue`.
+    #[cfg(target_os = "linux")]
+    pub recv_err: bool,
+    /// Path MTU discovery mode of the connection socket; `None` keeps the system default.
+    #[cfg(target_os = "linux")]
+    pub mtu_discover: Option<MtuDiscover>,
+    /// Set or clear the don't-fragment flag on the connection socket; `None` keeps the system default.
+    ///
+    /// Applied after `mtu_discover`, which it overrides on IPv4.
+    #[cfg(target_os = "linux")]
+    pub dont_fragment: Option<bool>,
+}
+
+/// Modes of `IP_MTU_DISCOVER`/`IPV6_MTU_DISCOVER`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtuDiscover {
+    /// Use per-route settings.
+    Want,
+    /// Always set the don't-fragment flag; sends above the path MTU fail with `MsgTooLong`.
+    Do,
+    /// Never set the don't-fragment flag.
+    Dont,
+    /// Set the don't-fragment flag but ignore the path MTU, to probe it.
+    Probe,
+}
+#[cfg(target_os = "linux")]
+impl MtuDiscover {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            Self::Want => libc::IP_PMTUDISC_WANT,
+            Self::Do => libc::IP_PMTUDISC_DO,
+            Self::Dont => libc::IP_PMTUDISC_DONT,
+            Self::Probe => libc::IP_PMTUDISC_PROBE,
+        }
+    }
+
+    fn from_raw(mode: libc::c_int) -> Option<Self> {
+        Some(match mode {
+            libc::IP_PMTUDISC_WANT => Self::Want,
+            libc::IP_PMTUDISC_DO => Self::Do,
+            libc::IP_PMTUDISC_DONT => Self::Dont,
+            libc::IP_PMTUDISC_PROBE => Self::Probe,
+            _ => return None,
+        })
+    }
+}
+
+/// The packet did not fit into the path MTU; carried by the `io::Error` of `UdpConn::send*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsgTooLong {
+    /// Path MTU at the time of the send, if known.
+    pub mtu: Option<u32>,
+}
+impl fmt::Display for MsgTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mtu {
+            Some(mtu) => write!(f, "packet exceeds the path MTU of {mtu} bytes"),
+            None => write!(f, "packet exceeds the path MTU"),
+        }
+    }
+}
+impl std::error::Error for MsgTooLong {}
+impl MsgTooLong {
+    /// The `MsgTooLong` carried by `err`, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnRecvMode {
+    /// Recover the four-tuple of every packet and hand packets of other four-tuples to the listener.
+    #[default]
+    Classify,
+    /// Trust the kernel to only deliver packets of the connected four-tuple.
+    ///
+    /// Skips `recvmsg` and the control message parsing; `recv` never returns `RecvRes::ListenerPkt`.
+    Connected,
+}
+
+/// Payload bytes and packets a connection has sent and received; see `UdpConn::traffic_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    /// Early packets included.
+    pub bytes_received: u64,
+    /// Early packets included.
+    pub packets_received: u64,
+}
+
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_received: AtomicU64,
+}
+impl TrafficCounters {
+    fn count_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct UdpConn<B: ChannelBackend = FuturesChannel> {
     socket: socket2::Socket,
     four_tuple: FourTuple,
-    chan: ConnChan,
+    chan: ConnChan<B>,
+    config: UdpConnConfig,
+    /// Shared with the clones of `try_clone`.
+    traffic: Arc<TrafficCounters>,
+    /// Control message buffer reused by `recv*`.
+    cmsg_buf: Vec<u8>,
 }
 
-impl UdpConn {
-    pub fn new(socket: socket2::Socket, four_tuple: FourTuple, chan: ConnChan) -> Self {
+impl<B: ChannelBackend> UdpConn<B> {
+    pub fn new(
+        socket: socket2::Socket,
+        four_tuple: FourTuple,
+        chan: ConnChan<B>,
+        config: UdpConnConfig,
+    ) -> Self {
         Self {
             socket,
             four_tuple,
             chan,
+            config,
+            traffic: Arc::default(),
+            cmsg_buf: Vec::new(),
         }
     }
 
@@ -28,35 +189,693 @@ impl UdpConn {
         &mut self.socket
     }
 
+    /// Size of the next datagram queued on the socket.
+    pub fn peek_len(&self) -> io::Result<usize> {
+        peek_len(socket_fd(&self.socket))
+    }
+
     /// Receive a packet from the socket, not from the early packet channel.
     ///
     /// Returns the number of bytes received.
     ///
     /// If the received packet is not meant for this connection, returns `RecvRes::ListenerPkt`.
+    ///
+    /// See `UdpConnConfig::soft_peer_unreachable` for `RecvRes::PeerUnreachable`.
+    ///
+    /// See `recv_plain` for the fast path.
     pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<(RecvRes, usize)> {
-        let (four_tuple, len) = recv_from_to(
-            self.socket.as_raw_fd(),
-            buf,
-            self.four_tuple.local_addr.port(),
-        )?;
-        if four_tuple != self.four_tuple {
-            let buf = buf[..len].to_vec();
-            match self.chan.send_listener_pkt(four_tuple, buf) {
-                SendRes::Ok => (),
-                SendRes::Full(_) => (),
-                SendRes::NotExist(_) => (),
-            };
-            return Ok((RecvRes::ListenerPkt(four_tuple), len));
+        let (res, len, _) = self.recv_with_meta(buf)?;
+        Ok((res, len))
+    }
+
+    /// Receive a packet from the socket by a plain `recv`, trusting the kernel to only deliver packets of the connected four-tuple.
+    ///
+    /// The fast path: skips `recvmsg`, the control message parsing and the four-tuple check regardless of `UdpConnConfig::recv_mode`.
+    /// Use `recv` to catch the packets of other four-tuples that the `SO_REUSEADDR` demultiplexing occasionally misroutes to this socket,
+    /// and in `UdpConnConfig::unconnected`, where the socket receives packets of every remote.
+    pub fn recv_plain(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = recv_connected(&self.socket, buf)?;
+        self.traffic.count_received(len);
+        Ok(len)
+    }
+
+    /// Receive from the early packet channel if it has a packet buffered, or else from the socket.
+    ///
+    /// Early packets are taken first since they arrived before anything the socket holds.
+    /// In blocking mode, an early packet delivered while blocking on the socket waits for the next call.
+    pub fn recv_any(&mut self, buf: &mut [u8]) -> io::Result<RecvAny> {
+        if let Ok(pkt) = self.chan.recv_early_pkt_mut().try_recv() {
+            self.traffic.count_received(pkt.len());
+            return Ok(RecvAny::Early(pkt));
+        }
+        let (res, len) = self.recv(buf)?;
+        Ok(RecvAny::Socket { res, len })
+    }
+
+    /// `recv_any` that copies an early packet into `buf` too, so every packet is taken in order by one call.
+    ///
+    /// An early packet longer than `buf` is cut short like a datagram from the socket.
+    pub fn recv_unified(&mut self, buf: &mut [u8]) -> io::Result<(RecvSource, usize)> {
+        match self.recv_any(buf)? {
+            RecvAny::Early(pkt) => {
+                let len = pkt.len().min(buf.len());
+                buf[..len].copy_from_slice(&pkt[..len]);
+                Ok((RecvSource::Early, len))
+            }
+            RecvAny::Socket { res, len } => Ok((RecvSource::Socket(res), len)),
         }
-        Ok((RecvRes::Ok, len))
+    }
+
+    /// Block the current thread until an early packet arrives, without an async executor.
+    ///
+    /// Waits in `std::sync::mpsc` on `StdChannel`, and by parking the thread between polls on `FuturesChannel` and `TokioChannel`.
+    /// Fails once the listener is gone and every early packet is taken.
+    pub fn recv_early_pkt_blocking(&mut self) -> Result<Vec<u8>, RecvError> {
+        let pkt = self.chan.recv_early_pkt_mut().recv()?;
+        self.traffic.count_received(pkt.len());
+        Ok(pkt)
+    }
+
+    /// `recv_early_pkt_blocking` that gives up after `timeout`.
+    pub fn recv_early_pkt_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RecvTimeoutError> {
+        let pkt = self.chan.recv_early_pkt_mut().recv_timeout(timeout)?;
+        self.traffic.count_received(pkt.len());
+        Ok(pkt)
+    }
+
+    /// Take every early packet buffered so far, in order, without blocking.
+    pub fn drain_early_pkts(&mut self) -> Vec<Vec<u8>> {
+        let early_pkt_recv = self.chan.recv_early_pkt_mut();
+        let mut pkts = Vec::new();
+        while let Ok(pkt) = early_pkt_recv.try_recv() {
+            self.traffic.count_received(pkt.len());
+            pkts.push(pkt);
+        }
+        pkts
+    }
+
+    /// Copy the next buffered early packet into `buf` without blocking, cut short like `recv_unified` does.
+    ///
+    /// `None` if no early packet is buffered; `is_early_pkt_closed` tells whether one ever will be.
+    pub fn try_recv_early_pkt(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let pkt = self.chan.recv_early_pkt_mut().try_recv().ok()?;
+        self.traffic.count_received(pkt.len());
+        let len = pkt.len().min(buf.len());
+        buf[..len].copy_from_slice(&pkt[..len]);
+        Some(len)
+    }
+
+    /// Whether an early packet is buffered, without taking it.
+    pub fn early_pkt_pending(&self) -> bool {
+        self.chan.recv_early_pkt().has_pending()
+    }
+
+    /// Whether no early packet will ever arrive, as after the listener is gone or the connection is evicted,
+    /// and every buffered one is taken.
+    pub fn is_early_pkt_closed(&self) -> bool {
+        self.chan.recv_early_pkt().is_closed()
+    }
+
+    /// `recv` that also returns what `recvmsg` reported about the packet.
+    ///
+    /// `ConnRecvMode::Connected` skips `recvmsg` and always reports empty metadata.
+    pub fn recv_with_meta(&mut self, buf: &mut [u8]) -> io::Result<(RecvRes, usize, RecvMeta)> {
+        let recv_mode = match self.config.unconnected {
+            true => ConnRecvMode::Classify,
+            false => self.config.recv_mode,
+        };
+        let res = match recv_mode {
+            ConnRecvMode::Classify => recv_from_to_cmsg_buf(
+                socket_fd(&self.socket),
+                buf,
+                self.four_tuple.local_addr.port(),
+                // The socket is bound to the local address, so no pktinfo is needed.
+                &RecvOptions {
+                    local_ip: Some(self.four_tuple.local_addr.ip()),
+                    ..Default::default()
+                },
+                &mut self.cmsg_buf,
+            ),
+            ConnRecvMode::Connected => recv_connected(&self.socket, buf)
+                .map(|len| (self.four_tuple, len, RecvMeta::default())),
+        };
+        let (four_tuple, len, meta) = match res {
+            Ok(x) => x,
+            Err(e) if self.config.soft_peer_unreachable && is_peer_unreachable(&e) => {
+                return Ok((RecvRes::PeerUnreachable, 0, RecvMeta::default()));
+            }
+            Err(e) => return Err(e),
+        };
+        if four_tuple != self.four_tuple && !self.roam(four_tuple) {
+            self.redirect(four_tuple, buf[..len].to_vec());
+            return Ok((RecvRes::ListenerPkt(four_tuple), len, meta));
+        }
+        self.traffic.count_received(len);
+        Ok((RecvRes::Ok, len, meta))
+    }
+
+    /// Hand a packet of `four_tuple` that landed on this socket to the listener, to be passed to `UdpListener::accept_raw`.
+    ///
+    /// `redirect` is quicker and does not need the listener packet channel to be pumped.
+    pub fn forward_to_listener(&mut self, four_tuple: FourTuple, buf: &[u8]) -> ForwardRes {
+        match self.chan.send_listener_pkt(four_tuple, buf.to_vec()) {
+            SendRes::Ok => ForwardRes::Ok,
+            SendRes::Full(_) => ForwardRes::Full,
+            SendRes::NotExist(_) => ForwardRes::ListenerGone,
+        }
+    }
+
+    /// Deliver a packet of `four_tuple` that landed on this socket straight into the early packet channel of the connection owning it.
+    ///
+    /// Falls back to the listener packet channel, as `forward_to_listener`, if no connection owns `four_tuple`.
+    /// `recv` does this for `RecvRes::ListenerPkt`.
+    pub fn redirect(&mut self, four_tuple: FourTuple, buf: Vec<u8>) -> SendRes {
+        self.chan.redirect(four_tuple, buf)
+    }
+
+    /// Follow the peer of an unconnected socket to `four_tuple` if only its remote port changed.
+    fn roam(&mut self, four_tuple: FourTuple) -> bool {
+        let roams = self.config.unconnected
+            && four_tuple.local_addr == self.four_tuple.local_addr
+            && four_tuple.remote_addr.ip() == self.four_tuple.remote_addr.ip();
+        if !roams || self.chan.rekey(four_tuple).is_err() {
+            return false;
+        }
+        self.four_tuple = four_tuple;
+        true
+    }
+
+    /// Send `buf` to the peer.
+    ///
+    /// Fails with an error passing `is_peer_unreachable` if the peer answered an earlier packet with an ICMP port unreachable.
+    /// The error is reported once; the next send goes through again.
+    ///
+    /// Fails with an error carrying `MsgTooLong` if `buf` does not fit into the path MTU.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if self.config.unconnected {
+            return self.send_to(buf, self.four_tuple.remote_addr);
+        }
+        let len = self
+            .socket
+            .send(buf)
+            .map_err(|e| self.classify_send_err(e))?;
+        self.traffic.count_sent(len);
+        Ok(len)
+    }
+
+    /// Send `buf` to `dst`, which need not be the peer; for sockets of `UdpConnConfig::unconnected`.
+    pub fn send_to(&self, buf: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        let len = self
+            .socket
+            .send_to(buf, &dst.into())
+            .map_err(|e| self.classify_send_err(e))?;
+        self.traffic.count_sent(len);
+        Ok(len)
+    }
+
+    fn classify_send_err(&self, err: io::Error) -> io::Error {
+        if err.raw_os_error() == Some(libc::EMSGSIZE) {
+            #[cfg(target_os = "linux")]
+            let mtu = self.path_mtu().ok();
+            #[cfg(not(target_os = "linux"))]
+            let mtu = None;
+            return io::Error::new(io::ErrorKind::InvalidInput, MsgTooLong { mtu });
+        }
+        classify_send_err(err)
+    }
+
+    /// Send every buffer of `bufs` to the peer as its own datagram by a single `sendmmsg`.
+    ///
+    /// Returns the number of datagrams sent, which may be less than `bufs.len()`;
+    /// send the rest again. Fails like `send` if not even the first datagram was sent.
+    ///
+    /// Needs a connected socket; fails with `EDESTADDRREQ` in `UdpConnConfig::unconnected`.
+    #[cfg(target_os = "linux")]
+    pub fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let sent =
+            send_batch(socket_fd(&self.socket), bufs).map_err(|e| self.classify_send_err(e))?;
+        for buf in &bufs[..sent] {
+            self.traffic.count_sent(buf.len());
+        }
+        Ok(sent)
+    }
+
+    /// Set the path MTU discovery mode, overriding `UdpConnConfig::mtu_discover`.
+    #[cfg(target_os = "linux")]
+    pub fn set_mtu_discover(&self, mode: MtuDiscover) -> io::Result<()> {
+        set_mtu_discover(&self.socket, &self.four_tuple, mode)
+    }
+
+    /// Path MTU discovery mode of the connection socket.
+    #[cfg(target_os = "linux")]
+    pub fn mtu_discover(&self) -> io::Result<MtuDiscover> {
+        let (level, name) = match self.four_tuple.local_addr {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+        };
+        let mode = getsockopt_int(&self.socket, level, name)?;
+        MtuDiscover::from_raw(mode).ok_or(io::Error::other(
+            "the path MTU discovery mode is not one of MtuDiscover",
+        ))
+    }
+
+    /// Path MTU to the peer as currently known by the kernel, by `IP_MTU`/`IPV6_MTU`.
+    #[cfg(target_os = "linux")]
+    pub fn path_mtu(&self) -> io::Result<u32> {
+        let (level, name) = match self.four_tuple.local_addr {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        let mtu = getsockopt_int(&self.socket, level, name)?;
+        Ok(mtu as u32)
+    }
+
+    /// Set or clear the don't-fragment flag on outgoing packets, overriding `UdpConnConfig::dont_fragment`.
+    ///
+    /// On IPv4, this sets `IP_MTU_DISCOVER` to `IP_PMTUDISC_DO` or `IP_PMTUDISC_DONT`.
+    #[cfg(target_os = "linux")]
+    pub fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        set_dont_fragment(&self.socket, &self.four_tuple, dont_fragment)
+    }
+
+    /// Whether outgoing packets carry the don't-fragment flag.
+    #[cfg(target_os = "linux")]
+    pub fn dont_fragment(&self) -> io::Result<bool> {
+        match self.four_tuple.local_addr {
+            SocketAddr::V4(_) => {
+                let mode = getsockopt_int(&self.socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER)?;
+                Ok(matches!(
+                    mode,
+                    libc::IP_PMTUDISC_DO | libc::IP_PMTUDISC_PROBE
+                ))
+            }
+            SocketAddr::V6(_) => {
+                let value = getsockopt_int(&self.socket, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG)?;
+                Ok(value != 0)
+            }
+        }
+    }
+
+    /// Enable or disable `IP_RECVERR`/`IPV6_RECVERR`, overriding `UdpConnConfig::recv_err`.
+    #[cfg(target_os = "linux")]
+    pub fn set_recv_err(&self, recv_err: bool) -> io::Result<()> {
+        set_recv_err(&self.socket, &self.four_tuple, recv_err)
+    }
+
+    /// Pop the oldest error queued on the socket, e.g. an ICMP port unreachable from the peer.
+    ///
+    /// The payload of the offending packet is copied into `buf`.
+    /// Requires `IP_RECVERR`/`IPV6_RECVERR`; see `UdpConnConfig::recv_err`.
+    ///
+    /// Returns `None` if no error is queued.
+    #[cfg(target_os = "linux")]
+    pub fn take_error_queue(&self, buf: &mut [u8]) -> io::Result<Option<SockError>> {
+        recv_err_queue(socket_fd(&self.socket), buf)
+    }
+
+    /// `take_error_queue` discarding the payload of the offending packet.
+    #[cfg(target_os = "linux")]
+    pub fn recv_err(&self) -> io::Result<Option<SockError>> {
+        self.take_error_queue(&mut [])
+    }
+
+    /// See `UdpListener::set_send_checksum_coverage`.
+    #[cfg(target_os = "linux")]
+    pub fn set_send_checksum_coverage(&self, coverage: u16) -> io::Result<()> {
+        set_send_checksum_coverage(&self.socket, coverage)
+    }
+
+    /// See `UdpListener::set_recv_checksum_coverage`.
+    #[cfg(target_os = "linux")]
+    pub fn set_recv_checksum_coverage(&self, coverage: u16) -> io::Result<()> {
+        set_recv_checksum_coverage(&self.socket, coverage)
+    }
+
+    /// Send `buf` to the peer with `src` as the source address instead of the bound one.
+    ///
+    /// The source is pinned by an `IP_PKTINFO`/`IPV6_PKTINFO` control message,
+    /// so replies keep a valid source even if the bound local IP went away.
+    ///
+    /// Fails like `send` if the peer is unreachable.
+    pub fn send_from(&self, buf: &[u8], src: IpAddr) -> io::Result<usize> {
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(src, self.four_tuple.local_addr.port()),
+            remote_addr: self.four_tuple.remote_addr,
+        };
+        let len = send_from_to(socket_fd(&self.socket), buf, &four_tuple)
+            .map_err(|e| self.classify_send_err(e))?;
+        self.traffic.count_sent(len);
+        Ok(len)
     }
 
     /// Receiver of the early packet channel.
-    pub fn recv_early_pkt(&self) -> &ConnChan {
+    pub fn recv_early_pkt(&self) -> &ConnChan<B> {
+        &self.chan
+    }
+
+    pub fn recv_early_pkt_mut(&mut self) -> &mut ConnChan<B> {
+        &mut self.chan
+    }
+
+    /// Move the receiver of early packets out, e.g. to `select!` over it apart from the connection.
+    ///
+    /// Returns `None` if it was taken before or this is a clone from `try_clone`.
+    /// The socket keeps working, but `recv_any` and the other early packet readers of the connection get nothing,
+    /// and packets taken by the receiver do not count in `traffic_stats`.
+    pub fn take_early_pkt_recv(&mut self) -> Option<EarlyPktRecv<B>> {
+        self.chan.take_early_pkt_recv()
+    }
+
+    pub fn four_tuple(&self) -> &FourTuple {
+        &self.four_tuple
+    }
+
+    /// Whether the listener that accepted this connection still exists.
+    ///
+    /// Early packets stop arriving once it is gone.
+    pub fn listener_alive(&self) -> bool {
+        self.chan.is_listener_alive()
+    }
+
+    /// Follow the peer to `four_tuple`, e.g. after its NAT rebinds it to a new address.
+    ///
+    /// The socket is connected to the new remote address, unless `UdpConnConfig::unconnected`,
+    /// and the listener routes early packets of `four_tuple` to this connection.
+    /// The local address cannot change.
+    pub fn reconnect(&mut self, four_tuple: FourTuple) -> io::Result<()> {
+        if four_tuple.local_addr != self.four_tuple.local_addr {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reconnect cannot change the local address",
+            ));
+        }
+        let connected = !self.config.unconnected;
+        if connected {
+            self.socket.connect(&four_tuple.remote_addr.into())?;
+        }
+        if let Err(e) = self.chan.rekey(four_tuple) {
+            if connected {
+                self.socket.connect(&self.four_tuple.remote_addr.into())?;
+            }
+            let e = match e {
+                RekeyErr::NotExist => io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "the listener no longer knows the connection",
+                ),
+                RekeyErr::AlreadyExists => io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another connection owns the four-tuple",
+                ),
+                RekeyErr::IpFull => {
+                    io::Error::other("the new remote IP reached its connection limit")
+                }
+            };
+            return Err(e);
+        }
+        self.four_tuple = four_tuple;
+        Ok(())
+    }
+
+    /// Convert the connection socket into a `std::net::UdpSocket`.
+    ///
+    /// The early packet channel is dropped, so the listener forgets this connection.
+    pub fn into_std(self) -> std::net::UdpSocket {
+        self.into_parts().0.into()
+    }
+
+    /// Duplicate the connection, e.g. to send from one thread while receiving on another.
+    ///
+    /// The clone shares the socket but receives no early packets, and dropping it leaves the connection registered in the listener.
+    /// Only the original can `reconnect`.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: self.socket.try_clone()?,
+            four_tuple: self.four_tuple,
+            chan: self.chan.clone_detached(),
+            config: self.config.clone(),
+            traffic: Arc::clone(&self.traffic),
+            cmsg_buf: Vec::new(),
+        })
+    }
+
+    /// Payload sent and received by `send*` and `recv*` so far, including by the clones of `try_clone`.
+    ///
+    /// Early packets count once taken by `recv_any`, `drain_early_pkts`, `try_recv_early_pkt` or `recv_early_pkt_*`;
+    /// packets passed on as `RecvRes::ListenerPkt` do not count.
+    pub fn traffic_stats(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Take the connection socket and its four-tuple apart.
+    ///
+    /// The early packet channel is dropped, so the listener forgets this connection.
+    pub fn into_parts(self) -> (socket2::Socket, FourTuple) {
+        (self.socket, self.four_tuple)
+    }
+}
+impl<B: ChannelBackend> fmt::Debug for UdpConn<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpConn")
+            .field("four_tuple", &self.four_tuple)
+            .finish_non_exhaustive()
+    }
+}
+impl<B: ChannelBackend> AsRawFd for UdpConn<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+impl<B: ChannelBackend> AsFd for UdpConn<B> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        socket_fd(&self.socket)
+    }
+}
+/// The early packet channel is dropped, so the listener forgets this connection.
+impl<B: ChannelBackend> IntoRawFd for UdpConn<B> {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_parts().0.into_raw_fd()
+    }
+}
+/// The early packet channel is dropped, so the listener forgets this connection.
+impl<B: ChannelBackend> From<UdpConn<B>> for OwnedFd {
+    fn from(conn: UdpConn<B>) -> Self {
+        conn.into_std().into()
+    }
+}
+
+/// Whether `err` from `UdpConn::send*` or `UdpConn::recv` means that the peer reported nothing listens on its side,
+/// usually by an ICMP port unreachable.
+///
+/// The flow can be torn down gracefully on such errors.
+pub fn is_peer_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+fn recv_connected(socket: &socket2::Socket, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: `buf` is valid for writes of its length.
+    let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+fn classify_send_err(err: io::Error) -> io::Error {
+    if !is_peer_unreachable(&err) {
+        return err;
+    }
+    io::Error::new(
+        err.kind(),
+        "peer is unreachable: an earlier packet was answered by ICMP port unreachable",
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_mtu_discover(
+    socket: &socket2::Socket,
+    four_tuple: &FourTuple,
+    mode: MtuDiscover,
+) -> io::Result<()> {
+    // The `IPV6_PMTUDISC_*` values equal the `IP_PMTUDISC_*` ones.
+    let (level, name) = match four_tuple.local_addr {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER),
+    };
+    setsockopt_int(socket, level, name, mode.as_raw())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_dont_fragment(
+    socket: &socket2::Socket,
+    four_tuple: &FourTuple,
+    dont_fragment: bool,
+) -> io::Result<()> {
+    match four_tuple.local_addr {
+        SocketAddr::V4(_) => {
+            let mode = match dont_fragment {
+                true => MtuDiscover::Do,
+                false => MtuDiscover::Dont,
+            };
+            set_mtu_discover(socket, four_tuple, mode)
+        }
+        SocketAddr::V6(_) => setsockopt_int(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_DONTFRAG,
+            dont_fragment.into(),
+        ),
+    }
+}
+
+/// `UDPLITE_SEND_CSCOV` and `UDPLITE_RECV_CSCOV` of `linux/udp.h`, missing from `libc`.
+#[cfg(target_os = "linux")]
+const UDPLITE_SEND_CSCOV: libc::c_int = 10;
+#[cfg(target_os = "linux")]
+const UDPLITE_RECV_CSCOV: libc::c_int = 11;
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_send_checksum_coverage(
+    socket: &socket2::Socket,
+    coverage: u16,
+) -> io::Result<()> {
+    setsockopt_int(
+        socket,
+        libc::IPPROTO_UDPLITE,
+        UDPLITE_SEND_CSCOV,
+        coverage.into(),
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_recv_checksum_coverage(
+    socket: &socket2::Socket,
+    coverage: u16,
+) -> io::Result<()> {
+    setsockopt_int(
+        socket,
+        libc::IPPROTO_UDPLITE,
+        UDPLITE_RECV_CSCOV,
+        coverage.into(),
+    )
+}
+
+pub(crate) fn setsockopt_int(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` is a single `c_int`.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn getsockopt_int(
+    socket: &socket2::Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` describe a single `c_int`.
+    let res = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn set_recv_err(
+    socket: &socket2::Socket,
+    four_tuple: &FourTuple,
+    recv_err: bool,
+) -> io::Result<()> {
+    let (level, name) = match four_tuple.local_addr {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_RECVERR),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVERR),
+    };
+    setsockopt_int(socket, level, name, recv_err.into())
+}
+
+/// A connection without its own socket.
+///
+/// The listener forwards every packet of the four-tuple to the early packet channel,
+/// and `send` goes out of the listener socket with a pinned source address.
+pub struct UdpConnHandle<B: ChannelBackend = FuturesChannel> {
+    socket: Arc<socket2::Socket>,
+    four_tuple: FourTuple,
+    chan: ConnChan<B>,
+    pin_source: bool,
+}
+
+impl<B: ChannelBackend> UdpConnHandle<B> {
+    pub fn new(socket: Arc<socket2::Socket>, four_tuple: FourTuple, chan: ConnChan<B>) -> Self {
+        Self {
+            socket,
+            four_tuple,
+            chan,
+            pin_source: true,
+        }
+    }
+
+    /// Let the kernel pick the source address of `send`,
+    /// for flows whose local address cannot be a source, e.g. a broadcast address.
+    pub(crate) fn without_pinned_source(mut self) -> Self {
+        self.pin_source = false;
+        self
+    }
+
+    /// Send `buf` from `four_tuple.local_addr` to `four_tuple.remote_addr`.
+    ///
+    /// Flows to a multicast or broadcast address are answered from a unicast address picked by the kernel.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if !self.pin_source {
+            let unspecified = match self.four_tuple.local_addr.ip() {
+                IpAddr::V4(_) => IpAddr::from(std::net::Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let four_tuple = FourTuple {
+                local_addr: SocketAddr::new(unspecified, self.four_tuple.local_addr.port()),
+                remote_addr: self.four_tuple.remote_addr,
+            };
+            return send_from_to(socket_fd(&self.socket), buf, &four_tuple);
+        }
+        send_from_to(socket_fd(&self.socket), buf, &self.four_tuple)
+    }
+
+    /// Receiver of every packet of the connection.
+    pub fn recv_early_pkt(&self) -> &ConnChan<B> {
         &self.chan
     }
 
-    pub fn recv_early_pkt_mut(&mut self) -> &mut ConnChan {
+    pub fn recv_early_pkt_mut(&mut self) -> &mut ConnChan<B> {
         &mut self.chan
     }
 
@@ -64,8 +883,46 @@ impl UdpConn {
         &self.four_tuple
     }
 }
+impl<B: ChannelBackend> fmt::Debug for UdpConnHandle<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpConnHandle")
+            .field("four_tuple", &self.four_tuple)
+            .finish_non_exhaustive()
+    }
+}
 
+#[derive(Debug)]
 pub enum RecvRes {
     Ok,
+    /// The packet belongs to another four-tuple and was passed on to its connection or the listener; see `UdpConn::redirect`.
     ListenerPkt(FourTuple),
+    /// The peer reported that nothing listens on its side, usually by an ICMP port unreachable.
+    PeerUnreachable,
+}
+
+#[derive(Debug)]
+pub enum RecvAny {
+    /// A packet from the socket; see `UdpConn::recv`.
+    Socket { res: RecvRes, len: usize },
+    /// A packet from the early packet channel.
+    Early(Vec<u8>),
+}
+
+/// Outcome of `UdpConn::forward_to_listener`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardRes {
+    Ok,
+    /// The listener packet channel is full and the packet was dropped.
+    Full,
+    /// The listener is gone and the packet was dropped.
+    ListenerGone,
+}
+
+/// Where `UdpConn::recv_unified` took a packet from.
+#[derive(Debug)]
+pub enum RecvSource {
+    /// The early packet channel.
+    Early,
+    /// The socket; see `UdpConn::recv`.
+    Socket(RecvRes),
 }
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, hash::Hash, sync::RwLock};
+
+use crate::recv::FourTuple;
+
+/// A key identifying a connection across changes of its four-tuple, e.g. a connection ID in the payload.
+pub trait DemuxKey: Hash + Eq + Clone + Send + Sync + 'static {}
+impl<T> DemuxKey for T where T: Hash + Eq + Clone + Send + Sync + 'static {}
+
+/// Routes packets from unknown four-tuples to the connection that owns their key.
+///
+/// Connections stay keyed by their four-tuple; this index only maps keys to the four-tuple that registered them.
+pub struct Demux {
+    index: Box<dyn DemuxIndex>,
+}
+impl Demux {
+    /// Key packets by `extractor`.
+    pub fn by_key<K, F>(extractor: F) -> Self
+    where
+        K: DemuxKey,
+        F: Fn(&FourTuple, &[u8]) -> K + Send + Sync + 'static,
+    {
+        Self {
+            index: Box::new(KeyIndex {
+                extractor,
+                keys: RwLock::new(Keys {
+                    owners: HashMap::new(),
+                    keys: HashMap::new(),
+                }),
+            }),
+        }
+    }
+
+    /// The four-tuple of the live connection owning the key of `pkt`, if it is not `four_tuple` itself.
+    ///
+    /// Keys of connections that are no longer live are forgotten.
+    pub(crate) fn route(
+        &self,
+        four_tuple: &FourTuple,
+        pkt: &[u8],
+        is_live: &dyn Fn(&FourTuple) -> bool,
+    ) -> Option<FourTuple> {
+        self.index.route(four_tuple, pkt, is_live)
+    }
+
+    /// Let the connection of `four_tuple` own the key of `pkt`.
+    pub(crate) fn register(&self, four_tuple: &FourTuple, pkt: &[u8]) {
+        self.index.register(four_tuple, pkt);
+    }
+
+    /// Forget the keys of connections that are no longer live.
+    pub(crate) fn purge(&self, is_live: &dyn Fn(&FourTuple) -> bool) {
+        self.index.purge(is_live);
+    }
+
+    /// Forget the key of the connection of `four_tuple`, which is closed.
+    pub(crate) fn forget(&self, four_tuple: &FourTuple) {
+        self.index.forget(four_tuple);
+    }
+
+    /// Move the key of the connection of `old` to its new four-tuple `new`.
+    pub(crate) fn rekey(&self, old: &FourTuple, new: FourTuple) {
+        self.index.rekey(old, new);
+    }
+}
+
+/// Object-safe part of `KeyIndex` so that the listener is not generic over the key.
+trait DemuxIndex: Send + Sync {
+    fn route(
+        &self,
+        four_tuple: &FourTuple,
+        pkt: &[u8],
+        is_live: &dyn Fn(&FourTuple) -> bool,
+    ) -> Option<FourTuple>;
+    fn register(&self, four_tuple: &FourTuple, pkt: &[u8]);
+    fn purge(&self, is_live: &dyn Fn(&FourTuple) -> bool);
+    fn forget(&self, four_tuple: &FourTuple);
+    fn rekey(&self, old: &FourTuple, new: FourTuple);
+    #[cfg(test)]
+    fn len(&self) -> usize;
+}
+
+struct KeyIndex<K, F> {
+    extractor: F,
+    keys: RwLock<Keys<K>>,
+}
+
+/// Keys by owner and owners by key, kept in step.
+struct Keys<K> {
+    owners: HashMap<K, FourTuple>,
+    /// Key of every owner, so that closing a connection drops its key right away.
+    keys: HashMap<FourTuple, K>,
+}
+impl<K: DemuxKey> Keys<K> {
+    fn insert(&mut self, key: K, four_tuple: FourTuple) {
+        if let Some(old_key) = self.keys.insert(four_tuple, key.clone()) {
+            if old_key != key {
+                self.owners.remove(&old_key);
+            }
+        }
+        if let Some(old_owner) = self.owners.insert(key, four_tuple) {
+            if old_owner != four_tuple {
+                self.keys.remove(&old_owner);
+            }
+        }
+    }
+
+    fn remove_owner(&mut self, four_tuple: &FourTuple) -> Option<K> {
+        let key = self.keys.remove(four_tuple)?;
+        self.owners.remove(&key);
+        Some(key)
+    }
+}
+impl<K, F> DemuxIndex for KeyIndex<K, F>
+where
+    K: DemuxKey,
+    F: Fn(&FourTuple, &[u8]) -> K + Send + Sync,
+{
+    fn route(
+        &self,
+        four_tuple: &FourTuple,
+        pkt: &[u8],
+        is_live: &dyn Fn(&FourTuple) -> bool,
+    ) -> Option<FourTuple> {
+        let key = (self.extractor)(four_tuple, pkt);
+        let owner = *self.keys.read().unwrap().owners.get(&key)?;
+        if owner == *four_tuple {
+            return None;
+        }
+        if !is_live(&owner) {
+            let mut keys = self.keys.write().unwrap();
+            // Another thread may have registered a new owner in the meantime.
+            if keys.owners.get(&key) == Some(&owner) {
+                keys.remove_owner(&owner);
+            }
+            return None;
+        }
+        Some(owner)
+    }
+
+    fn register(&self, four_tuple: &FourTuple, pkt: &[u8]) {
+        let key = (self.extractor)(four_tuple, pkt);
+        self.keys.write().unwrap().insert(key, *four_tuple);
+    }
+
+    fn purge(&self, is_live: &dyn Fn(&FourTuple) -> bool) {
+        let mut keys = self.keys.write().unwrap();
+        let Keys { owners, keys } = &mut *keys;
+        owners.retain(|_, four_tuple| is_live(four_tuple));
+        keys.retain(|four_tuple, _| is_live(four_tuple));
+    }
+
+    fn forget(&self, four_tuple: &FourTuple) {
+        self.keys.write().unwrap().remove_owner(four_tuple);
+    }
+
+    fn rekey(&self, old: &FourTuple, new: FourTuple) {
+        let mut keys = self.keys.write().unwrap();
+        if let Some(key) = keys.remove_owner(old) {
+            keys.insert(key, new);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        let keys = self.keys.read().unwrap();
+        assert_eq!(keys.owners.len(), keys.keys.len());
+        keys.owners.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::channel::{FuturesChannel, ListenerChan};
+
+    fn four_tuple(remote_port: u16) -> FourTuple {
+        FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), remote_port),
+        }
+    }
+
+    #[test]
+    fn test_forget_keys_of_closed_conns() {
+        let demux = Arc::new(Demux::by_key(|_, pkt: &[u8]| pkt.first().copied()));
+        let chan = ListenerChan::<FuturesChannel>::default().with_demux(Arc::clone(&demux));
+        let is_live = |four_tuple: &FourTuple| chan.contains(four_tuple);
+
+        let conn_1 = chan.create_early_pkt_chan(four_tuple(1)).unwrap();
+        demux.register(&four_tuple(1), &[1]);
+        let mut conn_2 = chan.create_early_pkt_chan(four_tuple(2)).unwrap();
+        demux.register(&four_tuple(2), &[2]);
+        assert_eq!(demux.index.len(), 2);
+
+        // The key follows its connection to a new four-tuple.
+        conn_2.rekey(four_tuple(3)).unwrap();
+        assert_eq!(
+            demux.route(&four_tuple(4), &[2], &is_live),
+            Some(four_tuple(3))
+        );
+        assert_eq!(demux.index.len(), 2);
+
+        // Closing a connection drops its key without waiting for a lookup or a purge.
+        drop(conn_1);
+        assert_eq!(demux.index.len(), 1);
+        assert!(chan.evict(&four_tuple(3)));
+        assert_eq!(demux.index.len(), 0);
+        drop(conn_2);
+    }
+}
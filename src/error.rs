@@ -0,0 +1,118 @@
+use std::{fmt, io, net::SocketAddr};
+
+/// Why receiving or accepting a packet failed; carried by the `io::Error` of `recv_from_to*`, `UdpListener::accept*` and `UdpConn::recv*`.
+///
+/// Recover it by `UdpAcceptError::from_io_error`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UdpAcceptError {
+    /// `recvmsg` returned no `IP_PKTINFO`/`IPV6_PKTINFO`, so the local address is unknown.
+    MissingPktInfo,
+    /// `recvmsg` truncated the control messages holding the local address.
+    CtrlTruncated,
+    /// `recvmsg` returned no remote address.
+    MissingRemoteAddr,
+    /// `recvmsg` returned a remote address that is not an IP address.
+    InvalidRemoteAddr {
+        family: i32,
+    },
+    /// The socket is not bound to an IP address.
+    NotIpSocket,
+    /// Creating or setting the options of the connection socket failed.
+    ConnSocket {
+        source: io::Error,
+    },
+    /// The connection socket could not be created for lack of file descriptors, by `EMFILE` or `ENFILE`.
+    ///
+    /// A signal to shed load rather than to retry right away.
+    FdExhausted {
+        source: io::Error,
+    },
+    /// Binding the connection socket to the local address failed.
+    ConnBind {
+        source: io::Error,
+        addr: SocketAddr,
+    },
+    /// Connecting the connection socket to the remote address failed.
+    ConnConnect {
+        source: io::Error,
+        addr: SocketAddr,
+    },
+    Io(io::Error),
+}
+impl fmt::Display for UdpAcceptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPktInfo => write!(f, "recvmsg did not return a local address"),
+            Self::CtrlTruncated => write!(
+                f,
+                "recvmsg truncated the control messages holding the local address"
+            ),
+            Self::MissingRemoteAddr => write!(f, "recvmsg did not return a remote address"),
+            Self::InvalidRemoteAddr { family } => write!(
+                f,
+                "recvmsg returned a remote address of the non-IP family {family}"
+            ),
+            Self::NotIpSocket => write!(f, "socket address is not an IP address"),
+            Self::ConnSocket { source } => {
+                write!(f, "failed to set up the connection socket: {source}")
+            }
+            Self::FdExhausted { source } => {
+                write!(
+                    f,
+                    "out of file descriptors for the connection socket: {source}"
+                )
+            }
+            Self::ConnBind { source, addr } => {
+                write!(
+                    f,
+                    "failed to bind the connection socket to {addr}: {source}"
+                )
+            }
+            Self::ConnConnect { source, addr } => {
+                write!(
+                    f,
+                    "failed to connect the connection socket to {addr}: {source}"
+                )
+            }
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for UdpAcceptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ConnSocket { source }
+            | Self::FdExhausted { source }
+            | Self::ConnBind { source, .. }
+            | Self::ConnConnect { source, .. }
+            | Self::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for UdpAcceptError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<UdpAcceptError> for io::Error {
+    fn from(e: UdpAcceptError) -> Self {
+        let kind = match e {
+            UdpAcceptError::Io(e) => return e,
+            UdpAcceptError::ConnSocket { ref source }
+            | UdpAcceptError::FdExhausted { ref source }
+            | UdpAcceptError::ConnBind { ref source, .. }
+            | UdpAcceptError::ConnConnect { ref source, .. } => source.kind(),
+            UdpAcceptError::NotIpSocket => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+impl UdpAcceptError {
+    /// The `UdpAcceptError` carried by `err`, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
@@ -1,7 +1,34 @@
+//! Accept UDP "connections" on one listening socket, each flow identified by its `FourTuple`.
+//!
+//! The listener, its connections and channels, and `send` are Unix-only.
+//! Windows only gets the receive path of `recv`: `recv::recv_from_to` and its variants by `WSARecvMsg`,
+//! which can be checked from any host by `cargo check --target x86_64-pc-windows-gnu`.
+
+#[cfg(not(any(unix, windows)))]
+compile_error!(
+    "udp_acceptable only supports Unix and Windows: it needs `recvmsg`/`WSARecvMsg` with `IP_PKTINFO`/`IPV6_PKTINFO`"
+);
+
+// The listener is built on Unix socket APIs.
+#[cfg(unix)]
 pub mod channel;
+#[cfg(unix)]
 mod conn;
+#[cfg(unix)]
+mod demux;
+mod error;
+#[cfg(unix)]
 mod listener;
+#[cfg(unix)]
+mod rate_limit;
 pub mod recv;
+#[cfg(unix)]
+pub mod send;
 
+#[cfg(unix)]
 pub use conn::*;
+#[cfg(unix)]
+pub use demux::*;
+pub use error::*;
+#[cfg(unix)]
 pub use listener::*;
@@ -1,28 +1,314 @@
+#[cfg(feature = "tokio")]
+use std::task::{ready, Poll};
 use std::{
     borrow::Cow,
-    collections::HashSet,
-    io,
+    collections::{HashMap, HashSet},
+    fmt, io,
+    marker::PhantomData,
+    mem::{ManuallyDrop, MaybeUninit},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    os::fd::AsRawFd,
+    ops::Deref,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::Duration,
 };
 
-use futures::channel::mpsc;
-use nix::sys::socket::{
-    setsockopt,
-    sockopt::{Ipv4PacketInfo, Ipv6RecvPacketInfo},
-};
+#[cfg(feature = "tokio")]
+use futures::Stream;
+#[cfg(feature = "tokio")]
+use tokio::io::{unix::AsyncFd, Interest};
 
+#[cfg(target_os = "linux")]
+use crate::conn::{
+    getsockopt_int, set_dont_fragment, set_mtu_discover, set_recv_checksum_coverage, set_recv_err,
+    set_send_checksum_coverage,
+};
 use crate::{
-    channel::{ListenerChan, SendRes},
-    conn::UdpConn,
-    recv::{recv_from_to, FourTuple},
+    channel::{
+        ChannelBackend, ConnClosedEvents, EarlyPktStoreFactory, FuturesChannel, InsertErr,
+        ListenerChan, PktReceiver, SendRes,
+    },
+    conn::{setsockopt_int, UdpConn, UdpConnConfig, UdpConnHandle},
+    demux::Demux,
+    rate_limit::TokenBucket,
+    recv::{
+        peek_len, recv_from_to_cmsg_buf, recv_from_to_uninit, socket_fd, FourTuple, IpFamily,
+        RecvMeta, RecvOptions,
+    },
+    send::send_from_to,
+    UdpAcceptError,
 };
 
-pub struct UdpListener {
+pub struct UdpListenerConfig {
+    pub local_ip_filter: IpFilterConfig,
+    pub non_blocking: bool,
+    /// `SO_MARK` applied to the listener socket and every connection socket.
+    ///
+    /// Setting it requires `CAP_NET_ADMIN`.
+    #[cfg(target_os = "linux")]
+    pub fwmark: Option<u32>,
+    /// `SO_RCVBUF` of the listener socket.
+    pub recv_buffer_size: Option<usize>,
+    /// Set `recv_buffer_size` by `SO_RCVBUFFORCE` to exceed `net.core.rmem_max`.
+    ///
+    /// Falls back to `SO_RCVBUF` without `CAP_NET_ADMIN`.
+    #[cfg(target_os = "linux")]
+    pub force_recv_buffer_size: bool,
+    /// Set `IP_FREEBIND` on the listener and connection sockets,
+    /// so connection sockets can bind to local IPs not configured on the host (yet).
+    #[cfg(target_os = "linux")]
+    pub freebind: bool,
+    /// Set `IP_TRANSPARENT` on the listener and connection sockets, e.g. for TPROXY setups.
+    ///
+    /// The four-tuple of a redirected packet carries its original destination, port included,
+    /// and the connection socket binds to it, so replies leave with that destination as their source.
+    ///
+    /// Requires `CAP_NET_ADMIN`.
+    #[cfg(target_os = "linux")]
+    pub transparent: bool,
+    /// Set `SO_REUSEPORT` on the listener socket of `UdpListener::bind_with_config`,
+    /// so that several listeners, e.g. one per core, share the port.
+    #[cfg(target_os = "linux")]
+    pub reuse_port: bool,
+    /// `SO_INCOMING_CPU` of the listener socket: its reuseport group hands it the packets the CPU received.
+    #[cfg(target_os = "linux")]
+    pub incoming_cpu: Option<usize>,
+    /// Classic BPF program attached to the reuseport group of the listener socket by `SO_ATTACH_REUSEPORT_CBPF`.
+    ///
+    /// It returns the index of the socket in the group, in bind order, that gets the packet. Needs `reuse_port`.
+    #[cfg(target_os = "linux")]
+    pub reuseport_cbpf: Option<Vec<libc::sock_filter>>,
+    /// Upper bound on the number of live connections.
+    ///
+    /// `accept_raw` returns `AcceptRes::TableFull` instead of creating a socket beyond it.
+    pub max_connections: Option<usize>,
+    /// Upper bound on the number of live connections from the same remote IP.
+    ///
+    /// `accept_raw` returns `AcceptRes::IpConnLimit` instead of creating a socket beyond it.
+    pub max_conns_per_ip: Option<usize>,
+    /// Capacity of the queue behind `UdpListener::conn_closed_events`.
+    ///
+    /// `None` disables the events.
+    pub conn_closed_events: Option<usize>,
+    /// Rate of new connections the listener creates, across all peers.
+    ///
+    /// `accept_raw` returns `AcceptRes::RateLimited` for new connections beyond it.
+    /// Packets for existing connections are not limited.
+    pub max_new_conns_per_sec: Option<u32>,
+    /// Number of new connections allowed in a burst.
+    ///
+    /// Defaults to `max_new_conns_per_sec`.
+    pub new_conns_burst: Option<u32>,
+    /// Configuration of every accepted connection.
+    pub conn: UdpConnConfig,
+    /// Minimum length of the first packet of a new connection.
+    ///
+    /// `accept_raw` returns `AcceptRes::TooShort` for shorter packets without creating a socket.
+    /// Packets for existing connections are not checked.
+    pub min_first_packet_len: Option<usize>,
+    /// Make `accept` skip zero-length datagrams of unknown four-tuples and wait for the next datagram
+    /// instead of creating a connection.
+    ///
+    /// Zero-length datagrams of existing connections are still delivered; `accept_raw` is not affected.
+    pub ignore_empty_datagrams: bool,
+    /// See `RecvOptions::local_addr_fallback`.
+    pub local_addr_fallback: bool,
+    /// Chain of filters on the first packet of every new connection, evaluated in order.
+    ///
+    /// See `UdpListener::push_accept_filter`.
+    pub accept_filters: Vec<Box<dyn AcceptFilter>>,
+    /// Validate the source of every new connection before any state is kept for it.
+    ///
+    /// `Validation::Reply` sends a packet back from the listener socket and keeps no state,
+    /// which allows stateless address validation such as cookie or retry handshakes.
+    pub validator: Option<Validator>,
+    /// Whether every connection gets its own socket.
+    pub conn_strategy: ConnStrategy,
+    /// Route packets by a key other than the four-tuple, e.g. to follow roaming peers.
+    ///
+    /// A packet from an unknown four-tuple whose key belongs to a live connection is delivered to that connection
+    /// and reported as `AcceptRes::RoutedByKey`. `None` routes by the four-tuple only.
+    ///
+    /// The key of a connection is forgotten as soon as the connection is dropped or evicted.
+    pub demux: Option<Demux>,
+    /// Only accept packets arriving on these interfaces, by index; see `recv::if_name_to_index`.
+    ///
+    /// The `accept*` methods report other packets as `AcceptRes::Filtered`.
+    /// `accept_raw` does not know the interface and skips the check.
+    pub allowed_ifindexes: Option<HashSet<u32>>,
+    /// Drop packets by their remote IP, for new and existing connections alike.
+    ///
+    /// `accept_raw` returns `AcceptRes::RemoteFiltered` for dropped packets.
+    pub remote_ip_filter: Option<RemoteIpFilter>,
+    /// Accept packets sent to a broadcast address, e.g. for SSDP- or DHCP-style responders.
+    ///
+    /// Broadcast destinations pass the local IP filter, and their flows share the listener socket
+    /// as `AcceptRes::Shared` since no socket can be connected from a broadcast address.
+    /// `SO_BROADCAST` is set on the listener socket so that `UdpListener::send_to` can reach broadcast addresses.
+    ///
+    /// Subnet broadcast addresses are learned from the interfaces at bind time; see `UdpListener::refresh_broadcast_addrs`.
+    pub allow_broadcast: bool,
+    /// Transport protocol of the listener and connection sockets.
+    pub protocol: UdpProtocol,
+    /// Number of early packets buffered per connection before more are dropped.
+    ///
+    /// Defaults to one. `ConnStrategy::SharedSocket` uses its `queue_len` instead.
+    pub early_pkt_buffer: Option<usize>,
+    /// Bytes of early packets queued across all connections before more are dropped.
+    ///
+    /// `accept_raw` reports refused packets of existing connections as `AcceptRes::ConnAlreadyExists { delivered: false }`;
+    /// see `UdpListener::early_pkt_over_budget_count`.
+    pub early_pkt_budget: Option<usize>,
+    /// Drop packets of four-tuples whose connection was dropped or evicted within this window,
+    /// e.g. retransmits of a dead flow, instead of accepting them as a new connection, like TCP's `TIME_WAIT`.
+    ///
+    /// `accept_raw` returns `AcceptRes::Filtered` for them. `None` accepts them right away.
+    pub recently_closed_window: Option<Duration>,
+    /// Keep the early packet channels of the connections in custom stores, e.g. to track them elsewhere.
+    ///
+    /// The map is split into shards, each holding the store the factory makes for it.
+    /// `None` keeps them in `HashMap`s.
+    pub early_pkt_store: Option<EarlyPktStoreFactory>,
+    /// Times the connection socket is set up again after its bind or connect failed with `EADDRINUSE`,
+    /// e.g. while a just-closed connection of the same four-tuple is still being released.
+    ///
+    /// On a blocking listener, retries are a millisecond apart, so `accept_raw` blocks for up to that many milliseconds.
+    /// A non-blocking listener retries at once, never stalling an async accept loop.
+    pub conn_bind_retries: usize,
+    /// Admit new connections as `AcceptRes::Shared` on the listener socket
+    /// while no connection socket can be created for lack of file descriptors.
+    ///
+    /// The shared socket is duplicated at bind time so that the fallback itself needs no descriptor.
+    /// Without it, `accept_raw` returns `AcceptRes::ConnFailed` with `UdpAcceptError::FdExhausted`.
+    pub shared_socket_on_fd_exhaustion: bool,
+}
+impl UdpListenerConfig {
+    pub fn new(local_ip_filter: IpFilterConfig, non_blocking: bool) -> Self {
+        Self {
+            local_ip_filter,
+            non_blocking,
+            #[cfg(target_os = "linux")]
+            fwmark: None,
+            recv_buffer_size: None,
+            #[cfg(target_os = "linux")]
+            force_recv_buffer_size: false,
+            #[cfg(target_os = "linux")]
+            freebind: false,
+            #[cfg(target_os = "linux")]
+            transparent: false,
+            #[cfg(target_os = "linux")]
+            reuse_port: false,
+            #[cfg(target_os = "linux")]
+            incoming_cpu: None,
+            #[cfg(target_os = "linux")]
+            reuseport_cbpf: None,
+            max_connections: None,
+            max_conns_per_ip: None,
+            conn_closed_events: None,
+            max_new_conns_per_sec: None,
+            new_conns_burst: None,
+            conn: UdpConnConfig::default(),
+            min_first_packet_len: None,
+            ignore_empty_datagrams: false,
+            local_addr_fallback: false,
+            accept_filters: Vec::new(),
+            validator: None,
+            conn_strategy: ConnStrategy::default(),
+            demux: None,
+            allowed_ifindexes: None,
+            remote_ip_filter: None,
+            allow_broadcast: false,
+            protocol: UdpProtocol::default(),
+            early_pkt_buffer: None,
+            early_pkt_budget: None,
+            recently_closed_window: None,
+            early_pkt_store: None,
+            conn_bind_retries: 0,
+            shared_socket_on_fd_exhaustion: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpProtocol {
+    #[default]
+    Udp,
+    /// UDP-Lite, whose checksum may cover only a prefix of the packet; see `UdpListener::set_recv_checksum_coverage`.
+    #[cfg(target_os = "linux")]
+    UdpLite,
+}
+impl UdpProtocol {
+    fn to_socket2(self) -> socket2::Protocol {
+        match self {
+            Self::Udp => socket2::Protocol::UDP,
+            #[cfg(target_os = "linux")]
+            Self::UdpLite => socket2::Protocol::from(libc::IPPROTO_UDPLITE),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnStrategy {
+    /// `accept_raw` returns `AcceptRes::Ok` with a `UdpConn` owning a connected socket.
+    #[default]
+    OwnSocket,
+    /// `accept_raw` returns `AcceptRes::Shared` with a `UdpConnHandle` sharing the listener socket.
+    ///
+    /// No socket is created per connection, so every packet goes through the listener
+    /// and is buffered in the connection's channel, up to `queue_len` packets.
+    SharedSocket { queue_len: usize },
+}
+
+/// `UdpListener` is `Send + Sync`.
+///
+/// The `accept*` methods take `&self`, so several threads can accept on the same listener concurrently,
+/// each with its own receive buffer.
+/// The listener packet receiver stays single-consumer since polling it requires `&mut self`.
+///
+/// The early packet and listener packet channels run on the queues of `B`; see `ChannelBackend`.
+pub struct UdpListener<B: ChannelBackend = FuturesChannel> {
     socket: socket2::Socket,
-    chan: ListenerChan,
-    local_ip_filter: IpFilter,
+    domain: socket2::Domain,
+    protocol: UdpProtocol,
+    chan: ListenerChan<B>,
+    /// Port the listener socket is bound to, resolved once since it cannot change after bind.
+    local_port: u16,
+    local_ip_filter: RwLock<IpFilter>,
     non_blocking: bool,
+    #[cfg(target_os = "linux")]
+    fwmark: Option<u32>,
+    #[cfg(target_os = "linux")]
+    freebind: bool,
+    #[cfg(target_os = "linux")]
+    transparent: bool,
+    recv_errors: Mutex<HashMap<io::ErrorKind, u64>>,
+    new_conn_limiter: Option<TokenBucket>,
+    rate_limited: AtomicU64,
+    conn_config: UdpConnConfig,
+    accept_filters: Vec<Box<dyn AcceptFilter>>,
+    min_first_packet_len: Option<usize>,
+    too_short: AtomicU64,
+    ignore_empty_datagrams: bool,
+    conn_bind_retries: usize,
+    shared_socket_on_fd_exhaustion: bool,
+    validator: Option<Validator>,
+    recv_options: RecvOptions,
+    /// Control message buffer reused by the `accept*` methods.
+    cmsg_buf: Mutex<Vec<u8>>,
+    last_cmsg_bytes: AtomicUsize,
+    /// The listener socket shared with `UdpConnHandle`s, in `ConnStrategy::SharedSocket`.
+    shared_socket: Option<Arc<socket2::Socket>>,
+    /// The listener socket shared, in `ConnStrategy::OwnSocket`, with the `UdpConnHandle`s of flows without a socket of their own:
+    /// multicast and broadcast flows, and flows admitted by `UdpListenerConfig::shared_socket_on_fd_exhaustion`.
+    shared_listener_socket: OnceLock<Arc<socket2::Socket>>,
+    /// Subnet broadcast addresses, if `UdpListenerConfig::allow_broadcast`.
+    broadcast_addrs: Option<RwLock<HashSet<Ipv4Addr>>>,
+    remote_ip_filter: Option<RemoteIpFilter>,
+    allowed_ifindexes: Option<HashSet<u32>>,
+    demux: Option<Arc<Demux>>,
 }
 impl UdpListener {
     pub fn bind(
@@ -30,368 +316,5388 @@ impl UdpListener {
         local_ip_filter: IpFilterConfig,
         non_blocking: bool,
     ) -> io::Result<Self> {
+        Self::bind_with_config(port, UdpListenerConfig::new(local_ip_filter, non_blocking))
+    }
+
+    pub fn bind_with_config(port: u16, config: UdpListenerConfig) -> io::Result<Self> {
+        Self::bind_with_backend(port, config)
+    }
+
+    /// Adopt the bound UDP socket `fd`, e.g. from systemd socket activation.
+    ///
+    /// Connection sockets bind to the same port, so `fd` should have `SO_REUSEADDR` set.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open socket owned by the caller; the listener takes over its ownership
+    /// and closes it when dropped, including when this function fails.
+    pub unsafe fn from_raw_fd(
+        fd: RawFd,
+        local_ip_filter: IpFilterConfig,
+        non_blocking: bool,
+    ) -> io::Result<Self> {
+        // SAFETY: the caller hands over the ownership of `fd`.
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        let local_addr = socket.local_addr()?.as_socket().ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket is not an IP socket",
+        ))?;
+        if local_addr.port() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "socket is not bound",
+            ));
+        }
+        let is_same_family = match local_addr {
+            SocketAddr::V4(_) => local_ip_filter.domain() == socket2::Domain::IPV4,
+            SocketAddr::V6(_) => local_ip_filter.domain() == socket2::Domain::IPV6,
+        };
+        if !is_same_family {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "socket address family does not match the local IP filter",
+            ));
+        }
+        Self::with_socket(
+            socket,
+            UdpListenerConfig::new(local_ip_filter, non_blocking),
+        )
+    }
+}
+impl<B: ChannelBackend> UdpListener<B> {
+    /// `bind_with_config` with the early packet and listener packet channels on `B`,
+    /// e.g. `UdpListener::<StdChannel>::bind_with_backend` to receive them by blocking threads,
+    /// or `UdpListener::<TokioChannel>::bind_with_backend` to `recv().await` them on tokio.
+    pub fn bind_with_backend(port: u16, config: UdpListenerConfig) -> io::Result<Self> {
+        let domain = config.local_ip_filter.domain();
         let socket = socket2::Socket::new(
-            match local_ip_filter {
-                IpFilterConfig::V4(_) => socket2::Domain::IPV4,
-                IpFilterConfig::V6(_) => socket2::Domain::IPV6,
-            },
+            domain,
             socket2::Type::DGRAM,
-            Some(socket2::Protocol::UDP),
+            Some(config.protocol.to_socket2()),
         )?;
-        let listen_addr = match local_ip_filter {
-            IpFilterConfig::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
-            IpFilterConfig::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        let listen_addr = match domain {
+            socket2::Domain::IPV4 => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            _ => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
         };
-        socket.set_nonblocking(non_blocking)?;
         socket.set_reuse_address(true)?;
-        match local_ip_filter {
-            IpFilterConfig::V4(_) => {
-                setsockopt(socket.as_raw_fd(), Ipv4PacketInfo, &true)?;
+        #[cfg(target_os = "linux")]
+        if config.reuse_port {
+            setsockopt_int(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        #[cfg(target_os = "linux")]
+        set_freebind_transparent(&socket, config.freebind, config.transparent)?;
+        socket.bind(&listen_addr.into())?;
+        Self::with_socket(socket, config)
+    }
+
+    /// Configure the bound `socket` by `config`.
+    fn with_socket(socket: socket2::Socket, config: UdpListenerConfig) -> io::Result<Self> {
+        let UdpListenerConfig {
+            local_ip_filter,
+            non_blocking,
+            #[cfg(target_os = "linux")]
+            fwmark,
+            recv_buffer_size,
+            #[cfg(target_os = "linux")]
+            force_recv_buffer_size,
+            #[cfg(target_os = "linux")]
+            freebind,
+            #[cfg(target_os = "linux")]
+            transparent,
+            #[cfg(target_os = "linux")]
+            reuse_port,
+            #[cfg(target_os = "linux")]
+            incoming_cpu,
+            #[cfg(target_os = "linux")]
+            reuseport_cbpf,
+            max_connections,
+            max_conns_per_ip,
+            conn_closed_events,
+            max_new_conns_per_sec,
+            new_conns_burst,
+            conn,
+            min_first_packet_len,
+            ignore_empty_datagrams,
+            local_addr_fallback,
+            accept_filters,
+            validator,
+            conn_bind_retries,
+            shared_socket_on_fd_exhaustion,
+            conn_strategy,
+            demux,
+            allowed_ifindexes,
+            remote_ip_filter,
+            allow_broadcast,
+            protocol,
+            early_pkt_buffer,
+            early_pkt_budget,
+            recently_closed_window,
+            early_pkt_store,
+        } = config;
+        let domain = local_ip_filter.domain();
+        let local_port = socket
+            .local_addr()?
+            .as_socket()
+            .ok_or(UdpAcceptError::NotIpSocket)?
+            .port();
+        socket.set_nonblocking(non_blocking)?;
+        match domain {
+            socket2::Domain::IPV4 => {
+                setsockopt_int(&socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
             }
-            IpFilterConfig::V6(_) => {
-                setsockopt(socket.as_raw_fd(), Ipv6RecvPacketInfo, &true)?;
+            _ => {
+                setsockopt_int(&socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
             }
         }
-        socket.bind(&listen_addr.into())?;
+        // The original destination of TPROXY-redirected packets keeps its port.
+        #[cfg(target_os = "linux")]
+        if transparent {
+            match domain {
+                socket2::Domain::IPV4 => {
+                    setsockopt_int(&socket, libc::IPPROTO_IP, libc::IP_RECVORIGDSTADDR, 1)?
+                }
+                _ => setsockopt_int(&socket, libc::IPPROTO_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?,
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = fwmark {
+            set_mark(&socket, mark)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cpu) = incoming_cpu {
+            let cpu = cpu.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "incoming CPU out of range")
+            })?;
+            setsockopt_int(&socket, libc::SOL_SOCKET, libc::SO_INCOMING_CPU, cpu)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(filter) = &reuseport_cbpf {
+            if !reuse_port {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a reuseport BPF program needs `reuse_port`",
+                ));
+            }
+            attach_reuseport_cbpf(&socket, filter)?;
+        }
+        if let Some(size) = recv_buffer_size {
+            #[cfg(target_os = "linux")]
+            let forced = force_recv_buffer_size
+                && match setsockopt_int(
+                    &socket,
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVBUFFORCE,
+                    size.try_into().unwrap_or(libc::c_int::MAX),
+                ) {
+                    Ok(()) => true,
+                    Err(e) if e.raw_os_error() == Some(libc::EPERM) => false,
+                    Err(e) => return Err(e),
+                };
+            #[cfg(not(target_os = "linux"))]
+            let forced = false;
+            if !forced {
+                socket.set_recv_buffer_size(size)?;
+            }
+        }
+        let broadcast_addrs = match allow_broadcast {
+            true => {
+                socket.set_broadcast(true)?;
+                Some(RwLock::new(interface_broadcast_addrs()?))
+            }
+            false => None,
+        };
+        let mut chan = ListenerChan::new(max_connections, max_conns_per_ip);
+        if let Some(capacity) = conn_closed_events {
+            chan = chan.with_conn_closed_events(capacity);
+        }
+        if let Some(buffer) = early_pkt_buffer {
+            chan = chan.with_early_pkt_buffer(buffer);
+        }
+        if let Some(bytes) = early_pkt_budget {
+            chan = chan.with_early_pkt_budget(bytes);
+        }
+        if let Some(window) = recently_closed_window {
+            chan = chan.with_recently_closed(window);
+        }
+        let demux = demux.map(Arc::new);
+        if let Some(demux) = &demux {
+            chan = chan.with_demux(Arc::clone(demux));
+        }
+        if let Some(new_store) = early_pkt_store {
+            chan = chan.with_early_pkt_store(new_store);
+        }
+        let shared_socket = match conn_strategy {
+            ConnStrategy::OwnSocket => None,
+            ConnStrategy::SharedSocket { queue_len } => {
+                chan = chan.with_early_pkt_buffer(queue_len);
+                Some(Arc::new(socket.try_clone()?))
+            }
+        };
+        let shared_listener_socket = OnceLock::new();
+        if shared_socket_on_fd_exhaustion {
+            let _ = shared_listener_socket.set(Arc::new(socket.try_clone()?));
+        }
         Ok(Self {
             socket,
-            chan: ListenerChan::new(),
-            local_ip_filter: local_ip_filter.build(),
+            domain,
+            protocol,
+            chan,
+            local_port,
+            local_ip_filter: RwLock::new(local_ip_filter.build()),
             non_blocking,
+            #[cfg(target_os = "linux")]
+            fwmark,
+            #[cfg(target_os = "linux")]
+            freebind,
+            #[cfg(target_os = "linux")]
+            transparent,
+            recv_errors: Mutex::new(HashMap::new()),
+            new_conn_limiter: max_new_conns_per_sec
+                .map(|rate| TokenBucket::new(rate, new_conns_burst.unwrap_or(rate))),
+            rate_limited: AtomicU64::new(0),
+            conn_config: conn,
+            accept_filters,
+            min_first_packet_len,
+            too_short: AtomicU64::new(0),
+            ignore_empty_datagrams,
+            conn_bind_retries,
+            shared_socket_on_fd_exhaustion,
+            validator,
+            recv_options: RecvOptions {
+                local_addr_fallback,
+                family: Some(match domain {
+                    socket2::Domain::IPV4 => IpFamily::V4,
+                    _ => IpFamily::V6,
+                }),
+                #[cfg(target_os = "linux")]
+                orig_dst_addr: transparent,
+                ..Default::default()
+            },
+            cmsg_buf: Mutex::new(Vec::new()),
+            last_cmsg_bytes: AtomicUsize::new(0),
+            shared_socket,
+            shared_listener_socket,
+            broadcast_addrs,
+            remote_ip_filter,
+            allowed_ifindexes,
+            demux,
         })
     }
 
     /// <https://blog.cloudflare.com/everything-you-ever-wanted-to-know-about-udp-sockets-but-were-afraid-to-ask-part-1/>
-    pub fn accept(&self, rx_buf: &mut [u8]) -> io::Result<(AcceptRes, FourTuple, usize)> {
-        let local_port = self.local_port()?;
-        let (four_tuple, len) = recv_from_to(self.socket.as_raw_fd(), rx_buf, local_port)?;
+    pub fn accept(&self, rx_buf: &mut [u8]) -> io::Result<(AcceptRes<B>, FourTuple, usize)> {
+        let (res, four_tuple, len, _) = self.accept_with_meta(rx_buf)?;
+        Ok((res, four_tuple, len))
+    }
 
-        let conn = self.accept_raw(&four_tuple, Cow::from(&rx_buf[..len]))?;
+    /// `accept` that also returns what `recvmsg` reported about the packet.
+    pub fn accept_with_meta(
+        &self,
+        rx_buf: &mut [u8],
+    ) -> io::Result<(AcceptRes<B>, FourTuple, usize, RecvMeta)> {
+        let (four_tuple, len, meta) = self.recv(rx_buf)?;
 
-        Ok((conn, four_tuple, len))
+        let conn = self.accept_received(&four_tuple, &meta, Cow::from(&rx_buf[..len]))?;
+
+        Ok((conn, four_tuple, len, meta))
     }
 
-    pub fn accept_owned(&self, mut rx_buf: Vec<u8>) -> io::Result<(AcceptRes, FourTuple, usize)> {
-        let local_port = self.local_port()?;
-        let (four_tuple, len) = recv_from_to(self.socket.as_raw_fd(), &mut rx_buf, local_port)?;
+    /// `accept_with_meta` that receives the control messages into `cmsg_buf`
+    /// instead of the buffer owned by the listener.
+    ///
+    /// `cmsg_buf` is cleared rather than reallocated, so a buffer kept per accepting thread is allocated only once.
+    pub fn accept_with_cmsg_buf(
+        &self,
+        rx_buf: &mut [u8],
+        cmsg_buf: &mut Vec<u8>,
+    ) -> io::Result<(AcceptRes<B>, FourTuple, usize, RecvMeta)> {
+        let (four_tuple, len, meta) = self.recv_with_cmsg_buf(rx_buf, cmsg_buf)?;
+
+        let conn = self.accept_received(&four_tuple, &meta, Cow::from(&rx_buf[..len]))?;
+
+        Ok((conn, four_tuple, len, meta))
+    }
+
+    pub fn accept_owned(
+        &self,
+        mut rx_buf: Vec<u8>,
+    ) -> io::Result<(AcceptRes<B>, FourTuple, usize)> {
+        let (four_tuple, len, meta) = self.recv(&mut rx_buf)?;
 
         rx_buf.truncate(len);
 
-        let conn = self.accept_raw(&four_tuple, Cow::from(rx_buf))?;
+        let conn = self.accept_received(&four_tuple, &meta, Cow::from(rx_buf))?;
 
         Ok((conn, four_tuple, len))
     }
 
-    pub fn recv_listener_pkt(&self) -> &mpsc::Receiver<(FourTuple, Vec<u8>)> {
+    /// Create the connection of `four_tuple` before its first packet arrives, e.g. for NAT hole punching.
+    ///
+    /// The connection gets its own socket in every `ConnStrategy`.
+    /// Packets of `four_tuple` reaching the listener later are delivered to it as early packets.
+    ///
+    /// Fails with `io::ErrorKind::AlreadyExists` if a connection of `four_tuple` exists.
+    pub fn register(&self, four_tuple: FourTuple) -> io::Result<UdpConn<B>> {
+        self.check_four_tuple_family(&four_tuple)?;
+        let socket = self.conn_socket(&four_tuple)?;
+        let conn_chan = self
+            .chan
+            .create_early_pkt_chan(four_tuple)
+            .map_err(|e| match e {
+                InsertErr::AlreadyExists => io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "connection of the four-tuple already exists",
+                ),
+                InsertErr::Full => io::Error::other("connection table is full"),
+                InsertErr::IpFull => {
+                    io::Error::other("connection limit of the remote IP is reached")
+                }
+            })?;
+        Ok(UdpConn::new(
+            socket,
+            four_tuple,
+            conn_chan,
+            self.conn_config.clone(),
+        ))
+    }
+
+    /// Dial `remote` from `local_ip` on the listener port, e.g. for UDP simultaneous open.
+    ///
+    /// The connection is registered like `register`,
+    /// so replies of `remote` reaching the listener are delivered to it.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if the local IP filter drops `local_ip`.
+    pub fn connect_out(&self, remote: SocketAddr, local_ip: IpAddr) -> io::Result<UdpConn<B>> {
+        self.check_ip_family(&local_ip)?;
+        if !self.local_ip_filter.read().unwrap().pass(&local_ip) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local IP is dropped by the local IP filter",
+            ));
+        }
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(local_ip, self.local_port),
+            remote_addr: remote,
+        };
+        self.register(four_tuple)
+    }
+
+    /// Size of the next datagram queued on the listener socket, to size the buffer of `accept_owned`.
+    pub fn peek_len(&self) -> io::Result<usize> {
+        peek_len(socket_fd(&self.socket))
+    }
+
+    /// `accept` but without requiring `rx_buf` to be initialized.
+    ///
+    /// Returns the initialized prefix of `rx_buf` holding the received packet.
+    pub fn accept_uninit<'a>(
+        &self,
+        rx_buf: &'a mut [MaybeUninit<u8>],
+    ) -> io::Result<(AcceptRes<B>, FourTuple, &'a mut [u8])> {
+        let (four_tuple, len, meta) = loop {
+            let (four_tuple, pkt, meta) = self.count_recv_err(recv_from_to_uninit(
+                socket_fd(&self.socket),
+                rx_buf,
+                self.local_port,
+                &self.recv_options,
+            ))?;
+            if !self.is_ignored_empty(&four_tuple, pkt.len()) {
+                break (four_tuple, pkt.len(), meta);
+            }
+        };
+        // SAFETY: `recvmsg` initialized the first `len` bytes.
+        let pkt = unsafe { std::slice::from_raw_parts_mut(rx_buf.as_mut_ptr().cast::<u8>(), len) };
+
+        let conn = self.accept_received(&four_tuple, &meta, Cow::from(&pkt[..]))?;
+
+        Ok((conn, four_tuple, pkt))
+    }
+
+    /// Receive a packet with the control message buffer of the listener.
+    ///
+    /// Falls back to a fresh buffer if another thread is using it.
+    fn recv(&self, rx_buf: &mut [u8]) -> io::Result<(FourTuple, usize, RecvMeta)> {
+        match self.cmsg_buf.try_lock() {
+            Ok(mut cmsg_buf) => self.recv_with_cmsg_buf(rx_buf, &mut cmsg_buf),
+            Err(_) => self.recv_with_cmsg_buf(rx_buf, &mut Vec::new()),
+        }
+    }
+
+    fn recv_with_cmsg_buf(
+        &self,
+        rx_buf: &mut [u8],
+        cmsg_buf: &mut Vec<u8>,
+    ) -> io::Result<(FourTuple, usize, RecvMeta)> {
+        loop {
+            let (four_tuple, len, meta) = self.count_recv_err(recv_from_to_cmsg_buf(
+                socket_fd(&self.socket),
+                rx_buf,
+                self.local_port,
+                &self.recv_options,
+                cmsg_buf,
+            ))?;
+            if !self.is_ignored_empty(&four_tuple, len) {
+                return Ok((four_tuple, len, meta));
+            }
+        }
+    }
+
+    /// Whether a datagram of `len` bytes is skipped by `UdpListenerConfig::ignore_empty_datagrams`.
+    fn is_ignored_empty(&self, four_tuple: &FourTuple, len: usize) -> bool {
+        self.ignore_empty_datagrams && len == 0 && !self.chan.contains(four_tuple)
+    }
+
+    /// `accept_raw` after the checks that need what `recvmsg` reported.
+    fn accept_received(
+        &self,
+        four_tuple: &FourTuple,
+        meta: &RecvMeta,
+        rx_buf: Cow<[u8]>,
+    ) -> io::Result<AcceptRes<B>> {
+        self.last_cmsg_bytes.store(meta.cmsg_len, Ordering::Relaxed);
+        if let Some(allowed_ifindexes) = &self.allowed_ifindexes {
+            let allowed = meta
+                .ifindex
+                .is_some_and(|ifindex| allowed_ifindexes.contains(&ifindex));
+            if !allowed {
+                return Ok(AcceptRes::Filtered);
+            }
+        }
+        self.accept_raw(four_tuple, rx_buf)
+    }
+
+    /// Repeatedly `accept`, yielding only newly created connections and `AcceptRes::ConnFailed` as errors.
+    ///
+    /// Packets for existing connections are routed to them as usual.
+    /// Connections in `ConnStrategy::SharedSocket` are not yielded.
+    pub fn incoming(&self, buf_size: usize) -> impl Iterator<Item = io::Result<UdpConn<B>>> + '_ {
+        let mut rx_buf = vec![0; buf_size];
+        std::iter::from_fn(move || loop {
+            match self.accept(&mut rx_buf) {
+                Ok((AcceptRes::Ok(conn), _, _)) => return Some(Ok(conn)),
+                Ok((AcceptRes::ConnFailed { err, .. }, _, _)) => return Some(Err(err.into())),
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        })
+    }
+
+    /// `incoming` as a `Stream` owning the listener, waiting for datagrams on the tokio reactor.
+    ///
+    /// The listener socket is made non-blocking and registered on the first poll,
+    /// which must happen within a tokio runtime; a failed registration is the only item.
+    /// The connections keep `UdpListenerConfig::non_blocking`.
+    #[cfg(feature = "tokio")]
+    pub fn accept_stream(self, buf_size: usize) -> impl Stream<Item = io::Result<UdpConn<B>>> {
+        let mut rx_buf = vec![0; buf_size];
+        let mut listener = Some(self);
+        let mut async_fd: Option<AsyncFd<Self>> = None;
+        futures::stream::poll_fn(move |cx| {
+            let async_fd = match &mut async_fd {
+                Some(async_fd) => async_fd,
+                None => {
+                    let Some(listener) = listener.take() else {
+                        return Poll::Ready(None);
+                    };
+                    let res = listener
+                        .socket
+                        .set_nonblocking(true)
+                        .and_then(|()| AsyncFd::with_interest(listener, Interest::READABLE));
+                    match res {
+                        Ok(fd) => async_fd.insert(fd),
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+            };
+            loop {
+                let mut guard = ready!(async_fd.poll_read_ready(cx))?;
+                // `try_io` clears the readiness on `WouldBlock`, so the next poll waits for the reactor.
+                match guard.try_io(|async_fd| async_fd.get_ref().accept(&mut rx_buf)) {
+                    Ok(Ok((AcceptRes::Ok(conn), _, _))) => return Poll::Ready(Some(Ok(conn))),
+                    Ok(Ok((AcceptRes::ConnFailed { err, .. }, _, _))) => {
+                        return Poll::Ready(Some(Err(err.into())))
+                    }
+                    Ok(Ok(_)) | Err(_) => continue,
+                    Ok(Err(e)) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+        })
+    }
+
+    /// Packets connections handed back by `UdpConn::forward_to_listener`; pass each to `accept_raw`.
+    pub fn recv_listener_pkt(&self) -> &B::Receiver<(FourTuple, Vec<u8>)> {
         self.chan.recv_listener_pkt()
     }
 
-    pub fn recv_listener_pkt_mut(&mut self) -> &mut mpsc::Receiver<(FourTuple, Vec<u8>)> {
+    pub fn recv_listener_pkt_mut(&mut self) -> &mut B::Receiver<(FourTuple, Vec<u8>)> {
         self.chan.recv_listener_pkt_mut()
     }
 
+    /// Take the next packet a connection handed back without blocking; `None` if there is none.
+    ///
+    /// The channel closes only with the listener, so `None` always means empty.
+    pub fn try_recv_listener_pkt(&mut self) -> Option<(FourTuple, Vec<u8>)> {
+        self.chan.recv_listener_pkt_mut().try_recv().ok()
+    }
+
+    /// Block the current thread until a connection hands a packet back, without an async executor.
+    ///
+    /// Waits like `UdpConn::recv_early_pkt_blocking` does on the backend `B`.
+    /// The listener keeps the channel open, so this waits for as long as no connection hands a packet back.
+    pub fn recv_listener_pkt_blocking(&mut self) -> (FourTuple, Vec<u8>) {
+        self.chan
+            .recv_listener_pkt_timeout(None)
+            .expect("the listener keeps its packet channel open")
+    }
+
+    /// `recv_listener_pkt_blocking` that gives up after `timeout`.
+    pub fn recv_listener_pkt_timeout(&mut self, timeout: Duration) -> Option<(FourTuple, Vec<u8>)> {
+        self.chan.recv_listener_pkt_timeout(Some(timeout)).ok()
+    }
+
+    /// Four-tuples of connections that were dropped or evicted.
+    ///
+    /// Every connection removed from the listener is reported exactly once, whichever way it went away;
+    /// a connection moved by `UdpConn::reconnect` is not removed.
+    /// `ConnClosedEvents` is also a `Stream` for async consumers.
+    ///
+    /// Only available if `UdpListenerConfig::conn_closed_events` is set.
+    /// Once the queue is full, the oldest event is dropped so that connection teardown never blocks.
+    pub fn conn_closed_events(&self) -> Option<&ConnClosedEvents> {
+        self.chan.conn_closed_events()
+    }
+
+    pub fn conn_closed_events_mut(&mut self) -> Option<&mut ConnClosedEvents> {
+        self.chan.conn_closed_events_mut()
+    }
+
+    /// Replace the local IP filter without rebinding.
+    ///
+    /// Every `accept_raw` from then on sees the new filter.
+    pub fn set_ip_filter(&self, local_ip_filter: IpFilterConfig) -> io::Result<()> {
+        if local_ip_filter.domain() != self.domain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IP filter family does not match the listener",
+            ));
+        }
+        *self.local_ip_filter.write().unwrap() = local_ip_filter.build();
+        Ok(())
+    }
+
+    /// Let the local IP filter pass `ip`.
+    pub fn add_local_ip(&self, ip: IpAddr) -> io::Result<()> {
+        self.check_ip_family(&ip)?;
+        self.local_ip_filter.write().unwrap().add(ip);
+        Ok(())
+    }
+
+    /// Let the local IP filter drop `ip`.
+    ///
+    /// A filter passing every address turns into a deny list of `ip`.
+    pub fn remove_local_ip(&self, ip: IpAddr) -> io::Result<()> {
+        self.check_ip_family(&ip)?;
+        self.local_ip_filter.write().unwrap().remove(ip);
+        Ok(())
+    }
+
+    fn check_four_tuple_family(&self, four_tuple: &FourTuple) -> io::Result<()> {
+        let is_same_family = |addr: &SocketAddr| match addr {
+            SocketAddr::V4(_) => self.domain == socket2::Domain::IPV4,
+            SocketAddr::V6(_) => self.domain == socket2::Domain::IPV6,
+        };
+        if !is_same_family(&four_tuple.local_addr) || !is_same_family(&four_tuple.remote_addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "four-tuple address family does not match the listener",
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_ip_family(&self, ip: &IpAddr) -> io::Result<()> {
+        let is_same_family = match ip {
+            IpAddr::V4(_) => self.domain == socket2::Domain::IPV4,
+            IpAddr::V6(_) => self.domain == socket2::Domain::IPV6,
+        };
+        if !is_same_family {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "IP family does not match the listener",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checksum coverage of packets sent from the listener socket in `UdpProtocol::UdpLite`, in bytes including the header.
+    ///
+    /// `0` covers the whole packet.
+    #[cfg(target_os = "linux")]
+    pub fn set_send_checksum_coverage(&self, coverage: u16) -> io::Result<()> {
+        set_send_checksum_coverage(&self.socket, coverage)
+    }
+
+    /// Minimum checksum coverage of packets received on the listener socket in `UdpProtocol::UdpLite`;
+    /// the kernel drops packets of less coverage.
+    ///
+    /// Connection sockets do not inherit it; see `UdpConn::set_recv_checksum_coverage`.
+    #[cfg(target_os = "linux")]
+    pub fn set_recv_checksum_coverage(&self, coverage: u16) -> io::Result<()> {
+        set_recv_checksum_coverage(&self.socket, coverage)
+    }
+
+    /// Replace `UdpListenerConfig::validator`, the last say on every new connection before any state is kept for it.
+    pub fn set_validator(&mut self, validator: Option<Validator>) {
+        self.validator = validator;
+    }
+
+    /// `set_validator` with a predicate answering in `AcceptDecision`s.
+    pub fn set_accept_predicate(
+        &mut self,
+        predicate: impl Fn(&FourTuple, &[u8]) -> AcceptDecision + Send + Sync + 'static,
+    ) {
+        self.validator = Some(Box::new(move |four_tuple, pkt| {
+            predicate(four_tuple, pkt).into()
+        }));
+    }
+
+    /// Join the multicast `group` on the interface of the local IP `interface`, and let the local IP filter pass `group`.
+    ///
+    /// Every `(group, sender)` pair becomes a flow accepted as `AcceptRes::Shared`,
+    /// whose replies go out unicast to the sender.
+    pub fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        self.check_ip_family(&group.into())?;
+        self.socket.join_multicast_v4(&group, &interface)?;
+        self.local_ip_filter.write().unwrap().add(group.into());
+        Ok(())
+    }
+
+    /// `join_multicast_v4` for IPv6, on the interface of index `ifindex`, or any interface if `0`.
+    pub fn join_multicast_v6(&self, group: Ipv6Addr, ifindex: u32) -> io::Result<()> {
+        self.check_ip_family(&group.into())?;
+        self.socket.join_multicast_v6(&group, ifindex)?;
+        self.local_ip_filter.write().unwrap().add(group.into());
+        Ok(())
+    }
+
+    /// Append `filter` to the chain inspecting the first packet of every new connection.
+    ///
+    /// In `accept_raw`, the chain runs after packets for existing connections have been delivered
+    /// and after `min_first_packet_len`, but before the validator, the connection limits, and socket creation.
+    /// The first filter not deciding `FilterDecision::Accept` ends the chain.
+    pub fn push_accept_filter(&mut self, filter: Box<dyn AcceptFilter>) {
+        self.accept_filters.push(filter);
+    }
+
+    /// Send `buf` from the listener socket to `four_tuple.remote_addr` without creating a `UdpConn`.
+    ///
+    /// The source address is pinned to `four_tuple.local_addr`'s IP rather than left for the routing table to choose.
+    pub fn send_to(&self, four_tuple: &FourTuple, buf: &[u8]) -> io::Result<usize> {
+        send_from_to(socket_fd(&self.socket), buf, four_tuple)
+    }
+
     /// `accept` but without `recvmsg`
     ///
     /// This is useful when a connection received a packet that is meant for this listener.
-    pub fn accept_raw(&self, four_tuple: &FourTuple, rx_buf: Cow<[u8]>) -> io::Result<AcceptRes> {
-        if !self.local_ip_filter.pass(&four_tuple.local_addr.ip()) {
+    pub fn accept_raw(
+        &self,
+        four_tuple: &FourTuple,
+        rx_buf: Cow<[u8]>,
+    ) -> io::Result<AcceptRes<B>> {
+        self.check_four_tuple_family(four_tuple)?;
+
+        let is_broadcast = self.is_broadcast(&four_tuple.local_addr.ip());
+        if !is_broadcast
+            && !self
+                .local_ip_filter
+                .read()
+                .unwrap()
+                .pass(&four_tuple.local_addr.ip())
+        {
             return Ok(AcceptRes::Filtered);
         }
 
+        // Checked before delivering to existing connections
+        // so that a filtered source cannot reach them through the listener either.
+        if self
+            .remote_ip_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.pass(&four_tuple.remote_addr.ip()))
+        {
+            return Ok(AcceptRes::RemoteFiltered);
+        }
+
+        let is_owned = matches!(rx_buf, Cow::Owned(_));
         let buf = rx_buf.into_owned();
 
         // Send early packet to the existing connection.
-        let res = self.chan.send_early_pkt(&four_tuple, buf);
+        let res = self.chan.send_early_pkt(four_tuple, buf);
         let buf = match res {
-            SendRes::Ok => return Ok(AcceptRes::ConnAlreadyExists),
-            SendRes::Full(_) => return Ok(AcceptRes::ConnAlreadyExists),
+            SendRes::Ok => return Ok(AcceptRes::ConnAlreadyExists { delivered: true }),
+            SendRes::Full(_) => return Ok(AcceptRes::ConnAlreadyExists { delivered: false }),
             SendRes::NotExist(buf) => buf,
         };
 
-        // Create a new connection.
-        let conn_chan = self.chan.create_early_pkt_chan(four_tuple.clone());
-        let socket = socket2::Socket::new(
-            match four_tuple.local_addr.ip() {
-                std::net::IpAddr::V4(_) => socket2::Domain::IPV4,
-                std::net::IpAddr::V6(_) => socket2::Domain::IPV6,
-            },
-            socket2::Type::DGRAM,
-            Some(socket2::Protocol::UDP),
-        )?;
-        socket.set_nonblocking(self.non_blocking)?;
-        socket.set_reuse_address(true)?;
-        socket.bind(&four_tuple.local_addr.into())?;
-        socket.connect(&four_tuple.remote_addr.into())?;
-        let conn = UdpConn::new(socket, four_tuple.clone(), conn_chan);
+        // Send early packet to the connection owning the key of the packet.
+        if let Some(demux) = &self.demux {
+            let is_live = |four_tuple: &FourTuple| self.chan.contains(four_tuple);
+            if let Some(owner) = demux.route(four_tuple, &buf, &is_live) {
+                let _ = self.chan.send_early_pkt(&owner, buf);
+                return Ok(AcceptRes::RoutedByKey { four_tuple: owner });
+            }
+        }
+
+        if self.chan.is_recently_closed(four_tuple) {
+            return Ok(AcceptRes::Filtered);
+        }
+
+        if self
+            .min_first_packet_len
+            .is_some_and(|min_len| buf.len() < min_len)
+        {
+            self.too_short.fetch_add(1, Ordering::Relaxed);
+            return Ok(AcceptRes::TooShort);
+        }
+
+        for (index, filter) in self.accept_filters.iter().enumerate() {
+            match filter.check(four_tuple, &buf) {
+                FilterDecision::Accept => (),
+                FilterDecision::Reject => return Ok(AcceptRes::RejectedByFilter { index }),
+                FilterDecision::RejectSilently => return Ok(AcceptRes::Filtered),
+            }
+        }
+
+        if let Some(validator) = &self.validator {
+            match validator(four_tuple, &buf) {
+                Validation::CreateConn => (),
+                Validation::Reply(reply) => {
+                    // A failed reply concerns this packet only, not the listener.
+                    return Ok(match self.send_to(four_tuple, &reply) {
+                        Ok(_) => AcceptRes::Replied,
+                        Err(err) => AcceptRes::ReplyFailed { err },
+                    });
+                }
+                Validation::Drop => return Ok(AcceptRes::ValidationFailed),
+            }
+        }
+
+        if self.chan.is_full() {
+            return Ok(AcceptRes::TableFull {
+                four_tuple: *four_tuple,
+                buf: is_owned.then_some(buf),
+            });
+        }
+
+        if self.chan.is_ip_full(&four_tuple.remote_addr.ip()) {
+            return Ok(AcceptRes::IpConnLimit {
+                four_tuple: *four_tuple,
+            });
+        }
+
+        if let Some(limiter) = &self.new_conn_limiter {
+            if !limiter.try_acquire() {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return Ok(AcceptRes::RateLimited);
+            }
+        }
+
+        // Create a new connection.
+        //
+        // The socket is fully set up before the channel is committed to the map
+        // so that a failure leaves no entry behind.
+        //
+        // A socket cannot be connected from a multicast or broadcast address,
+        // so such flows always share the listener socket.
+        let shared_socket = match four_tuple.local_addr.ip().is_multicast() || is_broadcast {
+            true => Some(self.shared_listener_socket()?),
+            false => self.shared_socket.clone(),
+        };
+        let (socket, shared_socket) = match shared_socket {
+            Some(shared_socket) => (None, Some(shared_socket)),
+            None => match self.conn_socket(four_tuple) {
+                Ok(socket) => (Some(socket), None),
+                Err(UdpAcceptError::FdExhausted { .. }) if self.shared_socket_on_fd_exhaustion => {
+                    (None, Some(self.shared_listener_socket()?))
+                }
+                Err(err) => {
+                    return Ok(AcceptRes::ConnFailed {
+                        four_tuple: *four_tuple,
+                        buf: is_owned.then_some(buf),
+                        err,
+                    });
+                }
+            },
+        };
+        let conn_chan = match self.chan.create_early_pkt_chan(*four_tuple) {
+            Ok(conn_chan) => conn_chan,
+            Err(InsertErr::AlreadyExists) => {
+                // Another `accept` created the connection in the meantime.
+                let res = self.chan.send_early_pkt(four_tuple, buf);
+                return Ok(AcceptRes::ConnAlreadyExists {
+                    delivered: matches!(res, SendRes::Ok),
+                });
+            }
+            Err(InsertErr::Full) => {
+                return Ok(AcceptRes::TableFull {
+                    four_tuple: *four_tuple,
+                    buf: is_owned.then_some(buf),
+                });
+            }
+            Err(InsertErr::IpFull) => {
+                return Ok(AcceptRes::IpConnLimit {
+                    four_tuple: *four_tuple,
+                });
+            }
+        };
+        let accept_res = match (socket, shared_socket) {
+            (Some(socket), _) => AcceptRes::Ok(UdpConn::new(
+                socket,
+                *four_tuple,
+                conn_chan,
+                self.conn_config.clone(),
+            )),
+            (None, Some(shared_socket)) => {
+                let handle = UdpConnHandle::new(shared_socket, *four_tuple, conn_chan);
+                AcceptRes::Shared(match is_broadcast {
+                    true => handle.without_pinned_source(),
+                    false => handle,
+                })
+            }
+            (None, None) => unreachable!(),
+        };
+
+        if let Some(demux) = &self.demux {
+            demux.register(four_tuple, &buf);
+        }
+
+        // Send early packet to the new connection.
+        let res = self.chan.send_early_pkt(four_tuple, buf);
+        match res {
+            SendRes::Ok => {}
+            SendRes::Full(_) => {}
+            // Evicted in the meantime.
+            SendRes::NotExist(_) => {}
+        }
+
+        Ok(accept_res)
+    }
+
+    /// Whether `ip` is a broadcast address accepted by `UdpListenerConfig::allow_broadcast`.
+    fn is_broadcast(&self, ip: &IpAddr) -> bool {
+        let Some(broadcast_addrs) = &self.broadcast_addrs else {
+            return false;
+        };
+        let IpAddr::V4(ip) = ip else {
+            return false;
+        };
+        ip.is_broadcast() || broadcast_addrs.read().unwrap().contains(ip)
+    }
+
+    /// Learn the subnet broadcast addresses again, e.g. after the interfaces changed.
+    ///
+    /// Does nothing unless `UdpListenerConfig::allow_broadcast` is set.
+    pub fn refresh_broadcast_addrs(&self) -> io::Result<()> {
+        if let Some(broadcast_addrs) = &self.broadcast_addrs {
+            *broadcast_addrs.write().unwrap() = interface_broadcast_addrs()?;
+        }
+        Ok(())
+    }
+
+    /// The listener socket for a flow without a socket of its own:
+    /// the one of `ConnStrategy::SharedSocket`, or else a clone made on first use.
+    fn shared_listener_socket(&self) -> io::Result<Arc<socket2::Socket>> {
+        if let Some(shared_socket) = &self.shared_socket {
+            return Ok(Arc::clone(shared_socket));
+        }
+        if let Some(socket) = self.shared_listener_socket.get() {
+            return Ok(Arc::clone(socket));
+        }
+        let socket = Arc::new(self.socket.try_clone()?);
+        Ok(Arc::clone(
+            self.shared_listener_socket.get_or_init(|| socket),
+        ))
+    }
+
+    /// Create a socket bound to the local address and connected to the remote address of `four_tuple`.
+    ///
+    /// Retried up to `UdpListenerConfig::conn_bind_retries` times on `EADDRINUSE`.
+    fn conn_socket(&self, four_tuple: &FourTuple) -> Result<socket2::Socket, UdpAcceptError> {
+        let mut retries = self.conn_bind_retries;
+        loop {
+            match self.try_conn_socket(four_tuple) {
+                Err(
+                    UdpAcceptError::ConnBind { source, .. }
+                    | UdpAcceptError::ConnConnect { source, .. },
+                ) if retries != 0 && source.raw_os_error() == Some(libc::EADDRINUSE) => {
+                    retries -= 1;
+                    if !self.non_blocking {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_conn_socket(&self, four_tuple: &FourTuple) -> Result<socket2::Socket, UdpAcceptError> {
+        let socket =
+            self.setup_conn_socket(four_tuple)
+                .map_err(|source| match source.raw_os_error() {
+                    Some(libc::EMFILE | libc::ENFILE) => UdpAcceptError::FdExhausted { source },
+                    _ => UdpAcceptError::ConnSocket { source },
+                })?;
+        let addr = four_tuple.local_addr;
+        socket
+            .bind(&addr.into())
+            .map_err(|source| UdpAcceptError::ConnBind { source, addr })?;
+        if self.conn_config.unconnected {
+            return Ok(socket);
+        }
+        let addr = four_tuple.remote_addr;
+        socket
+            .connect(&addr.into())
+            .map_err(|source| UdpAcceptError::ConnConnect { source, addr })?;
+        Ok(socket)
+    }
+
+    fn setup_conn_socket(&self, four_tuple: &FourTuple) -> io::Result<socket2::Socket> {
+        let socket = socket2::Socket::new(
+            self.domain,
+            socket2::Type::DGRAM,
+            Some(self.protocol.to_socket2()),
+        )?;
+        socket.set_nonblocking(self.non_blocking)?;
+        socket.set_reuse_address(true)?;
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+        #[cfg(target_os = "linux")]
+        set_freebind_transparent(&socket, self.freebind, self.transparent)?;
+        #[cfg(target_os = "linux")]
+        if self.conn_config.recv_err {
+            set_recv_err(&socket, four_tuple, true)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mode) = self.conn_config.mtu_discover {
+            set_mtu_discover(&socket, four_tuple, mode)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(dont_fragment) = self.conn_config.dont_fragment {
+            set_dont_fragment(&socket, four_tuple, dont_fragment)?;
+        }
+        Ok(socket)
+    }
+
+    pub fn socket(&self) -> &socket2::Socket {
+        &self.socket
+    }
+
+    pub fn socket_mut(&mut self) -> &mut socket2::Socket {
+        &mut self.socket
+    }
+
+    /// `SO_INCOMING_CPU` of the listener socket; see `UdpListenerConfig::incoming_cpu`.
+    #[cfg(target_os = "linux")]
+    pub fn incoming_cpu(&self) -> io::Result<usize> {
+        let cpu = getsockopt_int(&self.socket, libc::SOL_SOCKET, libc::SO_INCOMING_CPU)?;
+        Ok(cpu as usize)
+    }
+
+    /// Give up the listener for its socket, e.g. to hand it over to another process.
+    ///
+    /// Connections keep working, but packets of unknown four-tuples are no longer accepted.
+    pub fn into_socket(self) -> socket2::Socket {
+        self.socket
+    }
+
+    /// Borrow the listener socket as a `std::net::UdpSocket`.
+    pub fn as_std(&self) -> StdUdpSocketRef<'_> {
+        // SAFETY: the fd stays open for `'_` and `ManuallyDrop` keeps the borrowed socket from closing it.
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(self.socket.as_raw_fd()) };
+        StdUdpSocketRef {
+            socket: ManuallyDrop::new(socket),
+            _listener: PhantomData,
+        }
+    }
+
+    /// Number of connections whose `UdpConn` is still alive.
+    pub fn conn_count(&self) -> usize {
+        self.chan.conn_count()
+    }
+
+    /// Number of live connections from the remote IP `ip`.
+    pub fn ip_conn_count(&self, ip: &IpAddr) -> usize {
+        self.chan.ip_conn_count(ip)
+    }
+
+    /// Four-tuples of the connections whose `UdpConn` is still alive.
+    pub fn active_tuples(&self) -> Vec<FourTuple> {
+        self.chan.active_tuples()
+    }
+
+    /// Whether a connection for `four_tuple` is alive, e.g. to check a four-tuple from a log.
+    ///
+    /// Together with `conn_closed_events` this tells when a connection went away.
+    pub fn has_connection(&self, four_tuple: &FourTuple) -> bool {
+        self.chan.contains(four_tuple)
+    }
+
+    /// Sever the connection of `four_tuple` from the listener.
+    ///
+    /// Later packets from `four_tuple` reaching the listener are treated as new connection attempts.
+    /// The evicted `UdpConn` keeps its socket, but its early packet stream ends.
+    ///
+    /// Returns whether the connection existed.
+    pub fn evict(&self, four_tuple: &FourTuple) -> bool {
+        self.chan.evict(four_tuple)
+    }
+
+    /// Drop the early packet senders of connections idle for longer than `older_than`.
+    ///
+    /// The early packet receivers of the affected connections observe the end of the stream.
+    ///
+    /// Keys of closed connections in `UdpListenerConfig::demux` are forgotten along the way.
+    ///
+    /// Returns the number of reaped connections.
+    pub fn reap_idle(&self, older_than: Duration) -> usize {
+        let reaped = self.chan.reap_idle(older_than);
+        if let Some(demux) = &self.demux {
+            demux.purge(&|four_tuple| self.chan.contains(four_tuple));
+        }
+        reaped
+    }
+
+    /// Number of early packets dropped by `UdpListenerConfig::early_pkt_budget`.
+    pub fn early_pkt_over_budget_count(&self) -> u64 {
+        self.chan.over_budget_count()
+    }
+
+    /// Bytes of early packets queued across all connections.
+    pub fn early_pkt_queued_bytes(&self) -> usize {
+        self.chan.queued_bytes()
+    }
+
+    /// Number of new connections rejected by `max_new_conns_per_sec`.
+    pub fn rate_limited_count(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Number of new connections rejected by `min_first_packet_len`.
+    pub fn too_short_count(&self) -> u64 {
+        self.too_short.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of control messages returned by the last `recvmsg` on the listener socket.
+    ///
+    /// Compare it against `cmsg_capacity` to tell how much of the control message buffer is used.
+    pub fn last_cmsg_bytes(&self) -> usize {
+        self.last_cmsg_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the control message buffer allocated for each `recvmsg` on the listener socket.
+    pub fn cmsg_capacity(&self) -> usize {
+        self.recv_options.cmsg_len()
+    }
+
+    /// Number of errors `recvmsg` returned on the listener socket, by kind.
+    ///
+    /// `WouldBlock` is not counted.
+    pub fn recv_errors(&self) -> HashMap<io::ErrorKind, u64> {
+        self.recv_errors.lock().unwrap().clone()
+    }
+
+    fn count_recv_err<T>(&self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &res {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                *self
+                    .recv_errors
+                    .lock()
+                    .unwrap()
+                    .entry(e.kind())
+                    .or_default() += 1;
+            }
+        }
+        res
+    }
+
+    /// Address the listener socket is bound to, as reported by the socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self
+            .socket
+            .local_addr()?
+            .as_socket()
+            .ok_or(UdpAcceptError::NotIpSocket)?)
+    }
+}
+impl<B: ChannelBackend> AsRawFd for UdpListener<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+impl<B: ChannelBackend> fmt::Debug for UdpListener<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UdpListener")
+            .field("local_addr", &self.local_addr().ok())
+            .field("conn_count", &self.conn_count())
+            .finish_non_exhaustive()
+    }
+}
+impl<B: ChannelBackend> IntoRawFd for UdpListener<B> {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_socket().into_raw_fd()
+    }
+}
+impl<B: ChannelBackend> AsFd for UdpListener<B> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        socket_fd(&self.socket)
+    }
+}
+
+/// Attach the classic BPF program `filter` to the reuseport group of `socket`.
+#[cfg(target_os = "linux")]
+fn attach_reuseport_cbpf(socket: &socket2::Socket, filter: &[libc::sock_filter]) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter
+            .len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "BPF program too long"))?,
+        // The kernel copies the program and never writes to it.
+        filter: filter.as_ptr().cast_mut(),
+    };
+    // SAFETY: `prog` points to `filter`, which outlives the call.
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            (&prog as *const libc::sock_fprog).cast(),
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `IP_FREEBIND` and `IP_TRANSPARENT` also apply to IPv6 sockets.
+#[cfg(target_os = "linux")]
+fn set_freebind_transparent(
+    socket: &socket2::Socket,
+    freebind: bool,
+    transparent: bool,
+) -> io::Result<()> {
+    if freebind {
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_FREEBIND, 1)?;
+    }
+    if transparent {
+        setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_TRANSPARENT, 1)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &socket2::Socket, mark: u32) -> io::Result<()> {
+    // `SO_MARK` takes the bits of a `u32` in an int.
+    setsockopt_int(socket, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+/// Broadcast addresses of the IPv4 interfaces of the host.
+fn interface_broadcast_addrs() -> io::Result<HashSet<Ipv4Addr>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    // SAFETY: `getifaddrs` only writes the head of the list into `ifaddrs`.
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addrs = HashSet::new();
+    let mut ifaddr = ifaddrs;
+    while !ifaddr.is_null() {
+        // SAFETY: `ifaddr` is a node of the list, which lives until `freeifaddrs`.
+        let entry = unsafe { &*ifaddr };
+        ifaddr = entry.ifa_next;
+        if entry.ifa_flags & libc::IFF_BROADCAST as libc::c_uint == 0 {
+            continue;
+        }
+        // The broadcast address shares its field with the point-to-point destination.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let addr = entry.ifa_ifu;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let addr = entry.ifa_dstaddr;
+        // SAFETY: a non-null address starts with its family, which says it is a `sockaddr_in`.
+        unsafe {
+            if addr.is_null() || libc::c_int::from((*addr).sa_family) != libc::AF_INET {
+                continue;
+            }
+            let addr = std::ptr::read_unaligned(addr.cast::<libc::sockaddr_in>());
+            addrs.insert(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)));
+        }
+    }
+    // SAFETY: `ifaddrs` came from `getifaddrs` and is not used after.
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// A `std::net::UdpSocket` borrowed from a `UdpListener`.
+pub struct StdUdpSocketRef<'a> {
+    socket: ManuallyDrop<std::net::UdpSocket>,
+    _listener: PhantomData<&'a UdpListener>,
+}
+impl Deref for StdUdpSocketRef<'_> {
+    type Target = std::net::UdpSocket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket
+    }
+}
+
+/// A stage of the filter chain on the first packet of new connections.
+///
+/// Closures of the same signature implement it.
+pub trait AcceptFilter: Send + Sync {
+    fn check(&self, four_tuple: &FourTuple, first_pkt: &[u8]) -> FilterDecision;
+}
+impl<F> AcceptFilter for F
+where
+    F: Fn(&FourTuple, &[u8]) -> FilterDecision + Send + Sync,
+{
+    fn check(&self, four_tuple: &FourTuple, first_pkt: &[u8]) -> FilterDecision {
+        self(four_tuple, first_pkt)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterDecision {
+    Accept,
+    /// Drop the packet and report `AcceptRes::RejectedByFilter`.
+    Reject,
+    /// Drop the packet and report `AcceptRes::Filtered`.
+    RejectSilently,
+}
+
+pub type Validator = Box<dyn Fn(&FourTuple, &[u8]) -> Validation + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Validation {
+    CreateConn,
+    /// Send the bytes back to the remote address from the local address the packet was sent to.
+    Reply(Vec<u8>),
+    Drop,
+}
+
+/// What an accept predicate decides on a new connection; see `UdpListener::set_accept_predicate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcceptDecision {
+    Accept,
+    /// Drop the packet and report `AcceptRes::ValidationFailed`.
+    Reject,
+    /// Send the bytes back like `Validation::Reply` and report `AcceptRes::Replied`, or `AcceptRes::ReplyFailed`.
+    Reply(Vec<u8>),
+}
+impl From<AcceptDecision> for Validation {
+    fn from(decision: AcceptDecision) -> Self {
+        match decision {
+            AcceptDecision::Accept => Self::CreateConn,
+            AcceptDecision::Reject => Self::Drop,
+            AcceptDecision::Reply(reply) => Self::Reply(reply),
+        }
+    }
+}
+
+/// Which local addresses the listener serves.
+///
+/// `V4`/`V6` pass only the listed addresses, or every address if `None`.
+/// `V4Deny`/`V6Deny` pass every address except the listed ones.
+///
+/// A filter is either an allow list or a deny list.
+/// Any later combination of both, e.g. an allowed range with holes in it, lets the deny list take precedence.
+#[derive(Debug)]
+pub enum IpFilterConfig {
+    V4(Option<HashSet<Ipv4Addr>>),
+    V6(Option<HashSet<Ipv6Addr>>),
+    V4Deny(HashSet<Ipv4Addr>),
+    V6Deny(HashSet<Ipv6Addr>),
+}
+impl IpFilterConfig {
+    /// Parse an allow list like `"10.0.0.1,10.0.0.2"`, or `"*"` to allow every local IP.
+    ///
+    /// Every address must be of `family`; without it, the family is taken from the first address.
+    pub fn parse(family: Option<IpFamily>, s: &str) -> Result<Self, IpFilterParseErr> {
+        let s = s.trim();
+        if s == "*" {
+            return match family {
+                Some(IpFamily::V4) => Ok(IpFilterConfig::V4(None)),
+                Some(IpFamily::V6) => Ok(IpFilterConfig::V6(None)),
+                None => Err(IpFilterParseErr::UnknownFamily),
+            };
+        }
+        let mut v4 = HashSet::new();
+        let mut v6 = HashSet::new();
+        let mut family = family;
+        for token in s.split(',').map(str::trim) {
+            let addr: IpAddr = token
+                .parse()
+                .map_err(|_| IpFilterParseErr::InvalidAddr(token.to_string()))?;
+            match (*family.get_or_insert(IpFamily::of(&addr)), addr) {
+                (IpFamily::V4, IpAddr::V4(addr)) => {
+                    v4.insert(addr);
+                }
+                (IpFamily::V6, IpAddr::V6(addr)) => {
+                    v6.insert(addr);
+                }
+                _ => return Err(IpFilterParseErr::WrongFamily(addr)),
+            }
+        }
+        Ok(match family {
+            Some(IpFamily::V6) => IpFilterConfig::V6(Some(v6)),
+            _ => IpFilterConfig::V4(Some(v4)),
+        })
+    }
+
+    fn domain(&self) -> socket2::Domain {
+        match self {
+            IpFilterConfig::V4(_) | IpFilterConfig::V4Deny(_) => socket2::Domain::IPV4,
+            IpFilterConfig::V6(_) | IpFilterConfig::V6Deny(_) => socket2::Domain::IPV6,
+        }
+    }
+
+    fn build(self) -> IpFilter {
+        match self {
+            IpFilterConfig::V4(filter) => match filter {
+                Some(filter) => IpFilter::V4(filter),
+                None => IpFilter::AlwaysPass,
+            },
+            IpFilterConfig::V6(filter) => match filter {
+                Some(filter) => IpFilter::V6(filter),
+                None => IpFilter::AlwaysPass,
+            },
+            IpFilterConfig::V4Deny(filter) => IpFilter::V4Deny(filter),
+            IpFilterConfig::V6Deny(filter) => IpFilter::V6Deny(filter),
+        }
+    }
+}
+
+/// Why `IpFilterConfig::parse` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpFilterParseErr {
+    /// A token is not an IP address.
+    InvalidAddr(String),
+    /// An address is not of the family given or taken from the first address.
+    WrongFamily(IpAddr),
+    /// `"*"` was given without a family.
+    UnknownFamily,
+}
+impl fmt::Display for IpFilterParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddr(token) => write!(f, "`{token}` is not an IP address"),
+            Self::WrongFamily(addr) => write!(f, "{addr} is of the wrong address family"),
+            Self::UnknownFamily => write!(f, "the wildcard needs an address family"),
+        }
+    }
+}
+impl std::error::Error for IpFilterParseErr {}
+
+/// Rejects silently packets to local addresses the filter does not pass.
+impl AcceptFilter for IpFilterConfig {
+    fn check(&self, four_tuple: &FourTuple, _first_pkt: &[u8]) -> FilterDecision {
+        let pass = match (self, four_tuple.local_addr.ip()) {
+            (IpFilterConfig::V4(filter), IpAddr::V4(addr)) => {
+                filter.as_ref().is_none_or(|filter| filter.contains(&addr))
+            }
+            (IpFilterConfig::V6(filter), IpAddr::V6(addr)) => {
+                filter.as_ref().is_none_or(|filter| filter.contains(&addr))
+            }
+            (IpFilterConfig::V4Deny(filter), IpAddr::V4(addr)) => !filter.contains(&addr),
+            (IpFilterConfig::V6Deny(filter), IpAddr::V6(addr)) => !filter.contains(&addr),
+            _ => false,
+        };
+        if pass {
+            FilterDecision::Accept
+        } else {
+            FilterDecision::RejectSilently
+        }
+    }
+}
+
+enum IpFilter {
+    V4(HashSet<Ipv4Addr>),
+    V6(HashSet<Ipv6Addr>),
+    V4Deny(HashSet<Ipv4Addr>),
+    V6Deny(HashSet<Ipv6Addr>),
+    AlwaysPass,
+}
+impl IpFilter {
+    pub fn pass(&self, addr: &IpAddr) -> bool {
+        match self {
+            IpFilter::V4(filter) => match addr {
+                IpAddr::V4(addr) => filter.contains(addr),
+                IpAddr::V6(_) => false,
+            },
+            IpFilter::V6(filter) => match addr {
+                IpAddr::V4(_) => false,
+                IpAddr::V6(addr) => filter.contains(addr),
+            },
+            IpFilter::V4Deny(filter) => match addr {
+                IpAddr::V4(addr) => !filter.contains(addr),
+                IpAddr::V6(_) => false,
+            },
+            IpFilter::V6Deny(filter) => match addr {
+                IpAddr::V4(_) => false,
+                IpAddr::V6(addr) => !filter.contains(addr),
+            },
+            IpFilter::AlwaysPass => true,
+        }
+    }
+
+    /// `addr` must be of the family of the filter.
+    fn add(&mut self, addr: IpAddr) {
+        match (self, addr) {
+            (IpFilter::V4(filter), IpAddr::V4(addr)) => {
+                filter.insert(addr);
+            }
+            (IpFilter::V6(filter), IpAddr::V6(addr)) => {
+                filter.insert(addr);
+            }
+            (IpFilter::V4Deny(filter), IpAddr::V4(addr)) => {
+                filter.remove(&addr);
+            }
+            (IpFilter::V6Deny(filter), IpAddr::V6(addr)) => {
+                filter.remove(&addr);
+            }
+            (IpFilter::AlwaysPass, _) => (),
+            _ => unreachable!("address family mismatch"),
+        }
+    }
+
+    /// `addr` must be of the family of the filter.
+    fn remove(&mut self, addr: IpAddr) {
+        match (&mut *self, addr) {
+            (IpFilter::V4(filter), IpAddr::V4(addr)) => {
+                filter.remove(&addr);
+            }
+            (IpFilter::V6(filter), IpAddr::V6(addr)) => {
+                filter.remove(&addr);
+            }
+            (IpFilter::V4Deny(filter), IpAddr::V4(addr)) => {
+                filter.insert(addr);
+            }
+            (IpFilter::V6Deny(filter), IpAddr::V6(addr)) => {
+                filter.insert(addr);
+            }
+            (IpFilter::AlwaysPass, IpAddr::V4(addr)) => {
+                *self = IpFilter::V4Deny([addr].into_iter().collect());
+            }
+            (IpFilter::AlwaysPass, IpAddr::V6(addr)) => {
+                *self = IpFilter::V6Deny([addr].into_iter().collect());
+            }
+            _ => unreachable!("address family mismatch"),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+impl IpPrefix {
+    /// Returns `None` if `len` exceeds the bit length of `addr`.
+    pub fn new(addr: IpAddr, len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (len <= max_len).then_some(Self { addr, len })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len)).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.len))
+                    .unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+/// A single address.
+impl From<IpAddr> for IpPrefix {
+    fn from(addr: IpAddr) -> Self {
+        let len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { addr, len }
+    }
+}
+
+/// Filter on the remote IP.
+///
+/// `deny` takes precedence over `allow`; `None` for `allow` passes every IP not denied.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteIpFilter {
+    pub allow: Option<Vec<IpPrefix>>,
+    pub deny: Vec<IpPrefix>,
+}
+impl RemoteIpFilter {
+    pub fn pass(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|prefix| prefix.contains(ip)) {
+            return false;
+        }
+        match &self.allow {
+            Some(allow) => allow.iter().any(|prefix| prefix.contains(ip)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AcceptRes<B: ChannelBackend = FuturesChannel> {
+    Ok(UdpConn<B>),
+    /// A new connection in `ConnStrategy::SharedSocket`.
+    Shared(UdpConnHandle<B>),
+    /// The packet belongs to a live connection.
+    ///
+    /// `delivered` is false if the early packet channel of the connection was full and the packet was dropped.
+    ConnAlreadyExists {
+        delivered: bool,
+    },
+    /// The packet came from an unknown four-tuple but was delivered to the connection at `four_tuple`
+    /// owning its key; see `UdpListenerConfig::demux`.
+    RoutedByKey {
+        four_tuple: FourTuple,
+    },
+    Filtered,
+    /// The remote IP did not pass `remote_ip_filter`.
+    RemoteFiltered,
+    /// The connection table reached `max_connections`.
+    ///
+    /// `buf` gives back the packet if it was passed in owned.
+    TableFull {
+        four_tuple: FourTuple,
+        buf: Option<Vec<u8>>,
+    },
+    /// New connections are created faster than `max_new_conns_per_sec`.
+    RateLimited,
+    /// The first packet is shorter than `min_first_packet_len`.
+    TooShort,
+    /// The accept filter at `index` of the chain decided `FilterDecision::Reject`.
+    RejectedByFilter {
+        index: usize,
+    },
+    /// The validator answered with `Validation::Reply`, which has been sent.
+    Replied,
+    /// The validator answered with `Validation::Reply`, which could not be sent,
+    /// e.g. by `EAGAIN` on a full send buffer; the packet is dropped.
+    ReplyFailed {
+        err: io::Error,
+    },
+    /// The validator decided `Validation::Drop`.
+    ValidationFailed,
+    /// The remote IP reached `max_conns_per_ip`.
+    IpConnLimit {
+        four_tuple: FourTuple,
+    },
+    /// Setting up the connection socket failed; no connection is left behind.
+    ///
+    /// `buf` gives back the packet if it was passed in owned, so that it can be accepted again.
+    ConnFailed {
+        four_tuple: FourTuple,
+        buf: Option<Vec<u8>>,
+        err: UdpAcceptError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::{
+        channel::{ConnClosedEvent, ConnClosedReason, ConnEntry, EarlyPktStore, StdChannel},
+        is_peer_unreachable,
+        recv::{if_name_to_index, RecvFlags},
+        ConnRecvMode, ForwardRes, MsgTooLong, RecvAny, RecvRes, RecvSource, TrafficStats,
+    };
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+        os::fd::{IntoRawFd, OwnedFd},
+        sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError},
+    };
+
+    #[cfg(feature = "tokio")]
+    use crate::channel::TokioChannel;
+    #[cfg(target_os = "linux")]
+    use crate::{recv::SockErrorOrigin, MtuDiscover};
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv4_wildcard() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+        assert_eq!(send_len, send_buf.len());
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(recv_len, send_len);
+        assert_eq!(&recv_buf[..recv_len], send_buf);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv4_specific() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V4(Some([Ipv4Addr::LOCALHOST].iter().cloned().collect()));
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+        assert_eq!(send_len, send_buf.len());
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(recv_len, send_len);
+        assert_eq!(&recv_buf[..recv_len], send_buf);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv6_wildcard() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V6(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+        assert_eq!(send_len, send_buf.len());
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(recv_len, send_len);
+        assert_eq!(&recv_buf[..recv_len], send_buf);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv6_specific() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V6(Some([Ipv6Addr::LOCALHOST].iter().cloned().collect()));
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+        assert_eq!(send_len, send_buf.len());
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(recv_len, send_len);
+        assert_eq!(&recv_buf[..recv_len], send_buf);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv4_specific_many_clients() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V4(Some([Ipv4Addr::LOCALHOST].iter().cloned().collect()));
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let mut conns = Vec::new();
+        for i in 0..100 {
+            let send_port = send_port_start + i;
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+            let send_buf = b"hello world";
+            let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+            assert_eq!(send_len, send_buf.len());
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+            assert_eq!(recv_len, send_len);
+            assert_eq!(&recv_buf[..recv_len], send_buf);
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            assert_eq!(conn.four_tuple().local_addr, listen_addr);
+
+            conns.push(conn);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_listen_ipv6_specific_many_clients() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V6(Some([Ipv6Addr::LOCALHOST].iter().cloned().collect()));
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let mut conns = Vec::new();
+        for i in 0..100 {
+            let send_port = send_port_start + i;
+            let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+            let send_buf = b"hello world";
+            let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
+            assert_eq!(send_len, send_buf.len());
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+            assert_eq!(recv_len, send_len);
+            assert_eq!(&recv_buf[..recv_len], send_buf);
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            assert_eq!(conn.four_tuple().local_addr, listen_addr);
+
+            conns.push(conn);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_accept_same_client() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, true).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        for _ in 0..16 {
+            send_socket.send_to(send_buf, listen_addr).unwrap();
+        }
+
+        let conns = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut recv_buf = [0u8; 1024];
+                    let deadline =
+                        std::time::Instant::now() + std::time::Duration::from_millis(200);
+                    while std::time::Instant::now() < deadline {
+                        match listener.accept(&mut recv_buf) {
+                            Ok((AcceptRes::Ok(conn), _, _)) => conns.lock().unwrap().push(conn),
+                            Ok(_) => (),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                std::thread::yield_now();
+                            }
+                            Err(e) => panic!("{e}"),
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(conns.into_inner().unwrap().len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[serial]
+    fn test_fwmark() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.fwmark = Some(42);
+
+        let listener = match UdpListener::bind_with_config(listen_port, config) {
+            Ok(listener) => listener,
+            // Setting `SO_MARK` requires `CAP_NET_ADMIN`.
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(listener.socket().mark().unwrap(), 42);
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.socket().mark().unwrap(), 42);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[serial]
+    fn test_incoming_cpu() {
+        setup();
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.incoming_cpu = Some(0);
+        let listener = UdpListener::bind_with_config(12345, config).unwrap();
+        assert_eq!(listener.incoming_cpu().unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[serial]
+    fn test_reuseport_cbpf() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let bind = |reuseport_cbpf| {
+            let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), true);
+            config.reuse_port = true;
+            config.reuseport_cbpf = reuseport_cbpf;
+            UdpListener::bind_with_config(listen_port, config).unwrap()
+        };
+        // Hand every packet to the second socket of the group.
+        let filter = libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: 1,
+        };
+        let first = bind(Some(vec![filter]));
+        let second = bind(None);
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello", listen_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let mut recv_buf = [0u8; 1024];
+        let err = first.accept(&mut recv_buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let (res, _, _) = second.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_connections() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.max_connections = Some(2);
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port_start = 54321;
+        let send_sockets = (0..4)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+                UdpSocket::bind(send_addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let send_buf = b"hello world";
+
+        let mut conns = Vec::new();
+        for send_socket in &send_sockets[..2] {
+            send_socket.send_to(send_buf, listen_addr).unwrap();
+            let (res, _, _) = listener.accept_owned(vec![0; 1024]).unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            conns.push(conn);
+        }
+        assert_eq!(listener.conn_count(), 2);
+
+        send_sockets[2].send_to(send_buf, listen_addr).unwrap();
+        let (res, four_tuple, _) = listener.accept_owned(vec![0; 1024]).unwrap();
+        let AcceptRes::TableFull {
+            four_tuple: full_four_tuple,
+            buf,
+        } = res
+        else {
+            panic!();
+        };
+        assert_eq!(full_four_tuple, four_tuple);
+        assert_eq!(buf.unwrap(), send_buf);
+        assert_eq!(listener.conn_count(), 2);
+
+        conns.pop();
+        assert_eq!(listener.conn_count(), 1);
+
+        send_sockets[3].send_to(send_buf, listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+        assert_eq!(listener.conn_count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_incoming() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let mut send_addrs = Vec::new();
+        let mut send_sockets = Vec::new();
+        for i in 0..2 {
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            send_addrs.push(send_addr);
+            send_sockets.push(send_socket);
+        }
+
+        let conns = listener
+            .incoming(1024)
+            .take(2)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let remote_addrs = conns
+            .iter()
+            .map(|conn| conn.four_tuple().remote_addr)
+            .collect::<Vec<_>>();
+        assert_eq!(remote_addrs, send_addrs);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    #[serial]
+    async fn test_accept_stream() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        let mut stream = Box::pin(listener.accept_stream(1024));
+
+        let send_addrs =
+            [54321, 54322].map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+        let send = move |send_addr| {
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            // Routed to the first connection rather than yielded.
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            send_socket
+        };
+
+        let _send_socket = send(send_addrs[0]);
+        let conn = stream.next().await.unwrap().unwrap();
+        assert_eq!(conn.four_tuple().remote_addr, send_addrs[0]);
+
+        // Sent while the stream waits on the reactor.
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            send(send_addrs[1])
+        });
+        let conn = stream.next().await.unwrap().unwrap();
+        assert_eq!(conn.four_tuple().remote_addr, send_addrs[1]);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_reap_idle() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        send_socket.send_to(send_buf, listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+
+        assert_eq!(listener.reap_idle(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(listener.reap_idle(Duration::from_millis(10)), 1);
+        assert_eq!(listener.conn_count(), 0);
+
+        let early_pkt_recv = conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv().unwrap(), send_buf);
+        assert_eq!(
+            early_pkt_recv.try_recv().unwrap_err(),
+            TryRecvError::Disconnected
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_uninit() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        send_socket.send_to(send_buf, listen_addr).unwrap();
+
+        let mut recv_buf = Vec::<u8>::with_capacity(1024);
+        let (res, _, pkt) = listener
+            .accept_uninit(recv_buf.spare_capacity_mut())
+            .unwrap();
+        assert_eq!(pkt, send_buf);
+        let recv_len = pkt.len();
+        // SAFETY: `accept_uninit` initialized the first `recv_len` bytes.
+        unsafe { recv_buf.set_len(recv_len) };
+        assert_eq!(recv_buf, send_buf);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_socket_failure_leaves_no_entry() {
+        setup();
+        let listen_port = 12345;
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        // Connecting to the broadcast address fails without `SO_BROADCAST`.
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port),
+            remote_addr: SocketAddr::new(Ipv4Addr::BROADCAST.into(), 54321),
+        };
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello world"[..]))
+            .unwrap();
+        let AcceptRes::ConnFailed { buf, err, .. } = res else {
+            panic!();
+        };
+        assert!(buf.is_none());
+        assert!(matches!(err, UdpAcceptError::ConnConnect { .. }));
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_active_tuples() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let mut conns = Vec::new();
+        let mut send_sockets = Vec::new();
+        for i in 0..2 {
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            send_sockets.push(send_socket);
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            conns.push(conn);
+        }
+
+        let mut active_tuples = listener.active_tuples();
+        active_tuples.sort_by_key(|four_tuple| four_tuple.remote_addr);
+        let four_tuples = conns
+            .iter()
+            .map(|conn| *conn.four_tuple())
+            .collect::<Vec<_>>();
+        assert_eq!(active_tuples, four_tuples);
+        assert_eq!(listener.conn_count(), 2);
+        assert!(listener.has_connection(&four_tuples[0]));
+
+        drop(conns.remove(0));
+        assert!(!listener.has_connection(&four_tuples[0]));
+        assert_eq!(listener.active_tuples(), &four_tuples[1..]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_ephemeral_port() {
+        setup();
+        let local_ip_filter = IpFilterConfig::V4(None);
+        let listener = UdpListener::bind(0, local_ip_filter, false).unwrap();
+        let listen_port = listener.local_addr().unwrap().port();
+        assert_ne!(listen_port, 0);
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(four_tuple.local_addr, listen_addr);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+
+        conn.send(b"hi").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"hi");
+        assert_eq!(from, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_without_getsockname() {
+        setup();
+        let listen_port = 12345;
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        // Swap the listener socket for one on another port behind its back;
+        // the four-tuple keeps the port taken at bind only if accepting does not query the socket.
+        let other_port = 12353;
+        let other =
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), other_port)).unwrap();
+        setsockopt_int(&other, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
+        let res = unsafe { libc::dup2(other.as_raw_fd(), listener.as_raw_fd()) };
+        assert_eq!(res, listener.as_raw_fd());
+        drop(other);
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let other_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), other_port);
+        send_socket.send_to(b"hello world", other_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (_, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        assert_eq!(four_tuple.local_addr.port(), listen_port);
+        assert_eq!(four_tuple.remote_addr, send_addr);
+
+        // The family taken at bind rejects four-tuples of the other family.
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port),
+            remote_addr: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 54321),
+        };
+        let err = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[serial]
+    fn test_recently_closed_window() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let window = Duration::from_millis(100);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.recently_closed_window = Some(window);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let four_tuple = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+            .unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        drop(conn);
+
+        // The retransmit of the dead flow is dropped.
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+        assert!(!listener.has_connection(&four_tuple));
+
+        // Evicted connections are remembered too.
+        let other = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54322),
+        };
+        let res = listener
+            .accept_raw(&other, Cow::from(&b"hello"[..]))
+            .unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+        assert!(listener.evict(&other));
+        let res = listener
+            .accept_raw(&other, Cow::from(&b"hello"[..]))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        std::thread::sleep(window * 2);
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_has_connection() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert!(listener.has_connection(&four_tuple));
+        assert!(!listener.has_connection(&four_tuple.flipped()));
+
+        drop(conn);
+        assert!(!listener.has_connection(&four_tuple));
+    }
+
+    #[test]
+    #[serial]
+    fn test_evict() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = b"hello world";
+        send_socket.send_to(send_buf, listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut old_conn) = res else {
+            panic!();
+        };
+
+        assert!(listener.evict(&four_tuple));
+        assert!(!listener.evict(&four_tuple));
+        let early_pkt_recv = old_conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv().unwrap(), send_buf);
+        assert_eq!(
+            early_pkt_recv.try_recv().unwrap_err(),
+            TryRecvError::Disconnected
+        );
+
+        // The same peer reconnects.
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&send_buf[..]))
+            .unwrap();
+        let AcceptRes::Ok(_new_conn) = res else {
+            panic!();
+        };
+
+        // Dropping the evicted connection leaves the new one in place.
+        drop(old_conn);
+        assert!(listener.has_connection(&four_tuple));
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_raw_family_mismatch() {
+        setup();
+        let listen_port = 12345;
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port),
+            remote_addr: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 54321),
+        };
+        let err = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello world"[..]))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_closed_events() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn_closed_events = Some(1);
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port_start = 54321;
+        let mut conns = Vec::new();
+        let mut send_sockets = Vec::new();
+        for i in 0..3 {
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            send_sockets.push(send_socket);
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            conns.push(conn);
+        }
+        let events = listener.conn_closed_events().unwrap();
+
+        let evicted = *conns[0].four_tuple();
+        assert!(listener.evict(&evicted));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConnClosedEvent {
+                four_tuple: evicted,
+                reason: ConnClosedReason::Evicted,
+            }
+        );
+        // The evicted connection does not report again when dropped.
+        drop(conns.remove(0));
+        assert!(events.try_recv().is_none());
+
+        // The oldest event is dropped when the queue is full.
+        let evicted = *conns[0].four_tuple();
+        let dropped = *conns[1].four_tuple();
+        assert!(listener.evict(&evicted));
+        drop(conns);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConnClosedEvent {
+                four_tuple: dropped,
+                reason: ConnClosedReason::Dropped,
+            }
+        );
+        assert!(events.try_recv().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_closed_events_on_drop() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn_closed_events = Some(8);
+        let mut listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_socket = UdpSocket::bind("127.0.0.1:54321").unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+
+        drop(conn);
+        let events = listener.conn_closed_events_mut().unwrap();
+        assert_eq!(
+            futures::executor::block_on(events.next()),
+            Some(ConnClosedEvent {
+                four_tuple,
+                reason: ConnClosedReason::Dropped,
+            })
+        );
+        assert!(events.try_recv().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_std_conversion() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        assert_eq!(listener.as_std().local_addr().unwrap().port(), listen_port);
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        let conn = conn.into_std();
+        assert!(!listener.has_connection(&four_tuple));
+
+        conn.send(b"ping").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"ping");
+        assert_eq!(from, listen_addr);
+
+        send_socket.send_to(b"pong", listen_addr).unwrap();
+        let recv_len = conn.recv(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"pong");
+    }
+
+    #[test]
+    #[serial]
+    fn test_as_fd() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        assert_eq!(listener.as_raw_fd(), listener.socket().as_raw_fd());
+        assert_eq!(listener.as_fd().as_raw_fd(), listener.socket().as_raw_fd());
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.as_raw_fd(), conn.socket().as_raw_fd());
+        assert_eq!(conn.as_fd().as_raw_fd(), conn.socket().as_raw_fd());
+
+        let fd = OwnedFd::from(conn);
+        assert!(!listener.has_connection(&four_tuple));
+        let conn = UdpSocket::from(fd);
+        conn.send(b"ping").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"ping");
+        assert_eq!(from, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_into_parts() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        let (socket, conn_four_tuple) = conn.into_parts();
+        assert_eq!(conn_four_tuple, four_tuple);
+        assert!(!listener.has_connection(&four_tuple));
+        assert_eq!(listener.conn_count(), 0);
+        socket.send(b"ping").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"ping");
+        assert_eq!(from, listen_addr);
+
+        // Otherwise it takes the packets of `send_addr` from the listener.
+        drop(socket);
+
+        // Hand the listener socket over as if to a new process.
+        let fd = listener.into_raw_fd();
+        // SAFETY: the fd is owned by nobody else.
+        let listener =
+            unsafe { UdpListener::from_raw_fd(fd, IpFilterConfig::V4(None), false) }.unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_try_clone() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        let mut clone = conn.try_clone().unwrap();
+        assert_eq!(clone.four_tuple(), conn.four_tuple());
+        assert!(clone
+            .recv_early_pkt_mut()
+            .recv_early_pkt_mut()
+            .try_recv()
+            .is_err());
+
+        clone.send(b"ping").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"ping");
+        assert_eq!(from, listen_addr);
+
+        // Only the original owns the entry.
+        drop(clone);
+        assert!(listener.has_connection(&four_tuple));
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"early"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        assert_eq!(
+            conn.drain_early_pkts(),
+            [b"hello world".to_vec(), b"early".to_vec()]
+        );
+        drop(conn);
+        assert!(!listener.has_connection(&four_tuple));
+    }
+
+    #[test]
+    #[serial]
+    fn test_listener_alive() {
+        setup();
+        let listen_port = 12345;
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        let conn = listener
+            .register(FourTuple {
+                local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port),
+                remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+            })
+            .unwrap();
+        let clone = conn.try_clone().unwrap();
+        assert!(conn.listener_alive());
+        assert!(clone.listener_alive());
+
+        drop(listener);
+        assert!(!conn.listener_alive());
+        assert!(!clone.listener_alive());
+    }
+
+    #[test]
+    #[serial]
+    fn test_debug() {
+        setup();
+        assert_eq!(
+            format!("{:?}", AcceptRes::<FuturesChannel>::Filtered),
+            "Filtered"
+        );
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+        assert_eq!(
+            format!("{:?}", RecvRes::ListenerPkt(four_tuple)),
+            format!("ListenerPkt({four_tuple:?})")
+        );
+
+        let listener = UdpListener::bind(12345, IpFilterConfig::V4(None), false).unwrap();
+        let conn = listener.register(four_tuple).unwrap();
+        assert_eq!(
+            format!("{:?}", AcceptRes::Ok(conn)),
+            format!("Ok(UdpConn {{ four_tuple: {four_tuple:?}, .. }})")
+        );
+        assert_eq!(
+            format!("{listener:?}"),
+            "UdpListener { local_addr: Some(0.0.0.0:12345), conn_count: 0, .. }"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_recv_errors() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, true).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let err = listener.accept(&mut recv_buf).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(listener.recv_errors().is_empty());
+
+        // Without pktinfo the local address cannot be recovered.
+        setsockopt_int(listener.socket(), libc::IPPROTO_IP, libc::IP_PKTINFO, 0).unwrap();
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let err = listener.accept(&mut recv_buf).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(matches!(
+            UdpAcceptError::from_io_error(&err),
+            Some(UdpAcceptError::MissingPktInfo)
+        ));
+        assert_eq!(listener.recv_errors()[&io::ErrorKind::Other], 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_bind_error() {
+        setup();
+        let listen_port = 12345;
+        let local_ip_filter = IpFilterConfig::V4(None);
+        let listener = UdpListener::bind(listen_port, local_ip_filter, true).unwrap();
+
+        // Not an address of this host.
+        let local_addr = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 1).into(), listen_port);
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+        let res = listener
+            .accept_raw(&four_tuple, Cow::Owned(b"hello world".to_vec()))
+            .unwrap();
+        let AcceptRes::ConnFailed {
+            four_tuple: failed,
+            buf,
+            err: UdpAcceptError::ConnBind { source, addr },
+        } = res
+        else {
+            panic!();
+        };
+        assert_eq!(failed, four_tuple);
+        assert_eq!(buf.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!(source.raw_os_error(), Some(libc::EADDRNOTAVAIL));
+        assert_eq!(addr, local_addr);
+        assert!(!listener.has_connection(&four_tuple));
+
+        let err = listener.register(four_tuple).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(matches!(
+            UdpAcceptError::from_io_error(&err),
+            Some(UdpAcceptError::ConnBind { .. })
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_bind_retries() {
+        setup();
+        let listen_port = 12345;
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn_bind_retries = 1000;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        // A socket without `SO_REUSEADDR` keeps the connection socket from binding.
+        let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12346);
+        let blocker = UdpSocket::bind(local_addr).unwrap();
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+
+        // A non-blocking listener does not sleep between retries.
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), true);
+        config.conn_bind_retries = 1000;
+        let non_blocking = UdpListener::bind_with_config(listen_port + 2, config).unwrap();
+        let start = std::time::Instant::now();
+        let res = non_blocking
+            .accept_raw(&four_tuple, Cow::Borrowed(b"hello world"))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::ConnFailed { .. }));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        drop(non_blocking);
+
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            drop(blocker);
+        });
+        let res = listener
+            .accept_raw(&four_tuple, Cow::Borrowed(b"hello world"))
+            .unwrap();
+        release.join().unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.drain_early_pkts(), [b"hello world".to_vec()]);
+    }
+
+    /// Run `f` with every new file descriptor failing by `EMFILE`.
+    fn with_fds_exhausted<T>(f: impl FnOnce() -> T) -> T {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        loop {
+            // The lowest free descriptor.
+            let fd = unsafe { libc::dup(0) };
+            assert!(fd >= 0);
+            unsafe { libc::close(fd) };
+            let exhausted = libc::rlimit {
+                rlim_cur: fd as libc::rlim_t,
+                rlim_max: limit.rlim_max,
+            };
+            assert_eq!(
+                unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &exhausted) },
+                0
+            );
+            let fd = unsafe { libc::dup(0) };
+            if fd < 0 {
+                break;
+            }
+            // Another thread closed a lower descriptor in the meantime.
+            unsafe { libc::close(fd) };
+        }
+        let res = f();
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        res
+    }
+
+    #[test]
+    #[serial]
+    fn test_fd_exhaustion() {
+        setup();
+        let listen_port = 12345;
+        let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), true).unwrap();
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), true);
+        config.shared_socket_on_fd_exhaustion = true;
+        let fallback_listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+        let (res, fallback_res) = with_fds_exhausted(|| {
+            let res = listener.accept_raw(&four_tuple, Cow::Owned(b"hello world".to_vec()));
+            let fallback_res = fallback_listener.accept_raw(&four_tuple, Cow::from(&b"hello"[..]));
+            (res, fallback_res)
+        });
+
+        let AcceptRes::ConnFailed {
+            buf,
+            err: UdpAcceptError::FdExhausted { source },
+            ..
+        } = res.unwrap()
+        else {
+            panic!();
+        };
+        assert_eq!(buf.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!(source.raw_os_error(), Some(libc::EMFILE));
+        assert!(!listener.has_connection(&four_tuple));
+
+        let AcceptRes::Shared(mut handle) = fallback_res.unwrap() else {
+            panic!();
+        };
+        assert_eq!(handle.four_tuple(), &four_tuple);
+        assert!(fallback_listener.has_connection(&four_tuple));
+        let early_pkt_recv = handle.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv().unwrap(), b"hello");
+
+        // Descriptors are back, so new connections get their own socket again.
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54322),
+        };
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_reaccept_after_drop() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+        let mut config = UdpListenerConfig::new(local_ip_filter, false);
+        config.conn_bind_retries = 10;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        for _ in 0..100 {
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            assert_eq!(four_tuple.remote_addr, send_addr);
+            drop(conn);
+            assert!(!listener.has_connection(&four_tuple));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_new_conn_rate_limit() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.max_new_conns_per_sec = Some(1);
+        config.new_conns_burst = Some(2);
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port_start = 54321;
+        let mut conns = Vec::new();
+        let mut send_sockets = Vec::new();
+        for i in 0..3 {
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            send_sockets.push(send_socket);
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            match res {
+                AcceptRes::Ok(conn) => conns.push(conn),
+                AcceptRes::RateLimited => assert_eq!(i, 2),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(conns.len(), 2);
+        assert_eq!(listener.rate_limited_count(), 1);
+
+        // Existing connections bypass the limiter.
+        let res = listener
+            .accept_raw(conns[0].four_tuple(), Cow::from(&b"hello world"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        assert_eq!(listener.rate_limited_count(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_soft_peer_unreachable() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn.soft_peer_unreachable = true;
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        conn.socket()
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // The peer port is closed, so the kernel answers with an ICMP port unreachable.
+        drop(send_socket);
+        conn.socket().send(b"hello world").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let (res, _) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::PeerUnreachable));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_take_error_queue() {
+        setup();
+        let listen_port = 12345;
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn.recv_err = true;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        // Nothing listens on the peer port.
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let conn = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .unwrap();
+        let mut err_buf = [0u8; 1024];
+        assert_eq!(conn.take_error_queue(&mut err_buf).unwrap(), None);
+
+        conn.send(b"hello").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let err = conn.take_error_queue(&mut err_buf).unwrap().unwrap();
+        assert_eq!(err.errno, libc::ECONNREFUSED);
+        assert_eq!(err.as_io_error().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.origin, SockErrorOrigin::Icmp);
+        // Destination unreachable, port unreachable.
+        assert_eq!((err.icmp_type, err.icmp_code), (3, 3));
+        assert_eq!(err.destination, Some(peer_addr));
+        assert_eq!(err.offender, Some(Ipv4Addr::LOCALHOST.into()));
+        assert_eq!(&err_buf[..err.len], b"hello");
+        assert_eq!(err.mtu, None);
+        assert_eq!(conn.take_error_queue(&mut err_buf).unwrap(), None);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_recv_err_mtu() {
+        setup();
+        let listen_port = 12345;
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn.recv_err = true;
+        config.conn.mtu_discover = Some(MtuDiscover::Do);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        // Any off-host route has a path MTU below the largest datagram.
+        let peer_addr = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 3).into(), 54321);
+        let probe = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+        if probe.connect(peer_addr).is_err() {
+            // No route.
+            return;
+        }
+        let local_ip = probe.local_addr().unwrap().ip();
+        let conn = listener.connect_out(peer_addr, local_ip).unwrap();
+        let mtu = conn.path_mtu().unwrap();
+
+        let err = conn.send(&vec![0; mtu as usize]).err().unwrap();
+        assert_eq!(MsgTooLong::from_io_error(&err).unwrap().mtu, Some(mtu));
+        let err = conn.recv_err().unwrap().unwrap();
+        assert_eq!(err.errno, libc::EMSGSIZE);
+        assert_eq!(err.origin, SockErrorOrigin::Local);
+        assert_eq!(err.mtu, Some(mtu));
+        assert_eq!(err.len, 0);
+        assert_eq!(conn.recv_err().unwrap(), None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_send_connection_refused() {
+        setup();
+        let listen_port = 12345;
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        // Nothing listens on the peer port.
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let conn = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .unwrap();
+
+        conn.send(b"hello").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let err = conn.send(b"hello").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(is_peer_unreachable(&err));
+        assert!(err.to_string().contains("ICMP port unreachable"));
+        conn.send(b"hello").unwrap();
+
+        // The same for sends with an explicit source.
+        std::thread::sleep(Duration::from_millis(10));
+        let err = conn
+            .send_from(b"hello", Ipv4Addr::LOCALHOST.into())
+            .err()
+            .unwrap();
+        assert!(is_peer_unreachable(&err));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_path_mtu() {
+        setup();
+        let listen_port = 12345;
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn.mtu_discover = Some(MtuDiscover::Do);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let _peer_socket = UdpSocket::bind(peer_addr).unwrap();
+        let conn = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .unwrap();
+        assert_eq!(conn.mtu_discover().unwrap(), MtuDiscover::Do);
+        let mtu = conn.path_mtu().unwrap();
+        assert!(mtu > 0);
+
+        conn.send(b"hello").unwrap();
+        // Headers push a payload of the MTU beyond it.
+        let err = conn.send(&vec![0; mtu as usize]).err().unwrap();
+        assert_eq!(
+            MsgTooLong::from_io_error(&err),
+            Some(&MsgTooLong { mtu: Some(mtu) })
+        );
+        conn.set_mtu_discover(MtuDiscover::Dont).unwrap();
+        assert_eq!(conn.mtu_discover().unwrap(), MtuDiscover::Dont);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_dont_fragment() {
+        setup();
+        let listen_port = 12345;
+        for (ip_filter, ip) in [
+            (IpFilterConfig::V4(None), IpAddr::from(Ipv4Addr::LOCALHOST)),
+            (IpFilterConfig::V6(None), Ipv6Addr::LOCALHOST.into()),
+        ] {
+            let mut config = UdpListenerConfig::new(ip_filter, false);
+            config.conn.dont_fragment = Some(true);
+            let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+            let conn = listener
+                .connect_out(SocketAddr::new(ip, 54321), ip)
+                .unwrap();
+            assert!(conn.dont_fragment().unwrap());
+            conn.set_dont_fragment(false).unwrap();
+            assert!(!conn.dont_fragment().unwrap());
+            conn.set_dont_fragment(true).unwrap();
+            assert!(conn.dont_fragment().unwrap());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_from_raw_fd() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let socket =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+        socket.set_reuse_address(true).unwrap();
+        socket
+            .bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), listen_port).into())
+            .unwrap();
+        let fd = socket.into_raw_fd();
+        let listener =
+            unsafe { UdpListener::from_raw_fd(fd, IpFilterConfig::V4(None), false) }.unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+        assert_eq!(four_tuple.local_addr, listen_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hello world");
+
+        // The family must match the filter.
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let fd = socket.into_raw_fd();
+        let res = unsafe { UdpListener::from_raw_fd(fd, IpFilterConfig::V6(None), false) };
+        assert_eq!(res.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[serial]
+    fn test_multicast() {
+        setup();
+        let listen_port = 12345;
+        let group = Ipv4Addr::new(224, 0, 0, 251);
+        let group_addr = SocketAddr::new(group.into(), listen_port);
+        let listener = UdpListener::bind(
+            listen_port,
+            IpFilterConfig::V4(Some(HashSet::from([Ipv4Addr::LOCALHOST]))),
+            false,
+        )
+        .unwrap();
+        listener
+            .join_multicast_v4(group, Ipv4Addr::LOCALHOST)
+            .unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        socket2::SockRef::from(&send_socket)
+            .set_multicast_if_v4(&Ipv4Addr::LOCALHOST)
+            .unwrap();
+        send_socket.send_to(b"hello", group_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Shared(mut handle) = res else {
+            panic!();
+        };
+        assert_eq!(four_tuple.local_addr, group_addr);
+        assert_eq!(four_tuple.remote_addr, send_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hello");
+        let early_pkt_recv = handle.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv().unwrap(), b"hello");
+
+        // Replies go out unicast.
+        handle.send(b"world").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"world");
+        assert_eq!(
+            from,
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port)
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_allow_broadcast() {
+        setup();
+        let listen_port = 12345;
+        let broadcast_addr = SocketAddr::new(Ipv4Addr::BROADCAST.into(), listen_port);
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let four_tuple = FourTuple {
+            local_addr: broadcast_addr,
+            remote_addr: send_addr,
+        };
+        let ip_filter = || IpFilterConfig::V4(Some(HashSet::from([Ipv4Addr::LOCALHOST])));
+
+        let listener = UdpListener::bind(listen_port, ip_filter(), false).unwrap();
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"discover"[..]))
+            .unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+        drop(listener);
+
+        let mut config = UdpListenerConfig::new(ip_filter(), false);
+        config.allow_broadcast = true;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        assert!(listener.socket().broadcast().unwrap());
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"discover"[..]))
+            .unwrap();
+        let AcceptRes::Shared(handle) = res else {
+            panic!();
+        };
+        assert_eq!(handle.four_tuple(), &four_tuple);
+
+        // Replies go out from a unicast address.
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        handle.send(b"offer").unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"offer");
+        assert_eq!(
+            from,
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port)
+        );
+        listener.refresh_broadcast_addrs().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_conns_per_ip() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.max_conns_per_ip = Some(2);
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port_start = 54321;
+        let send_sockets = (0..4)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+                UdpSocket::bind(send_addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let accept = |send_socket: &UdpSocket| {
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let mut recv_buf = [0u8; 1024];
+            listener.accept(&mut recv_buf).unwrap().0
+        };
+
+        let mut conns = Vec::new();
+        for send_socket in &send_sockets[..2] {
+            let AcceptRes::Ok(conn) = accept(send_socket) else {
+                panic!();
+            };
+            conns.push(conn);
+        }
+        let remote_ip = Ipv4Addr::LOCALHOST.into();
+        assert_eq!(listener.ip_conn_count(&remote_ip), 2);
+
+        let AcceptRes::IpConnLimit { four_tuple } = accept(&send_sockets[2]) else {
+            panic!();
+        };
+        assert_eq!(
+            four_tuple.remote_addr,
+            send_sockets[2].local_addr().unwrap()
+        );
+
+        conns.pop();
+        assert_eq!(listener.ip_conn_count(&remote_ip), 1);
+        let AcceptRes::Ok(_conn) = accept(&send_sockets[3]) else {
+            panic!();
+        };
+        assert_eq!(listener.ip_conn_count(&remote_ip), 2);
+    }
+
+    #[test]
+    fn test_listener_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<UdpListener>();
+        assert_send_sync::<UdpConn>();
+        assert_send_sync::<UdpListener<StdChannel>>();
+        assert_send_sync::<UdpConn<StdChannel>>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<UdpListener<TokioChannel>>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<UdpConn<TokioChannel>>();
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_accept_distinct_clients() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter = IpFilterConfig::V4(None);
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let send_sockets = (0..2)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+                UdpSocket::bind(send_addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut remote_addrs = std::thread::scope(|s| {
+            let threads = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut recv_buf = [0u8; 1024];
+                        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+                        let AcceptRes::Ok(conn) = res else {
+                            panic!();
+                        };
+                        conn.four_tuple().remote_addr
+                    })
+                })
+                .collect::<Vec<_>>();
+            for send_socket in &send_sockets {
+                send_socket.send_to(b"hello world", listen_addr).unwrap();
+            }
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        remote_addrs.sort();
+        let send_addrs = send_sockets
+            .iter()
+            .map(|send_socket| send_socket.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(remote_addrs, send_addrs);
+    }
+
+    #[test]
+    #[serial]
+    fn test_conn_recv_modes() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+
+        for recv_mode in [ConnRecvMode::Classify, ConnRecvMode::Connected] {
+            let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+            config.conn.recv_mode = recv_mode;
+            let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+            let send_port = 54321;
+            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(mut conn) = res else {
+                panic!();
+            };
+
+            send_socket.send_to(b"second", listen_addr).unwrap();
+            let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+            assert!(matches!(res, RecvRes::Ok));
+            assert_eq!(&recv_buf[..recv_len], b"second");
+
+            send_socket.send_to(b"third", listen_addr).unwrap();
+            let recv_len = conn.recv_plain(&mut recv_buf).unwrap();
+            assert_eq!(&recv_buf[..recv_len], b"third");
+            assert_eq!(conn.traffic_stats().packets_received, 2);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_unconnected_conn_roams() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.conn.unconnected = true;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+
+        // The NAT of the peer picks a new source port.
+        let roamed_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54322);
+        let roamed_socket = UdpSocket::bind(roamed_addr).unwrap();
+        roamed_socket.send_to(b"roamed", listen_addr).unwrap();
+        let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..recv_len], b"roamed");
+        assert_eq!(conn.four_tuple().remote_addr, roamed_addr);
+        assert!(listener.has_connection(conn.four_tuple()));
+        assert!(!listener.has_connection(&four_tuple));
+
+        conn.send(b"reply").unwrap();
+        let (recv_len, addr) = roamed_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"reply");
+        assert_eq!(addr, listen_addr);
+
+        conn.send_to(b"old port", send_addr).unwrap();
+        let (recv_len, _) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"old port");
+
+        // Another remote IP is not the peer.
+        let other_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 54321);
+        let other_socket = UdpSocket::bind(other_addr).unwrap();
+        other_socket.send_to(b"other", listen_addr).unwrap();
+        let (res, _) = conn.recv(&mut recv_buf).unwrap();
+        let RecvRes::ListenerPkt(other_four_tuple) = res else {
+            panic!();
+        };
+        assert_eq!(other_four_tuple.remote_addr, other_addr);
+        assert_eq!(conn.four_tuple().remote_addr, roamed_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_filters() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.accept_filters.push(Box::new(IpFilterConfig::V4Deny(
+            [Ipv4Addr::new(127, 0, 0, 2)].into_iter().collect(),
+        )));
+
+        let mut listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        listener.push_accept_filter(Box::new(|_: &FourTuple, pkt: &[u8]| match pkt.first() {
+            Some(b'h') => FilterDecision::Accept,
+            Some(_) => FilterDecision::Reject,
+            None => FilterDecision::RejectSilently,
+        }));
+        listener.push_accept_filter(Box::new(|_: &FourTuple, pkt: &[u8]| {
+            match pkt.starts_with(b"hello") {
+                true => FilterDecision::Accept,
+                false => FilterDecision::Reject,
+            }
+        }));
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        send_socket.send_to(b"bad magic", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::RejectedByFilter { index: 1 }));
+
+        send_socket.send_to(b"hi", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::RejectedByFilter { index: 2 }));
+
+        send_socket.send_to(b"", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        let denied_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), listen_port);
+        send_socket.send_to(b"hello world", denied_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+        assert_eq!(listener.conn_count(), 0);
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+
+        // Existing connections bypass the filters.
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"bad magic"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_ip_filter() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V4(Some([Ipv4Addr::LOCALHOST].iter().cloned().collect()));
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port_start = 54321;
+        let send_sockets = (0..2)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port_start + i);
+                UdpSocket::bind(send_addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut recv_buf = [0u8; 1024];
+
+        send_sockets[0]
+            .send_to(b"hello world", listen_addr)
+            .unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+
+        let other_ip = Ipv4Addr::new(127, 0, 0, 2);
+        listener
+            .set_ip_filter(IpFilterConfig::V4(Some(
+                [other_ip].iter().cloned().collect(),
+            )))
+            .unwrap();
+        send_sockets[1]
+            .send_to(b"hello world", listen_addr)
+            .unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        let err = listener
+            .set_ip_filter(IpFilterConfig::V6(None))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cookie_validation() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        let cookie = |four_tuple: &FourTuple| format!("cookie:{}", four_tuple.remote_addr.port());
+        config.validator = Some(Box::new(move |four_tuple, pkt| {
+            let cookie = cookie(four_tuple);
+            if pkt == cookie.as_bytes() {
+                Validation::CreateConn
+            } else {
+                Validation::Reply(cookie.into_bytes())
+            }
+        }));
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Replied));
+        assert_eq!(listener.conn_count(), 0);
+
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(from, listen_addr);
+        let cookie = recv_buf[..recv_len].to_vec();
+        assert_eq!(cookie, format!("cookie:{send_port}").as_bytes());
+
+        send_socket.send_to(&cookie, listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+        assert_eq!(listener.conn_count(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_validator() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        listener.set_validator(Some(Box::new(|_, pkt| match pkt {
+            b"accept" => Validation::CreateConn,
+            b"reply" => Validation::Reply(b"stateless".to_vec()),
+            _ => Validation::Drop,
+        })));
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        send_socket.send_to(b"drop", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::ValidationFailed));
+
+        send_socket.send_to(b"reply", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Replied));
+        let (recv_len, _) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"stateless");
+        assert_eq!(listener.conn_count(), 0);
+
+        // A reply too long for a datagram is reported rather than failing `accept`.
+        listener.set_validator(Some(Box::new(|_, _| Validation::Reply(vec![0; 1 << 16]))));
+        send_socket.send_to(b"reply", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::ReplyFailed { err } = res else {
+            panic!();
+        };
+        assert_eq!(err.raw_os_error(), Some(libc::EMSGSIZE));
+        assert_eq!(listener.conn_count(), 0);
+        listener.set_validator(Some(Box::new(|_, pkt| match pkt {
+            b"accept" => Validation::CreateConn,
+            _ => Validation::Drop,
+        })));
+
+        send_socket.send_to(b"accept", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+
+        // Without a validator, every new connection is created.
+        listener.set_validator(None);
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port + 1);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"drop", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn_2) = res else {
+            panic!();
+        };
+        assert_eq!(listener.conn_count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_predicate() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        listener.set_accept_predicate(move |four_tuple, pkt| {
+            assert_eq!(four_tuple.local_addr, listen_addr);
+            match pkt {
+                b"accept" => AcceptDecision::Accept,
+                b"reply" => AcceptDecision::Reply(b"stateless".to_vec()),
+                _ => AcceptDecision::Reject,
+            }
+        });
+
+        let send_socket = UdpSocket::bind("127.0.0.1:54321").unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        send_socket.send_to(b"reject", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::ValidationFailed));
+
+        send_socket.send_to(b"reply", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Replied));
+        let (recv_len, _) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"stateless");
+        assert_eq!(listener.conn_count(), 0);
+
+        send_socket.send_to(b"accept", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+        assert_eq!(listener.conn_count(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_futures_channel_backend() {
+        check_channel_backend::<FuturesChannel>();
+    }
+
+    #[test]
+    #[serial]
+    fn test_std_channel_backend() {
+        check_channel_backend::<StdChannel>();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    #[serial]
+    fn test_tokio_channel_backend() {
+        check_channel_backend::<TokioChannel>();
+    }
+
+    /// Accept a connection, route an early packet to it, and remove it on drop.
+    fn check_channel_backend<B: ChannelBackend>() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        let listener = UdpListener::<B>::bind_with_backend(listen_port, config).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"first", listen_addr).unwrap();
+        send_socket.send_to(b"early", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        let early_pkt_recv = conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv(), Ok(b"first".to_vec()));
+        assert_eq!(early_pkt_recv.try_recv(), Ok(b"early".to_vec()));
+        assert_eq!(early_pkt_recv.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(listener.early_pkt_queued_bytes(), 0);
+
+        drop(conn);
+        assert!(!listener.has_connection(&four_tuple));
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_ignore_empty_datagrams() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        // Off: an empty datagram creates a connection.
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        send_socket.send_to(b"", listen_addr).unwrap();
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+        assert_eq!(recv_len, 0);
+        drop(res);
+        drop(listener);
+
+        // On: the empty datagram is skipped for the next one.
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.ignore_empty_datagrams = true;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        send_socket.send_to(b"", listen_addr).unwrap();
+        send_socket.send_to(b"hello", listen_addr).unwrap();
+        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+        assert_eq!(recv_len, 5);
+        drop(res);
+
+        send_socket.send_to(b"", listen_addr).unwrap();
+        send_socket.send_to(b"hi", listen_addr).unwrap();
+        let mut recv_buf = [MaybeUninit::uninit(); 1024];
+        let (res, _, pkt) = listener.accept_uninit(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+        assert_eq!(pkt, b"hi");
+    }
+
+    #[test]
+    #[serial]
+    fn test_min_first_packet_len() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.min_first_packet_len = Some(4);
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        for pkt in [&b""[..], b"abc"] {
+            send_socket.send_to(pkt, listen_addr).unwrap();
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            assert!(matches!(res, AcceptRes::TooShort));
+        }
+        assert_eq!(listener.too_short_count(), 2);
+        assert_eq!(listener.conn_count(), 0);
+
+        send_socket.send_to(b"abcd", listen_addr).unwrap();
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+
+        // Existing connections are not checked.
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b""[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        assert_eq!(listener.too_short_count(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn test_send_to_ipv4() {
+        setup();
+        let listen_port = 12345;
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        // Not the address the routing table would pick.
+        let local_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), listen_port);
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: send_addr,
+        };
+        let send_len = listener.send_to(&four_tuple, b"hello world").unwrap();
+        assert_eq!(send_len, 11);
+
+        let mut recv_buf = [0u8; 1024];
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(from, local_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hello world");
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_send_to_ipv6() {
+        setup();
+        let listen_port = 12345;
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V6(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let local_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
+        let four_tuple = FourTuple {
+            local_addr,
+            remote_addr: send_addr,
+        };
+        let send_len = listener.send_to(&four_tuple, b"hello world").unwrap();
+        assert_eq!(send_len, 11);
+
+        let mut recv_buf = [0u8; 1024];
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(from, local_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hello world");
+    }
+
+    #[test]
+    #[serial]
+    fn test_shared_socket_interleaved_flows() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        let rounds = 5;
+        config.conn_strategy = ConnStrategy::SharedSocket { queue_len: rounds };
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let send_sockets = (0..10)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321 + i);
+                UdpSocket::bind(send_addr).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut handles = Vec::new();
+        let mut recv_buf = [0u8; 1024];
+        for round in 0..rounds {
+            for (i, send_socket) in send_sockets.iter().enumerate() {
+                let pkt = format!("{i}:{round}");
+                send_socket.send_to(pkt.as_bytes(), listen_addr).unwrap();
+                let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+                match res {
+                    AcceptRes::Shared(handle) => {
+                        assert_eq!(round, 0);
+                        handles.push(handle);
+                    }
+                    AcceptRes::ConnAlreadyExists { .. } => assert_ne!(round, 0),
+                    _ => panic!(),
+                }
+            }
+        }
+        assert_eq!(listener.conn_count(), send_sockets.len());
+
+        for (i, (handle, send_socket)) in handles.iter_mut().zip(&send_sockets).enumerate() {
+            assert_eq!(
+                handle.four_tuple().remote_addr,
+                send_socket.local_addr().unwrap()
+            );
+            for round in 0..rounds {
+                let pkt = handle
+                    .recv_early_pkt_mut()
+                    .recv_early_pkt_mut()
+                    .try_recv()
+                    .unwrap();
+                assert_eq!(pkt, format!("{i}:{round}").as_bytes());
+            }
+            assert!(handle
+                .recv_early_pkt_mut()
+                .recv_early_pkt_mut()
+                .try_recv()
+                .is_err());
+
+            handle.send(format!("ack:{i}").as_bytes()).unwrap();
+            let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+            assert_eq!(from, listen_addr);
+            assert_eq!(&recv_buf[..recv_len], format!("ack:{i}").as_bytes());
+        }
+
+        drop(handles);
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    fn test_parse_ip_filter() {
+        let filter = IpFilterConfig::parse(Some(IpFamily::V6), "*").unwrap();
+        assert!(matches!(filter, IpFilterConfig::V6(None)));
+        assert_eq!(
+            IpFilterConfig::parse(None, "*").unwrap_err(),
+            IpFilterParseErr::UnknownFamily
+        );
+
+        let filter = IpFilterConfig::parse(None, "10.0.0.1, 10.0.0.2").unwrap();
+        let IpFilterConfig::V4(Some(addrs)) = filter else {
+            panic!();
+        };
+        let expected = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        assert_eq!(addrs, expected.into_iter().collect());
+        let filter = IpFilterConfig::parse(Some(IpFamily::V6), "::1").unwrap();
+        assert!(
+            matches!(filter, IpFilterConfig::V6(Some(addrs)) if addrs.contains(&Ipv6Addr::LOCALHOST))
+        );
+
+        assert_eq!(
+            IpFilterConfig::parse(Some(IpFamily::V4), "10.0.0.1,::1").unwrap_err(),
+            IpFilterParseErr::WrongFamily(Ipv6Addr::LOCALHOST.into())
+        );
+        assert_eq!(
+            IpFilterConfig::parse(None, "::1,10.0.0.1").unwrap_err(),
+            IpFilterParseErr::WrongFamily(Ipv4Addr::new(10, 0, 0, 1).into())
+        );
+        assert_eq!(
+            IpFilterConfig::parse(None, "10.0.0.1,").unwrap_err(),
+            IpFilterParseErr::InvalidAddr(String::new())
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_deny_ip_filter_ipv4() {
+        setup();
+        let listen_port = 12345;
+        let denied_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let allowed_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), listen_port);
+        let local_ip_filter =
+            IpFilterConfig::V4Deny([Ipv4Addr::LOCALHOST].iter().cloned().collect());
+
+        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        send_socket.send_to(b"hello world", denied_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        send_socket.send_to(b"hello world", allowed_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.four_tuple().local_addr, allowed_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_deny_ip_filter_ipv6() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        {
+            let local_ip_filter =
+                IpFilterConfig::V6Deny([Ipv6Addr::LOCALHOST].iter().cloned().collect());
+            let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            assert!(matches!(res, AcceptRes::Filtered));
+        }
+
+        setup();
+        {
+            let other_ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+            let local_ip_filter = IpFilterConfig::V6Deny([other_ip].iter().cloned().collect());
+            let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            assert_eq!(conn.four_tuple().local_addr, listen_addr);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_reconnect() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let old_send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let old_send_socket = UdpSocket::bind(old_send_addr).unwrap();
+        let new_send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54322);
+        let new_send_socket = UdpSocket::bind(new_send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        old_send_socket.send_to(b"hello", listen_addr).unwrap();
+        let (res, old_four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        let pkt = conn
+            .recv_early_pkt_mut()
+            .recv_early_pkt_mut()
+            .try_recv()
+            .unwrap();
+        assert_eq!(pkt, b"hello");
+
+        let new_four_tuple = FourTuple {
+            local_addr: old_four_tuple.local_addr,
+            remote_addr: new_send_addr,
+        };
+        conn.reconnect(new_four_tuple).unwrap();
+        assert_eq!(conn.four_tuple(), &new_four_tuple);
+        assert!(!listener.has_connection(&old_four_tuple));
+        assert!(listener.has_connection(&new_four_tuple));
+        assert_eq!(listener.conn_count(), 1);
+
+        // Early packets of the new four-tuple reach the connection.
+        let res = listener
+            .accept_raw(&new_four_tuple, Cow::from(&b"world"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        let pkt = conn
+            .recv_early_pkt_mut()
+            .recv_early_pkt_mut()
+            .try_recv()
+            .unwrap();
+        assert_eq!(pkt, b"world");
+
+        // The socket talks to the new remote address.
+        conn.socket().send(b"hi").unwrap();
+        let (recv_len, from) = new_send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(from, listen_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hi");
+
+        drop(conn);
+        assert_eq!(listener.conn_count(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_remote_ip_filter() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.remote_ip_filter = Some(RemoteIpFilter {
+            allow: Some(vec![
+                IpPrefix::new(Ipv4Addr::new(127, 0, 0, 0).into(), 8).unwrap()
+            ]),
+            deny: vec![IpPrefix::from(IpAddr::from(Ipv4Addr::new(127, 0, 0, 2)))],
+        });
+
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+        let allowed_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let allowed_socket = UdpSocket::bind(allowed_addr).unwrap();
+        let denied_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 54321);
+        let denied_socket = UdpSocket::bind(denied_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        denied_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::RemoteFiltered));
+        assert_eq!(listener.conn_count(), 0);
+
+        allowed_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+    }
+
+    #[test]
+    fn test_ip_prefix() {
+        let prefix = IpPrefix::new(Ipv4Addr::new(10, 1, 0, 0).into(), 16).unwrap();
+        assert!(prefix.contains(&Ipv4Addr::new(10, 1, 2, 3).into()));
+        assert!(!prefix.contains(&Ipv4Addr::new(10, 2, 0, 0).into()));
+        assert!(!prefix.contains(&Ipv6Addr::LOCALHOST.into()));
+
+        let prefix = IpPrefix::new("2001:db8::".parse().unwrap(), 32).unwrap();
+        assert!(prefix.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!prefix.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all = IpPrefix::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap();
+        assert!(all.contains(&Ipv4Addr::BROADCAST.into()));
+        assert!(IpPrefix::new(Ipv4Addr::UNSPECIFIED.into(), 33).is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_peek_len() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        assert_eq!(listener.peek_len().unwrap(), 0);
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let send_buf = [7u8; 1500];
+        send_socket.send_to(&send_buf, listen_addr).unwrap();
+        assert_eq!(listener.peek_len().unwrap(), 1500);
+
+        let rx_buf = vec![0; listener.peek_len().unwrap()];
+        let (res, _, recv_len) = listener.accept_owned(rx_buf).unwrap();
+        assert_eq!(recv_len, 1500);
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        assert_eq!(listener.peek_len().unwrap(), 0);
+
+        send_socket.send_to(&send_buf[..100], listen_addr).unwrap();
+        assert_eq!(conn.peek_len().unwrap(), 100);
+    }
+
+    #[test]
+    #[serial]
+    fn test_add_remove_local_ip() {
+        setup();
+        let listen_port = 12345;
+        let listen_ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let listen_addr = SocketAddr::new(listen_ip, listen_port);
+
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+
+        listener.remove_local_ip(listen_ip).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        listener.add_local_ip(listen_ip).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(conn) = res else {
+            panic!();
+        };
+        drop(conn);
+
+        // An allow list shrinks and grows the same way.
+        listener
+            .set_ip_filter(IpFilterConfig::V4(Some(HashSet::new())))
+            .unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Filtered));
+
+        listener.add_local_ip(listen_ip).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn) = res else {
+            panic!();
+        };
+
+        let err = listener
+            .add_local_ip(Ipv6Addr::LOCALHOST.into())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[serial]
+    fn test_force_recv_buffer_size() {
+        setup();
+        let listen_port = 12345;
+        let size = 64 * 1024 * 1024;
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.recv_buffer_size = Some(size);
+        config.force_recv_buffer_size = true;
+
+        // Falls back to `SO_RCVBUF` without privileges instead of failing.
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        let actual = listener.socket().recv_buffer_size().unwrap();
+        let is_privileged = setsockopt_int(
+            listener.socket(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUFFORCE,
+            size.try_into().unwrap(),
+        )
+        .is_ok();
+        if is_privileged {
+            // The kernel doubles the value for its bookkeeping.
+            assert!(actual >= size);
+        } else {
+            assert!(actual > 0);
+        }
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_freebind() {
+        setup();
+        let listen_port = 12345;
+        // Not configured on the host.
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::new(198, 51, 100, 1).into(), listen_port),
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        let err = listener.register(four_tuple).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        drop(listener);
+
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.freebind = true;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        // The bind passes; `connect` still needs a route.
+        if let Err(e) = listener.register(four_tuple) {
+            assert_ne!(e.kind(), io::ErrorKind::AddrNotAvailable);
+        }
+        drop(listener);
+
+        let socket =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None).unwrap();
+        set_freebind_transparent(&socket, true, false).unwrap();
+        socket.bind(&four_tuple.local_addr.into()).unwrap();
+        drop(socket);
+
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.transparent = true;
+        match UdpListener::bind_with_config(listen_port, config) {
+            Ok(_listener) => (),
+            // Unprivileged.
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+        }
+    }
+
+    /// Intercepting real traffic also needs a TPROXY rule and a local route, e.g.
+    ///
+    /// ```sh
+    /// iptables -t mangle -A PREROUTING -p udp --dport 80 -j TPROXY --on-port 12345 --tproxy-mark 1
+    /// ip rule add fwmark 1 lookup 100
+    /// ip route add local 0.0.0.0/0 dev lo table 100
+    /// ```
+    ///
+    /// Skipped without `CAP_NET_ADMIN`.
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_transparent_proxy() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.transparent = true;
+        let listener = match UdpListener::bind_with_config(listen_port, config) {
+            Ok(listener) => listener,
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+                return;
+            }
+        };
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(res, AcceptRes::Ok(_)));
+        assert_eq!(four_tuple.local_addr, listen_addr);
+
+        // As if intercepted on its way to a foreign server.
+        let orig_dst = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 1).into(), 80);
+        let conn = listener
+            .register(FourTuple {
+                local_addr: orig_dst,
+                remote_addr: send_addr,
+            })
+            .unwrap();
+        conn.send(b"ping").unwrap();
+        let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"ping");
+        assert_eq!(from, orig_dst);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_udp_lite() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.protocol = UdpProtocol::UdpLite;
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+        listener.set_recv_checksum_coverage(8).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::DGRAM,
+            Some(UdpProtocol::UdpLite.to_socket2()),
+        )
+        .unwrap();
+        send_socket.bind(&send_addr.into()).unwrap();
+        set_send_checksum_coverage(&send_socket, 8).unwrap();
+        send_socket
+            .send_to(b"hello world", &listen_addr.into())
+            .unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, four_tuple, recv_len) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        assert_eq!(four_tuple.remote_addr, send_addr);
+        assert_eq!(&recv_buf[..recv_len], b"hello world");
+        assert_eq!(
+            conn.socket().protocol().unwrap(),
+            Some(UdpProtocol::UdpLite.to_socket2())
+        );
+
+        conn.set_recv_checksum_coverage(8).unwrap();
+        conn.set_send_checksum_coverage(8).unwrap();
+        send_socket.send_to(b"again", &listen_addr.into()).unwrap();
+        let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..recv_len], b"again");
+
+        conn.send(b"back").unwrap();
+        let std_socket = std::net::UdpSocket::from(send_socket);
+        let (recv_len, from) = std_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"back");
+        assert_eq!(from, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_with_meta() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 5];
+        let (res, _, recv_len, meta) = listener.accept_with_meta(&mut recv_buf).unwrap();
+        assert_eq!(recv_len, 5);
+        assert!(meta.flags.truncated);
+        assert!(!meta.flags.ctrl_truncated);
+        assert_eq!(meta.ifindex, Some(if_name_to_index("lo").unwrap()));
+        let pktinfo_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::in_pktinfo>() as _) };
+        assert_eq!(meta.cmsg_len, pktinfo_len as usize);
+        assert_eq!(listener.last_cmsg_bytes(), meta.cmsg_len);
+        assert!(listener.last_cmsg_bytes() <= listener.cmsg_capacity());
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, recv_len, meta) = conn.recv_with_meta(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(recv_len, 11);
+        assert_eq!(meta.flags, RecvFlags::default());
+    }
+
+    #[test]
+    #[serial]
+    fn test_accept_with_cmsg_buf() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        // Keep every packet on the listener socket.
+        listener.push_accept_filter(Box::new(|_: &FourTuple, _: &[u8]| {
+            FilterDecision::RejectSilently
+        }));
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let mut cmsg_buf = Vec::new();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        listener
+            .accept_with_cmsg_buf(&mut recv_buf, &mut cmsg_buf)
+            .unwrap();
+        let ptr = cmsg_buf.as_ptr();
+        let capacity = cmsg_buf.capacity();
+        assert!(capacity > 0);
+
+        for _ in 0..1000 {
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let (res, four_tuple, recv_len, _) = listener
+                .accept_with_cmsg_buf(&mut recv_buf, &mut cmsg_buf)
+                .unwrap();
+            assert!(matches!(res, AcceptRes::Filtered));
+            assert_eq!(four_tuple.local_addr, listen_addr);
+            assert_eq!(recv_len, 11);
+            // No allocation per packet.
+            assert_eq!(cmsg_buf.as_ptr(), ptr);
+            assert_eq!(cmsg_buf.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_register() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let four_tuple = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: send_addr,
+        };
+        let mut conn = listener.register(four_tuple).unwrap();
+        assert_eq!(conn.four_tuple(), &four_tuple);
+        let err = listener.register(four_tuple).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // The first packet is not lost.
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..recv_len], b"hello world");
+
+        // Packets reaching the listener take the existing-connection path.
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"hello world"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        let early_pkt_recv = conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkt_recv.try_recv().unwrap(), b"hello world");
+    }
+
+    #[test]
+    #[serial]
+    fn test_connect_out() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(
+            listen_port,
+            IpFilterConfig::V4(Some(HashSet::from([Ipv4Addr::LOCALHOST]))),
+            false,
+        )
+        .unwrap();
+
+        let peer_port = 54321;
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), peer_port);
+        let peer_socket = UdpSocket::bind(peer_addr).unwrap();
+
+        let err = listener
+            .connect_out(peer_addr, Ipv4Addr::new(127, 0, 0, 2).into())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut conn = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .unwrap();
+        let err = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // Out from the listener port.
+        conn.socket().send(b"hello").unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (recv_len, from) = peer_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"hello");
+        assert_eq!(from, listen_addr);
+
+        // And back.
+        peer_socket.send_to(b"world", listen_addr).unwrap();
+        let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..recv_len], b"world");
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_send_from() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let peer_port = 54321;
+        let peer_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), peer_port);
+        let peer_socket = UdpSocket::bind(peer_addr).unwrap();
+        let conn = listener
+            .connect_out(peer_addr, Ipv4Addr::LOCALHOST.into())
+            .unwrap();
+
+        // The whole 127.0.0.0/8 is local on Linux.
+        let src = Ipv4Addr::new(127, 0, 0, 2);
+        conn.send_from(b"hello", src.into()).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (recv_len, from) = peer_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"hello");
+        assert_eq!(from, SocketAddr::new(src.into(), listen_port));
+
+        conn.socket().send(b"world").unwrap();
+        let (_, from) = peer_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(from, listen_addr);
+    }
+
+    #[test]
+    #[serial]
+    fn test_recv_any() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"early", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        send_socket.send_to(b"socket", listen_addr).unwrap();
+
+        let RecvAny::Early(pkt) = conn.recv_any(&mut recv_buf).unwrap() else {
+            panic!();
+        };
+        assert_eq!(pkt, b"early");
+        let RecvAny::Socket { res, len } = conn.recv_any(&mut recv_buf).unwrap() else {
+            panic!();
+        };
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..len], b"socket");
+    }
+
+    #[test]
+    #[serial]
+    fn test_recv_unified() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        for pkt in [&b"early 1"[..], b"early 2", b"early 3", b"early 4 is long"] {
+            send_socket.send_to(pkt, listen_addr).unwrap();
+        }
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        // Route the next packet queued on the listener socket before the connection socket existed.
+        let route_early_pkt = || {
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            assert!(matches!(
+                res,
+                AcceptRes::ConnAlreadyExists { delivered: true }
+            ));
+        };
+        let recv_pkt = |conn: &mut UdpConn, buf_size: usize| {
+            let mut buf = vec![0u8; buf_size];
+            let (source, len) = conn.recv_unified(&mut buf).unwrap();
+            let early = match source {
+                RecvSource::Early => true,
+                RecvSource::Socket(RecvRes::Ok) => false,
+                RecvSource::Socket(res) => panic!("{res:?}"),
+            };
+            (early, buf[..len].to_vec())
+        };
+
+        send_socket.send_to(b"socket 1", listen_addr).unwrap();
+        route_early_pkt();
+        assert_eq!(recv_pkt(&mut conn, 1024), (true, b"early 1".to_vec()));
+        assert_eq!(recv_pkt(&mut conn, 1024), (true, b"early 2".to_vec()));
+        assert_eq!(recv_pkt(&mut conn, 1024), (false, b"socket 1".to_vec()));
+
+        // Blocks on the socket while the channel is empty.
+        let sender = send_socket.try_clone().unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send_to(b"socket 2", listen_addr).unwrap();
+        });
+        assert_eq!(recv_pkt(&mut conn, 1024), (false, b"socket 2".to_vec()));
+        handle.join().unwrap();
+
+        // The next call takes an early packet delivered in the meantime first.
+        send_socket.send_to(b"socket 3", listen_addr).unwrap();
+        route_early_pkt();
+        assert_eq!(recv_pkt(&mut conn, 1024), (true, b"early 3".to_vec()));
+        assert_eq!(recv_pkt(&mut conn, 1024), (false, b"socket 3".to_vec()));
+
+        // An early packet longer than the buffer is cut short.
+        route_early_pkt();
+        assert_eq!(recv_pkt(&mut conn, 7), (true, b"early 4".to_vec()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_blocking_channel_recv() {
+        check_blocking_channel_recv::<FuturesChannel>();
+    }
+
+    #[test]
+    #[serial]
+    fn test_blocking_channel_recv_std() {
+        check_blocking_channel_recv::<StdChannel>();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    #[serial]
+    fn test_blocking_channel_recv_tokio() {
+        check_blocking_channel_recv::<TokioChannel>();
+    }
+
+    fn check_blocking_channel_recv<B: ChannelBackend>() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        let mut listener = UdpListener::<B>::bind_with_backend(listen_port, config).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"early 1", listen_addr).unwrap();
+        send_socket.send_to(b"early 2", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.recv_early_pkt_blocking().unwrap(), b"early 1");
+        let err = conn
+            .recv_early_pkt_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err, RecvTimeoutError::Timeout);
+
+        // Another thread routes the second packet while this one blocks.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                let mut recv_buf = [0u8; 1024];
+                let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+                assert!(matches!(
+                    res,
+                    AcceptRes::ConnAlreadyExists { delivered: true }
+                ));
+            });
+            assert_eq!(conn.recv_early_pkt_blocking().unwrap(), b"early 2");
+        });
+        assert_eq!(conn.traffic_stats().packets_received, 2);
+
+        assert!(listener
+            .recv_listener_pkt_timeout(Duration::from_millis(10))
+            .is_none());
+        let four_tuple = *conn.four_tuple();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                conn.forward_to_listener(four_tuple, b"back");
+            });
+            let pkt = listener.recv_listener_pkt_blocking();
+            assert_eq!(pkt, (four_tuple, b"back".to_vec()));
+        });
+
+        drop(listener);
+        assert_eq!(conn.recv_early_pkt_blocking().unwrap_err(), RecvError);
+    }
+
+    #[test]
+    #[serial]
+    fn test_take_early_pkt_recv() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"early 1", listen_addr).unwrap();
+        send_socket.send_to(b"early 2", listen_addr).unwrap();
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        let mut early_pkt_recv = conn.take_early_pkt_recv().unwrap();
+        assert!(conn.take_early_pkt_recv().is_none());
+        assert!(conn.try_clone().unwrap().take_early_pkt_recv().is_none());
+
+        // The receiver keeps getting the early packets of the connection.
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        let pkts = futures::executor::block_on(early_pkt_recv.by_ref().take(2).collect::<Vec<_>>());
+        assert_eq!(pkts, [b"early 1".to_vec(), b"early 2".to_vec()]);
+        assert!(conn.drain_early_pkts().is_empty());
+
+        send_socket.send_to(b"socket", listen_addr).unwrap();
+        let (res, recv_len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(&recv_buf[..recv_len], b"socket");
+        conn.send(b"hi").unwrap();
+        let (recv_len, _) = send_socket.recv_from(&mut recv_buf).unwrap();
+        assert_eq!(&recv_buf[..recv_len], b"hi");
+
+        drop(conn);
+        assert_eq!(early_pkt_recv.recv().unwrap_err(), RecvError);
+    }
+
+    #[test]
+    #[serial]
+    fn test_forward_to_listener() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let mut accept = |listener: &UdpListener, ip: Ipv4Addr| {
+            let send_socket = UdpSocket::bind(SocketAddr::new(ip.into(), 54321)).unwrap();
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(mut conn) = res else {
+                panic!();
+            };
+            assert_eq!(conn.drain_early_pkts(), [b"hello".to_vec()]);
+            conn
+        };
+        let mut conn_a = accept(&listener, Ipv4Addr::LOCALHOST);
+        let mut conn_b = accept(&listener, Ipv4Addr::new(127, 0, 0, 2));
+
+        // A packet of B lands on the socket of A.
+        let four_tuple_b = *conn_b.four_tuple();
+        let res = conn_a.forward_to_listener(four_tuple_b, b"for b");
+        assert_eq!(res, ForwardRes::Ok);
+
+        let (four_tuple, pkt) = listener.recv_listener_pkt_mut().try_recv().unwrap();
+        assert_eq!(four_tuple, four_tuple_b);
+        let res = listener.accept_raw(&four_tuple, Cow::from(pkt)).unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        assert_eq!(conn_b.drain_early_pkts(), [b"for b".to_vec()]);
+        assert!(conn_a.drain_early_pkts().is_empty());
+
+        drop(listener);
+        let res = conn_a.forward_to_listener(four_tuple_b, b"for b");
+        assert_eq!(res, ForwardRes::ListenerGone);
+    }
+
+    #[test]
+    #[serial]
+    fn test_try_recv_without_executor() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+        let send_socket = UdpSocket::bind("127.0.0.1:54321").unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+
+        // The first packet waits in the early packet channel.
+        assert!(conn.early_pkt_pending());
+        let mut buf = [0u8; 5];
+        assert_eq!(conn.try_recv_early_pkt(&mut buf), Some(5));
+        assert_eq!(&buf, b"hello");
+        assert!(!conn.early_pkt_pending());
+        assert_eq!(conn.try_recv_early_pkt(&mut buf), None);
+        assert!(!conn.is_early_pkt_closed());
+        assert_eq!(conn.traffic_stats().bytes_received, 11);
+
+        assert!(listener.try_recv_listener_pkt().is_none());
+        let four_tuple = *conn.four_tuple();
+        assert_eq!(
+            conn.forward_to_listener(four_tuple, b"back"),
+            ForwardRes::Ok
+        );
+        assert_eq!(
+            listener.try_recv_listener_pkt(),
+            Some((four_tuple, b"back".to_vec()))
+        );
+        assert!(listener.try_recv_listener_pkt().is_none());
+
+        drop(listener);
+        assert!(conn.is_early_pkt_closed());
+        assert_eq!(conn.try_recv_early_pkt(&mut buf), None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_redirect() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let conns = (1..=3)
+            .map(|i| {
+                let send_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, i).into(), 54321);
+                let send_socket = UdpSocket::bind(send_addr).unwrap();
+                send_socket.send_to(b"hello", listen_addr).unwrap();
+                let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+                let AcceptRes::Ok(mut conn) = res else {
+                    panic!();
+                };
+                assert_eq!(conn.drain_early_pkts(), [b"hello".to_vec()]);
+                conn
+            })
+            .collect::<Vec<_>>();
+        let four_tuples = conns
+            .iter()
+            .map(|conn| *conn.four_tuple())
+            .collect::<Vec<_>>();
 
-        // Send early packet to the new connection.
-        let res = self.chan.send_early_pkt(&conn.four_tuple(), buf);
-        match res {
-            SendRes::Ok => {}
-            SendRes::Full(_) => {}
-            SendRes::NotExist(_) => unreachable!(),
+        // Every connection passes on a packet of the next one at the same time.
+        let mut conns = std::thread::scope(|s| {
+            let handles = conns
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut conn)| {
+                    let four_tuple = four_tuples[(i + 1) % four_tuples.len()];
+                    s.spawn(move || {
+                        let res = conn.redirect(four_tuple, format!("from {i}").into_bytes());
+                        assert!(matches!(res, SendRes::Ok));
+                        conn
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        for (i, conn) in conns.iter_mut().enumerate() {
+            let from = (i + four_tuples.len() - 1) % four_tuples.len();
+            assert_eq!(
+                conn.drain_early_pkts(),
+                [format!("from {from}").into_bytes()]
+            );
         }
 
-        Ok(AcceptRes::Ok(conn))
+        // A four-tuple without a connection falls back to the listener packet channel.
+        let unknown = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::new(127, 0, 0, 4).into(), 54321),
+        };
+        let res = conns[0].redirect(unknown, b"unknown".to_vec());
+        assert!(matches!(res, SendRes::Ok));
+        let pkt = listener.recv_listener_pkt_mut().try_recv().unwrap();
+        assert_eq!(pkt, (unknown, b"unknown".to_vec()));
     }
 
-    pub fn socket(&self) -> &socket2::Socket {
-        &self.socket
-    }
+    #[test]
+    #[serial]
+    fn test_traffic_stats() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
 
-    pub fn socket_mut(&mut self) -> &mut socket2::Socket {
-        &mut self.socket
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        send_socket.send_to(b"early", listen_addr).unwrap();
+
+        let mut recv_buf = [0u8; 1024];
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        assert_eq!(conn.traffic_stats(), TrafficStats::default());
+
+        assert_eq!(conn.drain_early_pkts(), [b"early".to_vec()]);
+        send_socket.send_to(b"socket", listen_addr).unwrap();
+        let (res, len) = conn.recv(&mut recv_buf).unwrap();
+        assert!(matches!(res, RecvRes::Ok));
+        assert_eq!(len, 6);
+
+        conn.send(b"hi").unwrap();
+        let clone = conn.try_clone().unwrap();
+        clone.send(b"there").unwrap();
+        for _ in 0..2 {
+            send_socket.recv_from(&mut recv_buf).unwrap();
+        }
+
+        let stats = TrafficStats {
+            bytes_sent: 7,
+            packets_sent: 2,
+            bytes_received: 11,
+            packets_received: 2,
+        };
+        assert_eq!(conn.traffic_stats(), stats);
+        assert_eq!(clone.traffic_stats(), stats);
     }
 
-    fn local_port(&self) -> io::Result<u16> {
-        let port = self
-            .socket
-            .local_addr()?
-            .as_socket()
-            .ok_or(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "socket address is not a socket address",
-            ))?
-            .port();
-        Ok(port)
+    /// Entries in a `Vec`, counting every insert.
+    struct VecStore {
+        entries: Vec<(FourTuple, ConnEntry)>,
+        inserts: Arc<AtomicU64>,
     }
-}
+    impl EarlyPktStore for VecStore {
+        fn insert(&mut self, four_tuple: FourTuple, entry: ConnEntry) -> Option<ConnEntry> {
+            self.inserts.fetch_add(1, Ordering::Relaxed);
+            let prev = self.remove(&four_tuple);
+            self.entries.push((four_tuple, entry));
+            prev
+        }
 
-pub enum IpFilterConfig {
-    V4(Option<HashSet<Ipv4Addr>>),
-    V6(Option<HashSet<Ipv6Addr>>),
-}
-impl IpFilterConfig {
-    fn build(self) -> IpFilter {
-        match self {
-            IpFilterConfig::V4(filter) => match filter {
-                Some(filter) => IpFilter::V4(filter),
-                None => IpFilter::AlwaysPass,
-            },
-            IpFilterConfig::V6(filter) => match filter {
-                Some(filter) => IpFilter::V6(filter),
-                None => IpFilter::AlwaysPass,
-            },
+        fn get(&self, four_tuple: &FourTuple) -> Option<&ConnEntry> {
+            self.entries
+                .iter()
+                .find(|(key, _)| key == four_tuple)
+                .map(|(_, entry)| entry)
         }
-    }
-}
 
-enum IpFilter {
-    V4(HashSet<Ipv4Addr>),
-    V6(HashSet<Ipv6Addr>),
-    AlwaysPass,
-}
-impl IpFilter {
-    pub fn pass(&self, addr: &IpAddr) -> bool {
-        match self {
-            IpFilter::V4(filter) => match addr {
-                IpAddr::V4(addr) => filter.contains(addr),
-                IpAddr::V6(_) => false,
-            },
-            IpFilter::V6(filter) => match addr {
-                IpAddr::V4(_) => false,
-                IpAddr::V6(addr) => filter.contains(addr),
-            },
-            IpFilter::AlwaysPass => true,
+        fn get_mut(&mut self, four_tuple: &FourTuple) -> Option<&mut ConnEntry> {
+            self.entries
+                .iter_mut()
+                .find(|(key, _)| key == four_tuple)
+                .map(|(_, entry)| entry)
         }
-    }
-}
 
-pub enum AcceptRes {
-    Ok(UdpConn),
-    ConnAlreadyExists,
-    Filtered,
-}
+        fn remove(&mut self, four_tuple: &FourTuple) -> Option<ConnEntry> {
+            let index = self.entries.iter().position(|(key, _)| key == four_tuple)?;
+            Some(self.entries.swap_remove(index).1)
+        }
 
-#[cfg(test)]
-mod tests {
-    use serial_test::serial;
+        fn len(&self) -> usize {
+            self.entries.len()
+        }
 
-    use super::*;
-    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+        fn four_tuples(&self) -> Vec<FourTuple> {
+            self.entries.iter().map(|(key, _)| *key).collect()
+        }
+    }
 
     #[test]
     #[serial]
-    fn test_listen_ipv4_wildcard() {
+    fn test_early_pkt_store() {
         setup();
         let listen_port = 12345;
         let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter = IpFilterConfig::V4(None);
-
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        let inserts = Arc::new(AtomicU64::new(0));
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        let store_inserts = Arc::clone(&inserts);
+        config.early_pkt_store = Some(Box::new(move || {
+            Box::new(VecStore {
+                entries: Vec::new(),
+                inserts: Arc::clone(&store_inserts),
+            })
+        }));
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
 
-        let send_port = 54321;
-        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
-        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let mut conns = Vec::new();
+        for send_port in [54321, 54322] {
+            let four_tuple = FourTuple {
+                local_addr: listen_addr,
+                remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port),
+            };
+            let res = listener
+                .accept_raw(&four_tuple, Cow::from(&b"hello"[..]))
+                .unwrap();
+            let AcceptRes::Ok(conn) = res else {
+                panic!();
+            };
+            conns.push(conn);
+        }
+        assert_eq!(inserts.load(Ordering::Relaxed), 2);
+        assert_eq!(listener.conn_count(), 2);
 
-        let send_buf = b"hello world";
-        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-        assert_eq!(send_len, send_buf.len());
+        let four_tuple = *conns[0].four_tuple();
+        let res = listener
+            .accept_raw(&four_tuple, Cow::from(&b"world"[..]))
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
+        assert_eq!(
+            conns[0].drain_early_pkts(),
+            [b"hello".to_vec(), b"world".to_vec()]
+        );
 
-        let mut recv_buf = [0u8; 1024];
-        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-        assert_eq!(recv_len, send_len);
-        assert_eq!(&recv_buf[..recv_len], send_buf);
-        let AcceptRes::Ok(conn) = res else {
-            panic!();
-        };
-        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+        drop(conns.remove(0));
+        assert!(!listener.has_connection(&four_tuple));
+        assert_eq!(listener.conn_count(), 1);
+        assert_eq!(listener.active_tuples(), [*conns[0].four_tuple()]);
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
     #[serial]
-    fn test_listen_ipv4_specific() {
+    fn test_send_batch() {
         setup();
         let listen_port = 12345;
         let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter =
-            IpFilterConfig::V4(Some([Ipv4Addr::LOCALHOST].iter().cloned().collect()));
+        let listener = UdpListener::bind(listen_port, IpFilterConfig::V4(None), false).unwrap();
 
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
-
-        let send_port = 54321;
-        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
         let send_socket = UdpSocket::bind(send_addr).unwrap();
-
-        let send_buf = b"hello world";
-        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-        assert_eq!(send_len, send_buf.len());
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
 
         let mut recv_buf = [0u8; 1024];
-        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-        assert_eq!(recv_len, send_len);
-        assert_eq!(&recv_buf[..recv_len], send_buf);
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
         let AcceptRes::Ok(conn) = res else {
             panic!();
         };
-        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+
+        let pkts = (0..16u8)
+            .map(|i| vec![i; usize::from(i) + 1])
+            .collect::<Vec<_>>();
+        let bufs = pkts.iter().map(|pkt| &pkt[..]).collect::<Vec<_>>();
+        assert_eq!(conn.send_batch(&bufs).unwrap(), 16);
+        for pkt in &pkts {
+            let (recv_len, from) = send_socket.recv_from(&mut recv_buf).unwrap();
+            assert_eq!(&recv_buf[..recv_len], &pkt[..]);
+            assert_eq!(from, listen_addr);
+        }
+        assert_eq!(conn.send_batch(&[]).unwrap(), 0);
+
+        let stats = conn.traffic_stats();
+        assert_eq!(stats.packets_sent, 16);
+        assert_eq!(stats.bytes_sent, (1..=16).sum::<u64>());
     }
 
     #[test]
     #[serial]
-    fn test_listen_ipv6_wildcard() {
+    fn test_conn_recv_without_pktinfo() {
         setup();
         let listen_port = 12345;
-        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter = IpFilterConfig::V6(None);
-
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        let listeners = [
+            (IpFilterConfig::V4(None), IpAddr::from(Ipv4Addr::LOCALHOST)),
+            (IpFilterConfig::V6(None), Ipv6Addr::LOCALHOST.into()),
+        ];
+        for (local_ip_filter, ip) in listeners {
+            let listen_addr = SocketAddr::new(ip, listen_port);
+            let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
 
-        let send_port = 54321;
-        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
-        let send_socket = UdpSocket::bind(send_addr).unwrap();
+            let send_addr = SocketAddr::new(ip, 54321);
+            let send_socket = UdpSocket::bind(send_addr).unwrap();
+            send_socket.send_to(b"first", listen_addr).unwrap();
 
-        let send_buf = b"hello world";
-        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-        assert_eq!(send_len, send_buf.len());
+            let mut recv_buf = [0u8; 1024];
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            let AcceptRes::Ok(mut conn) = res else {
+                panic!();
+            };
 
-        let mut recv_buf = [0u8; 1024];
-        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-        assert_eq!(recv_len, send_len);
-        assert_eq!(&recv_buf[..recv_len], send_buf);
-        let AcceptRes::Ok(conn) = res else {
-            panic!();
-        };
-        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+            // Connection sockets do not enable pktinfo.
+            send_socket.send_to(b"second", listen_addr).unwrap();
+            let (res, recv_len, meta) = conn.recv_with_meta(&mut recv_buf).unwrap();
+            assert!(matches!(res, RecvRes::Ok));
+            assert_eq!(&recv_buf[..recv_len], b"second");
+            assert_eq!(meta.ifindex, None);
+        }
     }
 
     #[test]
     #[serial]
-    fn test_listen_ipv6_specific() {
+    fn test_drain_early_pkts() {
         setup();
         let listen_port = 12345;
-        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter =
-            IpFilterConfig::V6(Some([Ipv6Addr::LOCALHOST].iter().cloned().collect()));
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.early_pkt_buffer = Some(3);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
 
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        let four_tuple = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
+        };
+        let mut conn = listener.register(four_tuple).unwrap();
+        assert!(conn.drain_early_pkts().is_empty());
 
-        let send_port = 54321;
-        let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
-        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        for pkt in [&b"one"[..], b"two", b"three"] {
+            let res = listener.accept_raw(&four_tuple, Cow::from(pkt)).unwrap();
+            assert!(matches!(
+                res,
+                AcceptRes::ConnAlreadyExists { delivered: true }
+            ));
+        }
+        assert_eq!(
+            conn.drain_early_pkts(),
+            [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+        assert!(conn.drain_early_pkts().is_empty());
+    }
 
-        let send_buf = b"hello world";
-        let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-        assert_eq!(send_len, send_buf.len());
+    #[test]
+    #[serial]
+    fn test_conn_already_exists_full() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.early_pkt_buffer = Some(1);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
 
-        let mut recv_buf = [0u8; 1024];
-        let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-        assert_eq!(recv_len, send_len);
-        assert_eq!(&recv_buf[..recv_len], send_buf);
-        let AcceptRes::Ok(conn) = res else {
-            panic!();
+        let four_tuple = FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321),
         };
-        assert_eq!(conn.four_tuple().local_addr, listen_addr);
+        let mut conn = listener.register(four_tuple).unwrap();
+
+        // The channel holds its buffer plus one slot for the sender.
+        let pkt = Cow::from(&b"hello"[..]);
+        for _ in 0..2 {
+            let res = listener.accept_raw(&four_tuple, pkt.clone()).unwrap();
+            assert!(matches!(
+                res,
+                AcceptRes::ConnAlreadyExists { delivered: true }
+            ));
+        }
+        let res = listener.accept_raw(&four_tuple, pkt.clone()).unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: false }
+        ));
+
+        assert_eq!(conn.drain_early_pkts().len(), 2);
+        let res = listener.accept_raw(&four_tuple, pkt).unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: true }
+        ));
     }
 
     #[test]
     #[serial]
-    fn test_listen_ipv4_specific_many_clients() {
+    fn test_early_pkt_budget() {
         setup();
         let listen_port = 12345;
         let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter =
-            IpFilterConfig::V4(Some([Ipv4Addr::LOCALHOST].iter().cloned().collect()));
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        config.early_pkt_buffer = Some(4);
+        config.early_pkt_budget = Some(10);
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
 
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        let four_tuple = |port| FourTuple {
+            local_addr: listen_addr,
+            remote_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+        };
+        let mut conn_1 = listener.register(four_tuple(54321)).unwrap();
+        let conn_2 = listener.register(four_tuple(54322)).unwrap();
 
-        let send_port_start = 54321;
-        let mut conns = Vec::new();
-        for i in 0..100 {
-            let send_port = send_port_start + i;
-            let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
-            let send_socket = UdpSocket::bind(send_addr).unwrap();
+        // The budget spans every connection.
+        let pkt = Cow::from(&b"hello"[..]);
+        listener
+            .accept_raw(&four_tuple(54321), pkt.clone())
+            .unwrap();
+        listener
+            .accept_raw(&four_tuple(54322), pkt.clone())
+            .unwrap();
+        assert_eq!(listener.early_pkt_queued_bytes(), 10);
+        let res = listener
+            .accept_raw(&four_tuple(54321), pkt.clone())
+            .unwrap();
+        assert!(matches!(
+            res,
+            AcceptRes::ConnAlreadyExists { delivered: false }
+        ));
+        assert_eq!(listener.early_pkt_over_budget_count(), 1);
+        assert_eq!(conn_1.drain_early_pkts(), [b"hello".to_vec()]);
 
-            let send_buf = b"hello world";
-            let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-            assert_eq!(send_len, send_buf.len());
+        // Taken and dropped packets free the budget.
+        assert_eq!(listener.early_pkt_queued_bytes(), 5);
+        drop(conn_2);
+        assert_eq!(listener.early_pkt_queued_bytes(), 0);
+        listener
+            .accept_raw(&four_tuple(54321), pkt.clone())
+            .unwrap();
+        listener.accept_raw(&four_tuple(54321), pkt).unwrap();
+        assert_eq!(listener.early_pkt_over_budget_count(), 1);
+        assert_eq!(conn_1.drain_early_pkts().len(), 2);
+    }
 
-            let mut recv_buf = [0u8; 1024];
-            let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-            assert_eq!(recv_len, send_len);
-            assert_eq!(&recv_buf[..recv_len], send_buf);
-            let AcceptRes::Ok(conn) = res else {
-                panic!();
-            };
-            assert_eq!(conn.four_tuple().local_addr, listen_addr);
+    #[test]
+    #[serial]
+    fn test_allowed_ifindexes() {
+        setup();
+        let listen_port = 12345;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let send_port = 54321;
+        let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
+        let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let lo = if_name_to_index("lo").unwrap();
+        let mut recv_buf = [0u8; 1024];
 
-            conns.push(conn);
+        for (allowed, accepted) in [(lo + 1000, false), (lo, true)] {
+            setup();
+            let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+            config.allowed_ifindexes = Some([allowed].into_iter().collect());
+            let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
+
+            send_socket.send_to(b"hello world", listen_addr).unwrap();
+            let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+            match accepted {
+                true => assert!(matches!(res, AcceptRes::Ok(_))),
+                false => assert!(matches!(res, AcceptRes::Filtered)),
+            }
         }
+
+        assert!(if_name_to_index("no-such-interface").is_err());
     }
 
     #[test]
     #[serial]
-    fn test_listen_ipv6_specific_many_clients() {
+    fn test_demux_by_key() {
         setup();
         let listen_port = 12345;
-        let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
-        let local_ip_filter =
-            IpFilterConfig::V6(Some([Ipv6Addr::LOCALHOST].iter().cloned().collect()));
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let mut config = UdpListenerConfig::new(IpFilterConfig::V4(None), false);
+        // The first byte is the connection ID.
+        config.demux = Some(Demux::by_key(|_, pkt: &[u8]| pkt.first().copied()));
 
-        let listener = UdpListener::bind(listen_port, local_ip_filter, false).unwrap();
+        let listener = UdpListener::bind_with_config(listen_port, config).unwrap();
 
-        let send_port_start = 54321;
-        let mut conns = Vec::new();
-        for i in 0..100 {
-            let send_port = send_port_start + i;
-            let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
-            let send_socket = UdpSocket::bind(send_addr).unwrap();
+        let send_sockets = (0..3)
+            .map(|i| UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321 + i)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut recv_buf = [0u8; 1024];
 
-            let send_buf = b"hello world";
-            let send_len = send_socket.send_to(send_buf, listen_addr).unwrap();
-            assert_eq!(send_len, send_buf.len());
+        send_sockets[0].send_to(&[1, 0], listen_addr).unwrap();
+        let (res, four_tuple_0, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(mut conn) = res else {
+            panic!();
+        };
+        let early_pkts = conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkts.try_recv().unwrap(), [1, 0]);
 
-            let mut recv_buf = [0u8; 1024];
-            let (res, _, recv_len) = listener.accept(&mut recv_buf).unwrap();
-            assert_eq!(recv_len, send_len);
-            assert_eq!(&recv_buf[..recv_len], send_buf);
-            let AcceptRes::Ok(conn) = res else {
-                panic!();
-            };
-            assert_eq!(conn.four_tuple().local_addr, listen_addr);
+        // The peer roams to another address, keeping its connection ID.
+        send_sockets[1].send_to(&[1, 1], listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::RoutedByKey { four_tuple } = res else {
+            panic!();
+        };
+        assert_eq!(four_tuple, four_tuple_0);
+        let early_pkts = conn.recv_early_pkt_mut().recv_early_pkt_mut();
+        assert_eq!(early_pkts.try_recv().unwrap(), [1, 1]);
+        assert_eq!(listener.conn_count(), 1);
 
-            conns.push(conn);
-        }
+        // Another connection ID makes another connection.
+        send_sockets[1].send_to(&[2], listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn_2) = res else {
+            panic!();
+        };
+
+        // The key of a closed connection is free again.
+        drop(conn);
+        send_sockets[2].send_to(&[1], listen_addr).unwrap();
+        let (res, _, _) = listener.accept(&mut recv_buf).unwrap();
+        let AcceptRes::Ok(_conn_1) = res else {
+            panic!();
+        };
+        assert_eq!(listener.conn_count(), 2);
     }
 
     fn setup() {
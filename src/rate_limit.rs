@@ -0,0 +1,62 @@
+use std::{sync::Mutex, time::Instant};
+
+/// Token bucket refilled at `rate` tokens per second and holding at most `burst` tokens.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<TokenBucketState>,
+}
+struct TokenBucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    /// The bucket starts full.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst);
+        Self {
+            rate: f64::from(rate),
+            burst,
+            state: Mutex::new(TokenBucketState {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take one token if available.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.last_refill = now;
+        if state.tokens < 1.0 {
+            return false;
+        }
+        state.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_burst() {
+        let bucket = TokenBucket::new(1, 2);
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let bucket = TokenBucket::new(100, 1);
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(bucket.try_acquire());
+    }
+}
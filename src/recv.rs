@@ -1,145 +1,483 @@
+#[cfg(windows)]
+use std::os::windows::io::BorrowedSocket;
 use std::{
-    io::{self, IoSliceMut},
-    net::{Ipv4Addr, SocketAddr},
-    os::fd::RawFd,
+    fmt, io, mem,
+    net::{IpAddr, SocketAddr},
+    ptr,
+    str::FromStr,
 };
-
-use nix::{
-    cmsg_space, libc,
-    sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, SockaddrStorage},
+#[cfg(unix)]
+use std::{
+    mem::MaybeUninit,
+    net::Ipv4Addr,
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+use crate::UdpAcceptError;
+
+#[cfg(target_os = "linux")]
+mod err_queue;
+#[cfg(target_os = "linux")]
+pub use err_queue::{recv_err_queue, SockError, SockErrorOrigin};
+#[cfg(unix)]
+mod libc_recv;
+#[cfg(all(unix, feature = "nix", any(not(feature = "no-nix"), test)))]
+mod nix_recv;
+#[cfg(windows)]
+mod windows_recv;
+
+/// The socket `recv_from_to*` receive from: a file descriptor on Unix, a `SOCKET` on Windows.
+#[cfg(unix)]
+pub type SocketRef<'a> = BorrowedFd<'a>;
+#[cfg(windows)]
+pub type SocketRef<'a> = BorrowedSocket<'a>;
+
+/// Ordered by the local address, then by the remote address.
+///
+/// The `Display` and `FromStr` forms round-trip, e.g. to persist four-tuples as strings.
+/// With the `serde` feature, four-tuples serialize as that string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FourTuple {
     pub local_addr: SocketAddr,
     pub remote_addr: SocketAddr,
 }
+impl FourTuple {
+    pub fn new(local_addr: SocketAddr, remote_addr: SocketAddr) -> Self {
+        Self {
+            local_addr,
+            remote_addr,
+        }
+    }
+
+    /// The four-tuple as seen from the peer, with the local and remote addresses swapped.
+    pub fn flipped(&self) -> Self {
+        Self::new(self.remote_addr, self.local_addr)
+    }
+
+    /// Whether both addresses are loopback addresses.
+    pub fn is_loopback(&self) -> bool {
+        self.local_addr.ip().is_loopback() && self.remote_addr.ip().is_loopback()
+    }
+
+    /// Whether both addresses are of the same IP family.
+    pub fn same_family(&self) -> bool {
+        self.local_addr.is_ipv4() == self.remote_addr.is_ipv4()
+    }
+}
+
+/// Formats as `<local_addr><-<remote_addr>`, e.g. `127.0.0.1:12345<-127.0.0.1:54321`.
+impl fmt::Display for FourTuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}<-{}", self.local_addr, self.remote_addr)
+    }
+}
+
+impl FromStr for FourTuple {
+    type Err = ParseFourTupleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (local_addr, remote_addr) = s.split_once("<-").ok_or(ParseFourTupleError)?;
+        Ok(Self {
+            local_addr: local_addr.parse().map_err(|_| ParseFourTupleError)?,
+            remote_addr: remote_addr.parse().map_err(|_| ParseFourTupleError)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFourTupleError;
+impl fmt::Display for ParseFourTupleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid four-tuple syntax")
+    }
+}
+impl std::error::Error for ParseFourTupleError {}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for FourTuple {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for FourTuple {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = FourTuple;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a four-tuple like `127.0.0.1:12345<-127.0.0.1:54321`")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecvOptions {
+    /// Fall back to `getsockname` for the local address if `recvmsg` returns no pktinfo.
+    ///
+    /// The local IP is only accurate if the socket is bound to a specific address;
+    /// a socket bound to the wildcard reports the unspecified address.
+    pub local_addr_fallback: bool,
+    /// Local IP to report if `recvmsg` returns no pktinfo, for sockets bound to a specific address
+    /// such as connection sockets.
+    ///
+    /// Takes precedence over `local_addr_fallback` and spares its `getsockname`.
+    pub local_ip: Option<IpAddr>,
+    /// Address family of the socket, to make room for its pktinfo only.
+    ///
+    /// `None` makes room for the pktinfo of either family.
+    pub family: Option<IpFamily>,
+    /// Make room for `IP_TOS`/`IPV6_TCLASS`, enabled on the socket by `IP_RECVTOS`/`IPV6_RECVTCLASS`.
+    pub tos: bool,
+    /// Make room for `SO_TIMESTAMP`, enabled on the socket by the option of the same name.
+    pub timestamp: bool,
+    /// Make room for `IP_ORIGDSTADDR`/`IPV6_ORIGDSTADDR`, enabled on the socket by `IP_RECVORIGDSTADDR`/`IPV6_RECVORIGDSTADDR`.
+    ///
+    /// The local address is then taken from it, port included,
+    /// so packets redirected by TPROXY report their original destination instead of the listen port.
+    #[cfg(target_os = "linux")]
+    pub orig_dst_addr: bool,
+}
+impl RecvOptions {
+    /// Size of the control buffer holding every control message the socket may return.
+    #[cfg(unix)]
+    pub fn cmsg_len(&self) -> usize {
+        fn space<T>() -> usize {
+            // SAFETY: `CMSG_SPACE` only does arithmetic.
+            unsafe { libc::CMSG_SPACE(std::mem::size_of::<T>() as u32) as usize }
+        }
+        let mut len = match self.family {
+            Some(IpFamily::V4) => space::<libc::in_pktinfo>(),
+            // sizeof(in6_pktinfo) > sizeof(in_pktinfo)
+            Some(IpFamily::V6) | None => space::<libc::in6_pktinfo>(),
+        };
+        if self.tos {
+            // `IP_TOS` carries a byte and `IPV6_TCLASS` an int.
+            len += space::<libc::c_int>();
+        }
+        if self.timestamp {
+            len += space::<libc::timeval>();
+        }
+        #[cfg(target_os = "linux")]
+        if self.orig_dst_addr {
+            len += match self.family {
+                Some(IpFamily::V4) => space::<libc::sockaddr_in>(),
+                Some(IpFamily::V6) | None => space::<libc::sockaddr_in6>(),
+            };
+        }
+        len
+    }
+
+    /// Size of the control buffer holding the pktinfo, the only control message parsed on Windows.
+    #[cfg(windows)]
+    pub fn cmsg_len(&self) -> usize {
+        windows_recv::cmsg_len(self.family)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+impl IpFamily {
+    pub fn of(addr: &IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+/// Flags `recvmsg` returned along with a packet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecvFlags {
+    /// `MSG_TRUNC`: the packet did not fit in the buffer and its tail was discarded.
+    pub truncated: bool,
+    /// `MSG_CTRUNC`: the control messages did not fit, so the local address may be missing.
+    pub ctrl_truncated: bool,
+}
+/// What `recvmsg` reported about a packet besides its four-tuple.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecvMeta {
+    pub flags: RecvFlags,
+    /// Index of the interface the packet arrived on, from the pktinfo.
+    pub ifindex: Option<u32>,
+    /// Bytes of control messages `recvmsg` returned, out of the `RecvOptions::cmsg_len` allocated.
+    pub cmsg_len: usize,
+}
+
+impl RecvFlags {
+    #[cfg(unix)]
+    fn from_bits(bits: libc::c_int) -> Self {
+        Self {
+            truncated: bits & libc::MSG_TRUNC != 0,
+            ctrl_truncated: bits & libc::MSG_CTRUNC != 0,
+        }
+    }
+
+    #[cfg(windows)]
+    fn from_bits(bits: u32) -> Self {
+        use windows_sys::Win32::Networking::WinSock::{MSG_CTRUNC, MSG_TRUNC};
+        Self {
+            truncated: bits & MSG_TRUNC != 0,
+            ctrl_truncated: bits & MSG_CTRUNC != 0,
+        }
+    }
+}
 
 /// <https://blog.cloudflare.com/everything-you-ever-wanted-to-know-about-udp-sockets-but-were-afraid-to-ask-part-1/>
 pub fn recv_from_to(
-    fd: RawFd,
+    fd: SocketRef<'_>,
     rx_buf: &mut [u8],
     listen_port: u16,
 ) -> io::Result<(FourTuple, usize)> {
-    // struct iovec { /* Scatter/gather array items */
-    //     void  *iov_base;              /* Starting address */
-    //     size_t iov_len;               /* Number of bytes to transfer */ };
-    let mut iov = [IoSliceMut::new(rx_buf)];
-
-    // struct msghdr {
-    //     void         *msg_name;       /* Optional address */
-    //     socklen_t     msg_namelen;    /* Size of address */
-    //     struct iovec *msg_iov;        /* Scatter/gather array */
-    //     size_t        msg_iovlen;     /* # elements in msg_iov */
-    //     void         *msg_control;    /* Ancillary data, see below */
-    //     size_t        msg_controllen; /* Ancillary data buffer len */
-    //     int           msg_flags;      /* Flags on received message */ };
-
-    // sizeof(in6_pktinfo) > sizeof(in_pktinfo)
-    let mut cmsg_space = cmsg_space!(libc::in6_pktinfo);
-    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg_space), MsgFlags::empty())?;
-
-    // struct cmsghdr {
-    //     size_t cmsg_len;    /* Data byte count, including header
-    //                            (type is socklen_t in POSIX) */
-    //     int    cmsg_level;  /* Originating protocol */
-    //     int    cmsg_type;   /* Protocol-specific type */ /* followed by
-    //     unsigned char cmsg_data[]; */ };
-
-    // Ancillary data should be accessed only by the macros defined in cmsg(3).
-
-    // Get local address.
-    let mut local_addr_ip = None;
-    for cmsg in msg.cmsgs() {
-        match cmsg {
-            ControlMessageOwned::Ipv4PacketInfo(info) => {
-                local_addr_ip = Some(in_addr_to_std(&info.ipi_addr).into());
-            }
-            ControlMessageOwned::Ipv6PacketInfo(info) => {
-                local_addr_ip = Some(info.ipi6_addr.s6_addr.into());
-            }
-            _ => {}
-        }
-    }
-    let local_addr_ip = local_addr_ip.ok_or(io::Error::new(
-        io::ErrorKind::Other,
-        "recvmsg did not return a local address",
-    ))?;
-    let local_addr = SocketAddr::new(local_addr_ip, listen_port);
-
-    // Get remote address.
-    let remote_addr = msg.address.ok_or(io::Error::new(
-        io::ErrorKind::Other,
-        "recvmsg did not return a remote address",
-    ))?;
-    // Convert to SocketAddr.
-    let remote_addr = storage_to_std(remote_addr).ok_or(io::Error::new(
-        io::ErrorKind::Other,
-        "recvmsg returned an invalid remote address",
-    ))?;
-
-    let four_tuple = FourTuple {
-        local_addr,
-        remote_addr,
-    };
+    recv_from_to_with(fd, rx_buf, listen_port, &RecvOptions::default())
+}
 
-    Ok((four_tuple, msg.bytes))
+/// `recv_from_to_with` on any socket exposing its fd.
+#[cfg(unix)]
+pub fn recv_from_to_fd<F: AsFd>(
+    fd: &F,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+) -> io::Result<(FourTuple, usize)> {
+    recv_from_to_with(fd.as_fd(), rx_buf, listen_port, options)
 }
 
-fn storage_to_std(ss: SockaddrStorage) -> Option<SocketAddr> {
-    match ss.as_sockaddr_in() {
-        Some(sin) => {
-            return Some(sockaddr_in_to_std(sin.as_ref()));
-        }
-        None => (),
-    };
-    match ss.as_sockaddr_in6() {
-        Some(sin6) => {
-            return Some(sockaddr_in6_to_std(sin6.as_ref()));
-        }
-        None => (),
+/// `recv_from_to` with `options`.
+pub fn recv_from_to_with(
+    fd: SocketRef<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+) -> io::Result<(FourTuple, usize)> {
+    let (four_tuple, len, _) = recv_from_to_meta(fd, rx_buf, listen_port, options)?;
+    Ok((four_tuple, len))
+}
+
+/// `recv_from_to_with` that also returns the metadata of the packet.
+pub fn recv_from_to_meta(
+    fd: SocketRef<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    recv_from_to_cmsg_buf(fd, rx_buf, listen_port, options, &mut Vec::new())
+}
+
+/// `recv_from_to_meta` that receives the control messages into `cmsg_buf`.
+///
+/// `cmsg_buf` is cleared and only grows if it cannot hold the control messages enabled by `options`,
+/// so reusing it across calls saves an allocation per packet.
+pub fn recv_from_to_cmsg_buf(
+    fd: SocketRef<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+    cmsg_buf: &mut Vec<u8>,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    let cmsg_len = options.cmsg_len();
+    cmsg_buf.clear();
+    cmsg_buf.reserve(cmsg_len);
+    #[cfg(all(unix, feature = "nix", not(feature = "no-nix")))]
+    return nix_recv::recv_from_to(fd, rx_buf, listen_port, options, cmsg_buf);
+    #[cfg(all(unix, any(not(feature = "nix"), feature = "no-nix")))]
+    return libc_recv::recv_from_to(fd, rx_buf, listen_port, options, cmsg_buf);
+    #[cfg(windows)]
+    return windows_recv::recv_from_to(fd, rx_buf, listen_port, options, cmsg_buf);
+}
+
+fn no_local_addr_err(flags: &RecvFlags) -> io::Error {
+    if flags.ctrl_truncated {
+        return UdpAcceptError::CtrlTruncated.into();
+    }
+    UdpAcceptError::MissingPktInfo.into()
+}
+
+/// Index of the network interface named `name`, e.g. to fill `UdpListenerConfig::allowed_ifindexes`.
+#[cfg(unix)]
+pub fn if_name_to_index(name: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name contains NUL"))?;
+    // SAFETY: `name` is a valid C string.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index)
+}
+
+/// Size of the next queued datagram by `ioctl(FIONREAD)`, or `0` if none is queued.
+#[cfg(unix)]
+pub fn peek_len(fd: BorrowedFd<'_>) -> io::Result<usize> {
+    let mut len: libc::c_int = 0;
+    // SAFETY: `FIONREAD` writes a single `c_int` into `len`.
+    let res = unsafe { libc::ioctl(fd.as_raw_fd(), libc::FIONREAD, &mut len) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// `recv_from_to` but into a buffer that does not need to be initialized.
+///
+/// Returns the initialized prefix of `rx_buf`.
+/// Always receives by `libc::recvmsg`, since `nix` needs an initialized buffer.
+#[cfg(unix)]
+pub fn recv_from_to_uninit<'a>(
+    fd: BorrowedFd<'_>,
+    rx_buf: &'a mut [MaybeUninit<u8>],
+    listen_port: u16,
+    options: &RecvOptions,
+) -> io::Result<(FourTuple, &'a mut [u8], RecvMeta)> {
+    let iov = libc::iovec {
+        iov_base: rx_buf.as_mut_ptr().cast(),
+        iov_len: rx_buf.len(),
     };
-    None
+    // SAFETY: `iov` covers `rx_buf`, which `recvmsg` only writes into.
+    let (four_tuple, len, meta) = unsafe {
+        libc_recv::recv_from_to_iov(
+            fd,
+            iov,
+            listen_port,
+            options,
+            &mut Vec::with_capacity(options.cmsg_len()),
+        )
+    }?;
+    // SAFETY: `recvmsg` initialized the first `len` bytes.
+    let pkt = unsafe { std::slice::from_raw_parts_mut(rx_buf.as_mut_ptr().cast::<u8>(), len) };
+    Ok((four_tuple, pkt, meta))
+}
+
+/// Borrow the fd of `socket`, which `socket2` 0.4 does not do by `AsFd`.
+#[cfg(unix)]
+pub(crate) fn socket_fd(socket: &socket2::Socket) -> BorrowedFd<'_> {
+    // SAFETY: the fd stays open for as long as `socket` is borrowed.
+    unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }
 }
 
+#[cfg(unix)]
 fn in_addr_to_std(ia: &libc::in_addr) -> Ipv4Addr {
     // Convert from big-endian to host byte order.
     let s_addr = u32::from_be(ia.s_addr);
     Ipv4Addr::from(s_addr)
 }
 
+#[cfg(unix)]
 fn sockaddr_in_to_std(sa: &libc::sockaddr_in) -> SocketAddr {
     let ip = in_addr_to_std(&sa.sin_addr);
     let port = u16::from_be(sa.sin_port);
     SocketAddr::new(ip.into(), port)
 }
 
+#[cfg(unix)]
 fn sockaddr_in6_to_std(sa: &libc::sockaddr_in6) -> SocketAddr {
     let ip = sa.sin6_addr.s6_addr;
     let port = u16::from_be(sa.sin6_port);
     SocketAddr::new(ip.into(), port)
 }
 
-#[cfg(test)]
-mod tests {
-    use nix::sys::socket::{
-        setsockopt,
-        sockopt::{Ipv4PacketInfo, Ipv6RecvPacketInfo},
-    };
+#[cfg(unix)]
+fn storage_to_std(ss: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match libc::c_int::from(ss.ss_family) {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a `sockaddr_in`.
+            let sin = unsafe { &*ptr::addr_of!(*ss).cast::<libc::sockaddr_in>() };
+            Some(sockaddr_in_to_std(sin))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a `sockaddr_in6`.
+            let sin6 = unsafe { &*ptr::addr_of!(*ss).cast::<libc::sockaddr_in6>() };
+            Some(sockaddr_in6_to_std(sin6))
+        }
+        _ => None,
+    }
+}
 
+/// The control messages `recvmsg` wrote into `cmsg_buf`, as `(cmsg_level, cmsg_type, data)`.
+///
+/// Stops at a header that is cut short or claims less than its own size.
+#[cfg(unix)]
+fn cmsgs(cmsg_buf: &[u8]) -> impl Iterator<Item = (libc::c_int, libc::c_int, &[u8])> {
+    // SAFETY: `CMSG_LEN` only does arithmetic.
+    let hdr_len = unsafe { libc::CMSG_LEN(0) } as usize;
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let rest = cmsg_buf.get(offset..)?;
+        if rest.len() < mem::size_of::<libc::cmsghdr>() {
+            return None;
+        }
+        // SAFETY: `rest` holds a whole header; the buffer need not be aligned for it.
+        let hdr = unsafe { ptr::read_unaligned(rest.as_ptr().cast::<libc::cmsghdr>()) };
+        let data_len = (hdr.cmsg_len as usize).checked_sub(hdr_len)?;
+        let data = rest.get(hdr_len..)?;
+        let data = &data[..data_len.min(data.len())];
+        // SAFETY: `CMSG_SPACE` only does arithmetic.
+        offset += unsafe { libc::CMSG_SPACE(data_len as u32) } as usize;
+        Some((hdr.cmsg_level, hdr.cmsg_type, data))
+    })
+}
+
+/// The `T` at the start of the data of a control message, if the data is long enough.
+fn read_cmsg<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() < mem::size_of::<T>() {
+        return None;
+    }
+    // SAFETY: `data` holds a whole `T`, which the kernel wrote; it need not be aligned.
+    Some(unsafe { ptr::read_unaligned(data.as_ptr().cast::<T>()) })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
     use super::*;
-    use std::{
-        net::{Ipv6Addr, UdpSocket},
-        os::fd::AsRawFd,
-    };
+    use crate::conn::setsockopt_int;
+    use std::net::{Ipv6Addr, UdpSocket};
+
+    /// Receive by the `nix` backend if `nix` and the `nix` feature is on, or else by the `libc` backend.
+    fn recv_by(
+        nix: bool,
+        fd: BorrowedFd<'_>,
+        rx_buf: &mut [u8],
+        listen_port: u16,
+        options: &RecvOptions,
+        cmsg_len: usize,
+    ) -> io::Result<(FourTuple, usize, RecvMeta)> {
+        match nix {
+            #[cfg(feature = "nix")]
+            true => nix_recv::recv_from_to(
+                fd,
+                rx_buf,
+                listen_port,
+                options,
+                &mut Vec::with_capacity(cmsg_len),
+            ),
+            _ => libc_recv::recv_from_to(
+                fd,
+                rx_buf,
+                listen_port,
+                options,
+                &mut Vec::with_capacity(cmsg_len),
+            ),
+        }
+    }
 
     #[test]
     fn test_recv_from_to_ipv4() {
         let listen_port = 12345;
         let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
         let listen_socket = UdpSocket::bind(listen_addr).unwrap();
-        let listen_fd = listen_socket.as_raw_fd();
-        setsockopt(listen_fd, Ipv4PacketInfo, &true).unwrap();
+        let listen_fd = listen_socket.as_fd();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
 
         let send_port = 54321;
         let send_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), send_port);
@@ -162,8 +500,8 @@ mod tests {
         let listen_port = 12345;
         let listen_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_port);
         let listen_socket = UdpSocket::bind(listen_addr).unwrap();
-        let listen_fd = listen_socket.as_raw_fd();
-        setsockopt(listen_fd, Ipv6RecvPacketInfo, &true).unwrap();
+        let listen_fd = listen_socket.as_fd();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1).unwrap();
 
         let send_port = 54321;
         let send_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), send_port);
@@ -180,4 +518,399 @@ mod tests {
         assert_eq!(four_tuple.remote_addr, send_addr);
         assert_eq!(&rx_buf[..recv_len], send_buf);
     }
+
+    #[test]
+    fn test_four_tuple_display_round_trip() {
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new(Ipv4Addr::new(10, 0, 0, 5).into(), 54321),
+        };
+        let s = four_tuple.to_string();
+        assert_eq!(s, "127.0.0.1:12345<-10.0.0.5:54321");
+        assert_eq!(s.parse::<FourTuple>().unwrap(), four_tuple);
+
+        let four_tuple = FourTuple {
+            local_addr: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 12345),
+            remote_addr: SocketAddr::new("fe80::1".parse::<Ipv6Addr>().unwrap().into(), 54321),
+        };
+        let s = four_tuple.to_string();
+        assert_eq!(s, "[::1]:12345<-[fe80::1]:54321");
+        assert_eq!(s.parse::<FourTuple>().unwrap(), four_tuple);
+
+        assert_eq!(
+            four_tuple.flipped().to_string(),
+            "[fe80::1]:54321<-[::1]:12345"
+        );
+
+        assert!("127.0.0.1:12345".parse::<FourTuple>().is_err());
+        assert!("127.0.0.1:12345<-nope".parse::<FourTuple>().is_err());
+    }
+
+    #[test]
+    fn test_four_tuple_ord() {
+        let four_tuple = |local: &str, remote: &str| FourTuple {
+            local_addr: local.parse().unwrap(),
+            remote_addr: remote.parse().unwrap(),
+        };
+        let mut four_tuples = [
+            four_tuple("127.0.0.1:12346", "127.0.0.1:1"),
+            four_tuple("127.0.0.1:12345", "127.0.0.1:54322"),
+            four_tuple("127.0.0.1:12345", "127.0.0.1:54321"),
+        ];
+        four_tuples.sort();
+        assert_eq!(
+            four_tuples,
+            [
+                four_tuple("127.0.0.1:12345", "127.0.0.1:54321"),
+                four_tuple("127.0.0.1:12345", "127.0.0.1:54322"),
+                four_tuple("127.0.0.1:12346", "127.0.0.1:1"),
+            ]
+        );
+
+        // The scope ID survives the round trip.
+        let four_tuple = four_tuple("[fe80::1%2]:12345", "[fe80::2%2]:54321");
+        let s = four_tuple.to_string();
+        assert_eq!(s, "[fe80::1%2]:12345<-[fe80::2%2]:54321");
+        assert_eq!(s.parse::<FourTuple>().unwrap(), four_tuple);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_four_tuple_serde_round_trip() {
+        let check = |local: &str, remote: &str| {
+            let four_tuple = FourTuple {
+                local_addr: local.parse().unwrap(),
+                remote_addr: remote.parse().unwrap(),
+            };
+            let json = serde_json::to_string(&four_tuple).unwrap();
+            assert_eq!(json, format!("\"{local}<-{remote}\""));
+            assert_eq!(
+                serde_json::from_str::<FourTuple>(&json).unwrap(),
+                four_tuple
+            );
+        };
+        check("127.0.0.1:12345", "10.0.0.5:54321");
+        check("[::1]:12345", "[fe80::1]:54321");
+        check("[fe80::1%2]:12345", "[fe80::2%2]:54321");
+
+        assert!(serde_json::from_str::<FourTuple>("\"127.0.0.1:12345\"").is_err());
+        assert!(serde_json::from_str::<FourTuple>("12345").is_err());
+    }
+
+    #[test]
+    fn test_four_tuple_helpers() {
+        let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345);
+        let remote_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 54321);
+        let four_tuple = FourTuple::new(local_addr, remote_addr);
+        assert_eq!(four_tuple.local_addr, local_addr);
+        assert_eq!(four_tuple.remote_addr, remote_addr);
+        assert_eq!(
+            four_tuple.flipped(),
+            FourTuple::new(remote_addr, local_addr)
+        );
+        assert_eq!(four_tuple.flipped().flipped(), four_tuple);
+        assert!(four_tuple.is_loopback());
+        assert!(four_tuple.same_family());
+
+        let remote_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 5).into(), 54321);
+        assert!(!FourTuple::new(local_addr, remote_addr).is_loopback());
+        let remote_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 54321);
+        let four_tuple = FourTuple::new(local_addr, remote_addr);
+        assert!(four_tuple.is_loopback());
+        assert!(!four_tuple.same_family());
+    }
+
+    #[test]
+    fn test_recv_from_to_local_addr_fallback() {
+        let listen_port = 12346;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        // No pktinfo is enabled on the socket.
+        let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+        let listen_fd = listen_socket.as_fd();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let send_addr = send_socket.local_addr().unwrap();
+
+        let mut rx_buf = [0u8; 1024];
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let err = recv_from_to(listen_fd, &mut rx_buf, listen_port)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(matches!(
+            UdpAcceptError::from_io_error(&err),
+            Some(UdpAcceptError::MissingPktInfo)
+        ));
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let options = RecvOptions {
+            local_addr_fallback: true,
+            ..Default::default()
+        };
+        let (four_tuple, recv_len) =
+            recv_from_to_with(listen_fd, &mut rx_buf, listen_port, &options).unwrap();
+        assert_eq!(four_tuple.local_addr, listen_addr);
+        assert_eq!(four_tuple.remote_addr, send_addr);
+        assert_eq!(&rx_buf[..recv_len], b"hello world");
+    }
+
+    #[test]
+    fn test_recv_from_to_local_addr_fallback_wildcard() {
+        // An ephemeral port, so that no other test can bind the wildcard address.
+        let listen_socket =
+            UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+        let wildcard_addr = listen_socket.local_addr().unwrap();
+        let listen_port = wildcard_addr.port();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        // The fallback cannot tell the destination IP of a socket bound to the wildcard.
+        let mut rx_buf = [0u8; 1024];
+        let options = RecvOptions {
+            local_addr_fallback: true,
+            ..Default::default()
+        };
+        let (four_tuple, _) =
+            recv_from_to_fd(&listen_socket, &mut rx_buf, listen_port, &options).unwrap();
+        assert_eq!(four_tuple.local_addr, wildcard_addr);
+    }
+
+    #[test]
+    fn test_recv_from_to_fd() {
+        let listen_port = 12347;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+        setsockopt_int(&listen_socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let send_addr = send_socket.local_addr().unwrap();
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+
+        let mut rx_buf = [0u8; 1024];
+        let (four_tuple, recv_len) = recv_from_to_fd(
+            &listen_socket,
+            &mut rx_buf,
+            listen_port,
+            &RecvOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(four_tuple.local_addr, listen_addr);
+        assert_eq!(four_tuple.remote_addr, send_addr);
+        assert_eq!(&rx_buf[..recv_len], b"hello world");
+    }
+
+    #[test]
+    #[cfg(feature = "nix")]
+    fn test_recv_backends_agree() {
+        for (listen_ip, send_ip) in [
+            (
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+            ),
+            (Ipv6Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()),
+        ] {
+            let listen_port = 12348;
+            let listen_addr = SocketAddr::new(listen_ip, listen_port);
+            let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+            let listen_fd = listen_socket.as_fd();
+            match listen_ip {
+                IpAddr::V4(_) => {
+                    setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap()
+                }
+                IpAddr::V6(_) => {
+                    setsockopt_int(&listen_fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)
+                        .unwrap()
+                }
+            }
+
+            let send_socket = UdpSocket::bind(SocketAddr::new(send_ip, 0)).unwrap();
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+
+            let options = RecvOptions::default();
+            let mut nix_buf = [0u8; 1024];
+            let (nix_four_tuple, nix_len, nix_flags) = nix_recv::recv_from_to(
+                listen_fd,
+                &mut nix_buf,
+                listen_port,
+                &options,
+                &mut Vec::with_capacity(options.cmsg_len()),
+            )
+            .unwrap();
+            let mut libc_buf = [0u8; 1024];
+            let (libc_four_tuple, libc_len, libc_flags) = libc_recv::recv_from_to(
+                listen_fd,
+                &mut libc_buf,
+                listen_port,
+                &options,
+                &mut Vec::with_capacity(options.cmsg_len()),
+            )
+            .unwrap();
+            assert_eq!(nix_four_tuple, libc_four_tuple);
+            assert_eq!(nix_flags, libc_flags);
+            assert_eq!(nix_buf[..nix_len], libc_buf[..libc_len]);
+            assert_eq!(libc_four_tuple.local_addr, listen_addr);
+            assert_eq!(
+                libc_four_tuple.remote_addr,
+                send_socket.local_addr().unwrap()
+            );
+
+            // Without pktinfo, only the fallback recovers the local address.
+            match listen_ip {
+                IpAddr::V4(_) => {
+                    setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 0).unwrap()
+                }
+                IpAddr::V6(_) => {
+                    setsockopt_int(&listen_fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 0)
+                        .unwrap()
+                }
+            }
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+            let options = RecvOptions {
+                local_addr_fallback: true,
+                ..Default::default()
+            };
+            let (four_tuple, _, _) = libc_recv::recv_from_to(
+                listen_fd,
+                &mut libc_buf,
+                listen_port,
+                &options,
+                &mut Vec::with_capacity(options.cmsg_len()),
+            )
+            .unwrap();
+            assert_eq!(four_tuple, libc_four_tuple);
+        }
+    }
+
+    #[test]
+    fn test_recv_flags() {
+        let listen_port = 12349;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+        let listen_fd = listen_socket.as_fd();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let mut rx_buf = [0u8; 4];
+
+        send_socket.send_to(b"hello world", listen_addr).unwrap();
+        let (_, recv_len, RecvMeta { flags, ifindex, .. }) =
+            recv_from_to_meta(listen_fd, &mut rx_buf, listen_port, &RecvOptions::default())
+                .unwrap();
+        assert!(ifindex.is_some());
+        assert_eq!(recv_len, 4);
+        assert!(flags.truncated);
+        assert!(!flags.ctrl_truncated);
+
+        // A control buffer with room for the header only cannot hold the pktinfo.
+        // SAFETY: `CMSG_SPACE` only does arithmetic.
+        let short_cmsg_len = unsafe { libc::CMSG_SPACE(0) } as usize;
+        let options = RecvOptions {
+            local_addr_fallback: true,
+            ..Default::default()
+        };
+        for nix in [true, false] {
+            send_socket.send_to(b"hi", listen_addr).unwrap();
+            let err = recv_by(
+                nix,
+                listen_fd,
+                &mut rx_buf,
+                listen_port,
+                &RecvOptions::default(),
+                short_cmsg_len,
+            )
+            .err()
+            .unwrap();
+            assert!(err.to_string().contains("truncated"));
+
+            send_socket.send_to(b"hi", listen_addr).unwrap();
+            let (four_tuple, _, RecvMeta { flags, .. }) = recv_by(
+                nix,
+                listen_fd,
+                &mut rx_buf,
+                listen_port,
+                &options,
+                short_cmsg_len,
+            )
+            .unwrap();
+            assert!(flags.ctrl_truncated);
+            assert!(!flags.truncated);
+            assert_eq!(four_tuple.local_addr, listen_addr);
+        }
+    }
+
+    #[test]
+    fn test_cmsg_len_fits_enabled_cmsgs() {
+        let listen_port = 12350;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+        let listen_fd = listen_socket.as_fd();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
+        setsockopt_int(&listen_fd, libc::SOL_SOCKET, libc::SO_TIMESTAMP, 1).unwrap();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1).unwrap();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let mut rx_buf = [0u8; 1024];
+
+        // Room for the pktinfo only.
+        let options = RecvOptions {
+            family: Some(IpFamily::V4),
+            ..Default::default()
+        };
+        send_socket.send_to(b"hello", listen_addr).unwrap();
+        let res = recv_from_to_meta(listen_fd, &mut rx_buf, listen_port, &options);
+        assert!(res.is_err_and(|e| e.to_string().contains("truncated")));
+
+        let options = RecvOptions {
+            family: Some(IpFamily::V4),
+            tos: true,
+            timestamp: true,
+            ..Default::default()
+        };
+        for nix in [true, false] {
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+            let (four_tuple, _, meta) = recv_by(
+                nix,
+                listen_fd,
+                &mut rx_buf,
+                listen_port,
+                &options,
+                options.cmsg_len(),
+            )
+            .unwrap();
+            assert!(!meta.flags.ctrl_truncated);
+            assert_eq!(four_tuple.local_addr, listen_addr);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_orig_dst_addr() {
+        let listen_port = 12351;
+        let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_port);
+        let listen_socket = UdpSocket::bind(listen_addr).unwrap();
+        let listen_fd = listen_socket.as_fd();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).unwrap();
+        setsockopt_int(&listen_fd, libc::IPPROTO_IP, libc::IP_RECVORIGDSTADDR, 1).unwrap();
+
+        let send_socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+        let mut rx_buf = [0u8; 1024];
+        let options = RecvOptions {
+            family: Some(IpFamily::V4),
+            tos: true,
+            timestamp: true,
+            orig_dst_addr: true,
+            ..Default::default()
+        };
+        for nix in [true, false] {
+            send_socket.send_to(b"hello", listen_addr).unwrap();
+            // The port comes from the original destination rather than the one passed in.
+            let (four_tuple, _, meta) =
+                recv_by(nix, listen_fd, &mut rx_buf, 0, &options, options.cmsg_len()).unwrap();
+            assert!(!meta.flags.ctrl_truncated);
+            assert_eq!(four_tuple.local_addr, listen_addr);
+        }
+    }
 }
@@ -0,0 +1,148 @@
+//! Errors queued on sockets with `IP_RECVERR`/`IPV6_RECVERR` enabled.
+
+use std::{
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsRawFd, BorrowedFd},
+    ptr,
+};
+
+use super::{cmsgs, read_cmsg, sockaddr_in6_to_std, sockaddr_in_to_std, storage_to_std};
+
+/// A decoded `sock_extended_err`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SockError {
+    /// `ee_errno`, e.g. `ECONNREFUSED` for an ICMP port unreachable.
+    pub errno: i32,
+    pub origin: SockErrorOrigin,
+    /// ICMP type, if `origin` is `Icmp` or `Icmp6`.
+    pub icmp_type: u8,
+    /// ICMP code, if `origin` is `Icmp` or `Icmp6`.
+    pub icmp_code: u8,
+    /// Destination of the packet that caused the error.
+    pub destination: Option<SocketAddr>,
+    /// Node that reported the error, e.g. the router sending the ICMP message.
+    pub offender: Option<IpAddr>,
+    /// Number of bytes of the offending packet copied into the buffer.
+    pub len: usize,
+    /// Path MTU reported along with `EMSGSIZE`, either by the local stack or by an ICMP "fragmentation needed".
+    pub mtu: Option<u32>,
+}
+impl SockError {
+    pub fn as_io_error(&self) -> io::Error {
+        io::Error::from_raw_os_error(self.errno)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SockErrorOrigin {
+    None,
+    /// Raised by the local stack.
+    Local,
+    Icmp,
+    Icmp6,
+    Other(u8),
+}
+impl From<u8> for SockErrorOrigin {
+    fn from(origin: u8) -> Self {
+        match origin {
+            libc::SO_EE_ORIGIN_NONE => Self::None,
+            libc::SO_EE_ORIGIN_LOCAL => Self::Local,
+            libc::SO_EE_ORIGIN_ICMP => Self::Icmp,
+            libc::SO_EE_ORIGIN_ICMP6 => Self::Icmp6,
+            origin => Self::Other(origin),
+        }
+    }
+}
+
+/// Pop the oldest error from the error queue of `fd` without blocking.
+///
+/// The payload of the offending packet is copied into `buf`.
+///
+/// Returns `None` if the queue is empty.
+pub fn recv_err_queue(fd: BorrowedFd<'_>, buf: &mut [u8]) -> io::Result<Option<SockError>> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: all-zero is a valid `sockaddr_storage`.
+    let mut destination: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // The extended error is followed by the address of the offender.
+    // SAFETY: `CMSG_SPACE` only does arithmetic.
+    let cmsg_len = unsafe {
+        libc::CMSG_SPACE(
+            (mem::size_of::<libc::sock_extended_err>() + mem::size_of::<libc::sockaddr_in6>())
+                as u32,
+        )
+    } as usize;
+    let mut cmsg_buf = Vec::<u8>::with_capacity(cmsg_len);
+
+    // SAFETY: all-zero is a valid `msghdr`.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = ptr::addr_of_mut!(destination).cast();
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = cmsg_buf.capacity() as _;
+
+    // SAFETY: every pointer in `msg` is valid for the length next to it.
+    let len = unsafe {
+        libc::recvmsg(
+            fd.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    let len = len as usize;
+    // SAFETY: `recvmsg` wrote `msg_controllen` bytes of control messages into `cmsg_buf`.
+    unsafe { cmsg_buf.set_len((msg.msg_controllen as usize).min(cmsg_buf.capacity())) };
+    let destination = match msg.msg_namelen {
+        0 => None,
+        _ => storage_to_std(&destination),
+    };
+    for (level, ty, data) in cmsgs(&cmsg_buf) {
+        let offender_data = data
+            .get(mem::size_of::<libc::sock_extended_err>()..)
+            .unwrap_or_default();
+        let offender = match (level, ty) {
+            (libc::IPPROTO_IP, libc::IP_RECVERR) => read_cmsg::<libc::sockaddr_in>(offender_data)
+                .filter(|sa| libc::c_int::from(sa.sin_family) == libc::AF_INET)
+                .map(|sa| sockaddr_in_to_std(&sa).ip()),
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) => {
+                read_cmsg::<libc::sockaddr_in6>(offender_data)
+                    .filter(|sa| libc::c_int::from(sa.sin6_family) == libc::AF_INET6)
+                    .map(|sa| sockaddr_in6_to_std(&sa).ip())
+            }
+            _ => continue,
+        };
+        let Some(err) = read_cmsg::<libc::sock_extended_err>(data) else {
+            continue;
+        };
+        // An offender of the unspecified address is not reported.
+        let offender = offender.filter(|ip| !ip.is_unspecified());
+        let errno = err.ee_errno as i32;
+        // `ee_info` carries the MTU for `EMSGSIZE` only.
+        let mtu = (errno == libc::EMSGSIZE && err.ee_info != 0).then_some(err.ee_info);
+        return Ok(Some(SockError {
+            errno,
+            origin: err.ee_origin.into(),
+            icmp_type: err.ee_type,
+            icmp_code: err.ee_code,
+            destination,
+            offender,
+            len,
+            mtu,
+        }));
+    }
+    Err(io::Error::other(
+        "recvmsg did not return an extended error from the error queue",
+    ))
+}
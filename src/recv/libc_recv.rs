@@ -0,0 +1,147 @@
+//! `recvmsg` by `libc` with the control messages parsed by hand.
+
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    os::fd::{AsRawFd, BorrowedFd},
+    ptr,
+};
+
+use super::{
+    cmsgs, in_addr_to_std, no_local_addr_err, read_cmsg, sockaddr_in6_to_std, sockaddr_in_to_std,
+    storage_to_std, FourTuple, RecvFlags, RecvMeta, RecvOptions,
+};
+use crate::UdpAcceptError;
+
+#[cfg(any(not(feature = "nix"), feature = "no-nix", test))]
+pub fn recv_from_to(
+    fd: BorrowedFd<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+    cmsg_buf: &mut Vec<u8>,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    let iov = libc::iovec {
+        iov_base: rx_buf.as_mut_ptr().cast(),
+        iov_len: rx_buf.len(),
+    };
+    // SAFETY: `iov` covers `rx_buf`.
+    unsafe { recv_from_to_iov(fd, iov, listen_port, options, cmsg_buf) }
+}
+
+/// `recv_from_to` into the memory `iov` points at, which does not need to be initialized.
+///
+/// # Safety
+///
+/// `iov` must be valid for writes of `iov_len` bytes.
+pub unsafe fn recv_from_to_iov(
+    fd: BorrowedFd<'_>,
+    mut iov: libc::iovec,
+    listen_port: u16,
+    options: &RecvOptions,
+    cmsg_buf: &mut Vec<u8>,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    // SAFETY: all-zero is a valid `sockaddr_storage`.
+    let mut remote_addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    // SAFETY: all-zero is a valid `msghdr`.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = ptr::addr_of_mut!(remote_addr).cast();
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    // Like the `nix` backend, the capacity is the room for control messages.
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = cmsg_buf.capacity() as _;
+
+    // SAFETY: every pointer in `msg` is valid for the length next to it.
+    let len = unsafe { libc::recvmsg(fd.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = RecvFlags::from_bits(msg.msg_flags);
+    let cmsg_len = (msg.msg_controllen as usize).min(cmsg_buf.capacity());
+    // SAFETY: `recvmsg` wrote `msg_controllen` bytes of control messages into `cmsg_buf`.
+    unsafe { cmsg_buf.set_len(cmsg_len) };
+
+    // Get local address.
+    let mut local_addr_ip = None;
+    let mut ifindex = None;
+    let mut orig_dst_addr = None;
+    for (level, ty, data) in cmsgs(cmsg_buf) {
+        match (level, ty) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                if let Some(info) = read_cmsg::<libc::in_pktinfo>(data) {
+                    local_addr_ip = Some(IpAddr::V4(in_addr_to_std(&info.ipi_addr)));
+                    ifindex = Some(info.ipi_ifindex as u32);
+                }
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                if let Some(info) = read_cmsg::<libc::in6_pktinfo>(data) {
+                    local_addr_ip = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                    ifindex = Some(info.ipi6_ifindex);
+                }
+            }
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IP, libc::IP_ORIGDSTADDR) => {
+                if let Some(addr) = read_cmsg::<libc::sockaddr_in>(data) {
+                    orig_dst_addr = Some(sockaddr_in_to_std(&addr));
+                }
+            }
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                if let Some(addr) = read_cmsg::<libc::sockaddr_in6>(data) {
+                    orig_dst_addr = Some(sockaddr_in6_to_std(&addr));
+                }
+            }
+            _ => {}
+        }
+    }
+    if flags.ctrl_truncated {
+        // The kernel cuts the pktinfo short, which leaves its address unreliable.
+        local_addr_ip = None;
+        orig_dst_addr = None;
+    }
+    local_addr_ip = local_addr_ip.or(options.local_ip);
+    if local_addr_ip.is_none() && options.local_addr_fallback {
+        local_addr_ip = getsockname(fd)?.map(|addr| addr.ip());
+    }
+    let local_addr_ip = local_addr_ip.ok_or_else(|| no_local_addr_err(&flags))?;
+    let local_addr = orig_dst_addr.unwrap_or(SocketAddr::new(local_addr_ip, listen_port));
+
+    // Get remote address.
+    if msg.msg_namelen == 0 {
+        return Err(UdpAcceptError::MissingRemoteAddr.into());
+    }
+    let remote_addr = storage_to_std(&remote_addr).ok_or(UdpAcceptError::InvalidRemoteAddr {
+        family: remote_addr.ss_family.into(),
+    })?;
+
+    let four_tuple = FourTuple {
+        local_addr,
+        remote_addr,
+    };
+
+    Ok((
+        four_tuple,
+        len as usize,
+        RecvMeta {
+            flags,
+            ifindex,
+            cmsg_len,
+        },
+    ))
+}
+
+fn getsockname(fd: BorrowedFd<'_>) -> io::Result<Option<SocketAddr>> {
+    // SAFETY: all-zero is a valid `sockaddr_storage`.
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `addr` is valid for `len` bytes.
+    let res =
+        unsafe { libc::getsockname(fd.as_raw_fd(), ptr::addr_of_mut!(addr).cast(), &mut len) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(storage_to_std(&addr))
+}
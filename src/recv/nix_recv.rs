@@ -0,0 +1,161 @@
+//! `recvmsg` by `nix`.
+
+use std::{
+    io::{self, IoSliceMut},
+    mem,
+    net::SocketAddr,
+    os::fd::{AsRawFd, BorrowedFd},
+    ptr,
+};
+
+use nix::sys::socket::{
+    getsockname, recvmsg, ControlMessageOwned, MsgFlags, SockaddrLike, SockaddrStorage,
+};
+
+use super::{
+    in_addr_to_std, no_local_addr_err, sockaddr_in6_to_std, sockaddr_in_to_std, FourTuple,
+    RecvFlags, RecvMeta, RecvOptions,
+};
+use crate::UdpAcceptError;
+
+pub fn recv_from_to(
+    fd: BorrowedFd<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+    cmsg_buf: &mut Vec<u8>,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    // struct iovec { /* Scatter/gather array items */
+    //     void  *iov_base;              /* Starting address */
+    //     size_t iov_len;               /* Number of bytes to transfer */ };
+    let mut iov = [IoSliceMut::new(rx_buf)];
+
+    // struct msghdr {
+    //     void         *msg_name;       /* Optional address */
+    //     socklen_t     msg_namelen;    /* Size of address */
+    //     struct iovec *msg_iov;        /* Scatter/gather array */
+    //     size_t        msg_iovlen;     /* # elements in msg_iov */
+    //     void         *msg_control;    /* Ancillary data, see below */
+    //     size_t        msg_controllen; /* Ancillary data buffer len */
+    //     int           msg_flags;      /* Flags on received message */ };
+
+    // `recvmsg` uses the capacity as `msg_controllen`.
+    let msg = recvmsg::<SockaddrStorage>(
+        fd.as_raw_fd(),
+        &mut iov,
+        Some(&mut *cmsg_buf),
+        MsgFlags::empty(),
+    )?;
+    let flags = RecvFlags::from_bits(msg.flags.bits());
+    let cmsg_count = msg.cmsgs().count();
+
+    // struct cmsghdr {
+    //     size_t cmsg_len;    /* Data byte count, including header
+    //                            (type is socklen_t in POSIX) */
+    //     int    cmsg_level;  /* Originating protocol */
+    //     int    cmsg_type;   /* Protocol-specific type */ /* followed by
+    //     unsigned char cmsg_data[]; */ };
+
+    // Ancillary data should be accessed only by the macros defined in cmsg(3).
+
+    // Get local address.
+    let mut local_addr_ip = None;
+    let mut ifindex = None;
+    let mut orig_dst_addr = None;
+    let (len, remote_addr) = (msg.bytes, msg.address);
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(info) => {
+                local_addr_ip = Some(in_addr_to_std(&info.ipi_addr).into());
+                ifindex = Some(info.ipi_ifindex as u32);
+            }
+            ControlMessageOwned::Ipv6PacketInfo(info) => {
+                local_addr_ip = Some(info.ipi6_addr.s6_addr.into());
+                ifindex = Some(info.ipi6_ifindex);
+            }
+            #[cfg(target_os = "linux")]
+            ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+                orig_dst_addr = Some(sockaddr_in_to_std(&addr));
+            }
+            #[cfg(target_os = "linux")]
+            ControlMessageOwned::Ipv6OrigDstAddr(addr) => {
+                orig_dst_addr = Some(sockaddr_in6_to_std(&addr));
+            }
+            _ => {}
+        }
+    }
+    // `RecvMsg` hides `msg_controllen`, so add up the control messages it parsed once it no longer borrows `cmsg_buf`.
+    // SAFETY: `recvmsg` wrote `cmsg_count` control messages into `cmsg_buf`.
+    let cmsg_len = unsafe { cmsg_bytes(cmsg_buf, cmsg_count) };
+    if flags.ctrl_truncated {
+        // The kernel cuts the pktinfo short, which leaves its address unreliable.
+        local_addr_ip = None;
+        orig_dst_addr = None;
+    }
+    local_addr_ip = local_addr_ip.or(options.local_ip);
+    if local_addr_ip.is_none() && options.local_addr_fallback {
+        let local_addr = getsockname::<SockaddrStorage>(fd.as_raw_fd())?;
+        local_addr_ip = storage_to_std(local_addr).map(|addr| addr.ip());
+    }
+    let local_addr_ip = local_addr_ip.ok_or_else(|| no_local_addr_err(&flags))?;
+    let local_addr = orig_dst_addr.unwrap_or(SocketAddr::new(local_addr_ip, listen_port));
+
+    // Get remote address.
+    let remote_addr = remote_addr.ok_or(UdpAcceptError::MissingRemoteAddr)?;
+    // Convert to SocketAddr.
+    let remote_addr = storage_to_std(remote_addr).ok_or(UdpAcceptError::InvalidRemoteAddr {
+        family: remote_addr
+            .family()
+            .map_or(libc::AF_UNSPEC, |family| family as i32),
+    })?;
+
+    let four_tuple = FourTuple {
+        local_addr,
+        remote_addr,
+    };
+
+    Ok((
+        four_tuple,
+        len,
+        RecvMeta {
+            flags,
+            ifindex,
+            cmsg_len,
+        },
+    ))
+}
+
+fn storage_to_std(ss: SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(sin) = ss.as_sockaddr_in() {
+        return Some(sockaddr_in_to_std(sin.as_ref()));
+    }
+    if let Some(sin6) = ss.as_sockaddr_in6() {
+        return Some(sockaddr_in6_to_std(sin6.as_ref()));
+    }
+    None
+}
+
+/// Bytes the first `count` control messages in the spare capacity of `cmsg_buf` take up,
+/// as `recvmsg` reports in `msg_controllen`.
+///
+/// Stops at a header that is cut short or claims less than its own size.
+///
+/// # Safety
+///
+/// `recvmsg` must have written `count` control messages at the start of the capacity of `cmsg_buf`.
+unsafe fn cmsg_bytes(cmsg_buf: &Vec<u8>, count: usize) -> usize {
+    let capacity = cmsg_buf.capacity();
+    let hdr_len = libc::CMSG_LEN(0) as usize;
+    let mut len = 0;
+    for _ in 0..count {
+        if len + mem::size_of::<libc::cmsghdr>() > capacity {
+            break;
+        }
+        let hdr = ptr::read_unaligned(cmsg_buf.as_ptr().add(len).cast::<libc::cmsghdr>());
+        let Some(data_len) = (hdr.cmsg_len as usize).checked_sub(hdr_len) else {
+            break;
+        };
+        len += libc::CMSG_SPACE(data_len as _) as usize;
+    }
+    len.min(capacity)
+}
@@ -0,0 +1,284 @@
+//! `WSARecvMsg` by `windows-sys` with the control messages parsed by hand.
+//!
+//! Only `IP_PKTINFO`/`IPV6_PKTINFO` are parsed, which the socket must have enabled;
+//! `RecvOptions::tos` and `RecvOptions::timestamp` are ignored.
+
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::windows::io::{AsRawSocket, BorrowedSocket},
+    ptr,
+    sync::OnceLock,
+};
+
+use windows_sys::Win32::Networking::WinSock::{
+    self, CMSGHDR, IN6_PKTINFO, IN_PKTINFO, IPPROTO_IP, IPPROTO_IPV6, IPV6_PKTINFO, IP_PKTINFO,
+    LPFN_WSARECVMSG, SIO_GET_EXTENSION_FUNCTION_POINTER, SOCKET, SOCKET_ERROR, WSABUF, WSAEMSGSIZE,
+    WSAID_WSARECVMSG, WSAMSG,
+};
+
+use super::{no_local_addr_err, read_cmsg, FourTuple, IpFamily, RecvFlags, RecvMeta, RecvOptions};
+use crate::UdpAcceptError;
+
+/// `TYPE_ALIGNMENT(WSACMSGHDR)`, which is also `MAX_NATURAL_ALIGNMENT` on every Windows target.
+const CMSG_ALIGN: usize = mem::align_of::<CMSGHDR>();
+
+/// Offset of the data of a control message, `WSA_CMSG_DATA`.
+const CMSG_DATA_OFFSET: usize = cmsg_align(mem::size_of::<CMSGHDR>());
+
+const fn cmsg_align(len: usize) -> usize {
+    (len + CMSG_ALIGN - 1) & !(CMSG_ALIGN - 1)
+}
+
+/// `WSA_CMSG_SPACE`.
+const fn cmsg_space(len: usize) -> usize {
+    cmsg_align(mem::size_of::<CMSGHDR>() + cmsg_align(len))
+}
+
+/// Size of the control buffer holding the pktinfo of `family`; see `RecvOptions::cmsg_len`.
+pub fn cmsg_len(family: Option<IpFamily>) -> usize {
+    match family {
+        Some(IpFamily::V4) => cmsg_space(mem::size_of::<IN_PKTINFO>()),
+        // sizeof(IN6_PKTINFO) > sizeof(IN_PKTINFO)
+        Some(IpFamily::V6) | None => cmsg_space(mem::size_of::<IN6_PKTINFO>()),
+    }
+}
+
+pub fn recv_from_to(
+    socket: BorrowedSocket<'_>,
+    rx_buf: &mut [u8],
+    listen_port: u16,
+    options: &RecvOptions,
+    cmsg_buf: &mut Vec<u8>,
+) -> io::Result<(FourTuple, usize, RecvMeta)> {
+    let socket = socket.as_raw_socket() as SOCKET;
+    let recv_msg = wsa_recv_msg(socket)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the Winsock provider has no WSARecvMsg",
+        )
+    })?;
+    let mut buf = WSABUF {
+        len: u32::try_from(rx_buf.len()).unwrap_or(u32::MAX),
+        buf: rx_buf.as_mut_ptr().cast(),
+    };
+
+    // SAFETY: `WSARecvMsg` writes the remote address into the storage and its length into `namelen`.
+    let ((len, flags, cmsg_len), remote_addr) = unsafe {
+        socket2::SockAddr::init(|storage, storage_len| {
+            let mut msg = WSAMSG {
+                name: storage.cast(),
+                namelen: *storage_len,
+                lpBuffers: &mut buf,
+                dwBufferCount: 1,
+                // Like the Unix backends, the capacity is the room for control messages.
+                Control: WSABUF {
+                    len: u32::try_from(cmsg_buf.capacity()).unwrap_or(u32::MAX),
+                    buf: cmsg_buf.as_mut_ptr().cast(),
+                },
+                dwFlags: 0,
+            };
+            let mut len: u32 = 0;
+            // SAFETY: every pointer in `msg` is valid for the length next to it.
+            let res = recv_msg(socket, &mut msg, &mut len, ptr::null_mut(), None);
+            let mut flags = RecvFlags::from_bits(msg.dwFlags);
+            if res == SOCKET_ERROR {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(WSAEMSGSIZE) {
+                    return Err(err);
+                }
+                // The tail of a datagram longer than `rx_buf` is discarded, as by `MSG_TRUNC`.
+                flags.truncated = true;
+            }
+            *storage_len = msg.namelen;
+            Ok((len as usize, flags, msg.Control.len as usize))
+        })
+    }?;
+    let cmsg_len = cmsg_len.min(cmsg_buf.capacity());
+    // SAFETY: `WSARecvMsg` wrote `Control.len` bytes of control messages into `cmsg_buf`.
+    unsafe { cmsg_buf.set_len(cmsg_len) };
+
+    // Get local address.
+    let mut local_addr_ip = None;
+    let mut ifindex = None;
+    for (level, ty, data) in cmsgs(cmsg_buf) {
+        match (level, ty) {
+            (IPPROTO_IP, IP_PKTINFO) => {
+                if let Some(info) = read_cmsg::<IN_PKTINFO>(data) {
+                    // SAFETY: every variant of `S_un` is the same four bytes in network order.
+                    let addr = unsafe { info.ipi_addr.S_un.S_addr };
+                    local_addr_ip = Some(IpAddr::V4(Ipv4Addr::from(addr.to_ne_bytes())));
+                    ifindex = Some(info.ipi_ifindex);
+                }
+            }
+            (IPPROTO_IPV6, IPV6_PKTINFO) => {
+                if let Some(info) = read_cmsg::<IN6_PKTINFO>(data) {
+                    // SAFETY: every variant of `u` is the same sixteen bytes.
+                    let addr = unsafe { info.ipi6_addr.u.Byte };
+                    local_addr_ip = Some(IpAddr::V6(Ipv6Addr::from(addr)));
+                    ifindex = Some(info.ipi6_ifindex);
+                }
+            }
+            _ => {}
+        }
+    }
+    if flags.ctrl_truncated {
+        // A pktinfo cut short leaves its address unreliable.
+        local_addr_ip = None;
+    }
+    local_addr_ip = local_addr_ip.or(options.local_ip);
+    if local_addr_ip.is_none() && options.local_addr_fallback {
+        local_addr_ip = getsockname(socket)?.map(|addr| addr.ip());
+    }
+    let local_addr_ip = local_addr_ip.ok_or_else(|| no_local_addr_err(&flags))?;
+    let local_addr = SocketAddr::new(local_addr_ip, listen_port);
+
+    // Get remote address.
+    if remote_addr.len() == 0 {
+        return Err(UdpAcceptError::MissingRemoteAddr.into());
+    }
+    let remote_addr = remote_addr
+        .as_socket()
+        .ok_or(UdpAcceptError::InvalidRemoteAddr {
+            family: remote_addr.family().into(),
+        })?;
+
+    let four_tuple = FourTuple {
+        local_addr,
+        remote_addr,
+    };
+
+    Ok((
+        four_tuple,
+        len,
+        RecvMeta {
+            flags,
+            ifindex,
+            cmsg_len,
+        },
+    ))
+}
+
+/// `WSARecvMsg`, an extension function looked up once by `WSAIoctl`.
+///
+/// `None` if the Winsock provider does not implement it.
+fn wsa_recv_msg(socket: SOCKET) -> io::Result<LPFN_WSARECVMSG> {
+    static WSA_RECV_MSG: OnceLock<LPFN_WSARECVMSG> = OnceLock::new();
+    if let Some(recv_msg) = WSA_RECV_MSG.get() {
+        return Ok(*recv_msg);
+    }
+    let mut guid = WSAID_WSARECVMSG;
+    let mut recv_msg: LPFN_WSARECVMSG = None;
+    let mut len: u32 = 0;
+    // SAFETY: the in and out buffers are valid for the lengths next to them.
+    let res = unsafe {
+        WinSock::WSAIoctl(
+            socket,
+            SIO_GET_EXTENSION_FUNCTION_POINTER,
+            ptr::addr_of_mut!(guid).cast(),
+            mem::size_of_val(&guid) as u32,
+            ptr::addr_of_mut!(recv_msg).cast(),
+            mem::size_of_val(&recv_msg) as u32,
+            &mut len,
+            ptr::null_mut(),
+            None,
+        )
+    };
+    if res == SOCKET_ERROR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(*WSA_RECV_MSG.get_or_init(|| recv_msg))
+}
+
+/// The control messages `WSARecvMsg` wrote into `cmsg_buf`, as `(cmsg_level, cmsg_type, data)`.
+///
+/// Stops at a header that is cut short or claims less than its own size.
+fn cmsgs(cmsg_buf: &[u8]) -> impl Iterator<Item = (i32, i32, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let rest = cmsg_buf.get(offset..)?;
+        if rest.len() < mem::size_of::<CMSGHDR>() {
+            return None;
+        }
+        // SAFETY: `rest` holds a whole header; the buffer need not be aligned for it.
+        let hdr = unsafe { ptr::read_unaligned(rest.as_ptr().cast::<CMSGHDR>()) };
+        let data_len = hdr.cmsg_len.checked_sub(CMSG_DATA_OFFSET)?;
+        let data = rest.get(CMSG_DATA_OFFSET..)?;
+        let data = &data[..data_len.min(data.len())];
+        offset += cmsg_align(hdr.cmsg_len);
+        Some((hdr.cmsg_level, hdr.cmsg_type, data))
+    })
+}
+
+fn getsockname(socket: SOCKET) -> io::Result<Option<SocketAddr>> {
+    // SAFETY: `getsockname` writes the local address into the storage and its length into `len`.
+    let (_, addr) = unsafe {
+        socket2::SockAddr::init(|storage, len| {
+            if WinSock::getsockname(socket, storage.cast(), len) == SOCKET_ERROR {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }?;
+    Ok(addr.as_socket())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::UdpSocket,
+        os::windows::io::{AsRawSocket, AsSocket},
+    };
+
+    use super::*;
+    use crate::recv;
+
+    fn set_pktinfo(socket: &UdpSocket, level: i32, name: i32) {
+        let on: i32 = 1;
+        // SAFETY: `on` is valid for the length passed.
+        let res = unsafe {
+            WinSock::setsockopt(
+                socket.as_raw_socket() as SOCKET,
+                level,
+                name,
+                ptr::addr_of!(on).cast(),
+                mem::size_of_val(&on) as i32,
+            )
+        };
+        assert_ne!(res, SOCKET_ERROR, "{}", io::Error::last_os_error());
+    }
+
+    /// Through `recv::recv_from_to`, to check that it dispatches to this backend.
+    fn check(listen_ip: IpAddr, level: i32, name: i32) {
+        let listen_socket = UdpSocket::bind(SocketAddr::new(listen_ip, 0)).unwrap();
+        let listen_addr = listen_socket.local_addr().unwrap();
+        set_pktinfo(&listen_socket, level, name);
+        let send_socket = UdpSocket::bind(SocketAddr::new(listen_ip, 0)).unwrap();
+
+        let send_buf = b"hello world";
+        send_socket.send_to(send_buf, listen_addr).unwrap();
+
+        let mut rx_buf = [0u8; 1024];
+        let (four_tuple, recv_len) =
+            recv::recv_from_to(listen_socket.as_socket(), &mut rx_buf, listen_addr.port()).unwrap();
+        assert_eq!(four_tuple.local_addr, listen_addr);
+        assert_eq!(four_tuple.remote_addr, send_socket.local_addr().unwrap());
+        assert_eq!(&rx_buf[..recv_len], send_buf);
+    }
+
+    #[test]
+    fn test_recv_from_to_ipv4() {
+        check(Ipv4Addr::LOCALHOST.into(), IPPROTO_IP, IP_PKTINFO);
+    }
+
+    #[test]
+    fn test_recv_from_to_ipv6() {
+        check(Ipv6Addr::LOCALHOST.into(), IPPROTO_IPV6, IPV6_PKTINFO);
+    }
+
+    #[test]
+    fn test_cmsg_len() {
+        assert!(cmsg_len(Some(IpFamily::V4)) >= mem::size_of::<IN_PKTINFO>());
+        assert_eq!(cmsg_len(None), cmsg_len(Some(IpFamily::V6)));
+    }
+}
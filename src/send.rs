@@ -0,0 +1,149 @@
+use std::{
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, BorrowedFd},
+    ptr,
+};
+
+use crate::recv::FourTuple;
+
+/// Send `buf` to `four_tuple.remote_addr` with `four_tuple.local_addr`'s IP as the source address.
+///
+/// The source is pinned by an `IP_PKTINFO`/`IPV6_PKTINFO` control message,
+/// so it holds even if `fd` is bound to the wildcard address.
+///
+/// A multicast local IP cannot be a source, so the kernel picks a unicast one instead, e.g. to reply to a multicast sender.
+pub fn send_from_to(fd: BorrowedFd<'_>, buf: &[u8], four_tuple: &FourTuple) -> io::Result<usize> {
+    let remote_addr = socket2::SockAddr::from(four_tuple.remote_addr);
+
+    let local_ip = match four_tuple.local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_multicast() => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(ip) if ip.is_multicast() => Ipv6Addr::UNSPECIFIED.into(),
+        ip => ip,
+    };
+    match local_ip {
+        IpAddr::V4(local_ip) => {
+            let info = libc::in_pktinfo {
+                ipi_ifindex: 0,
+                ipi_spec_dst: std_to_in_addr(&local_ip),
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            send_with_cmsg(
+                fd,
+                buf,
+                &remote_addr,
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                info,
+            )
+        }
+        IpAddr::V6(local_ip) => {
+            let info = libc::in6_pktinfo {
+                ipi6_addr: std_to_in6_addr(&local_ip),
+                ipi6_ifindex: 0,
+            };
+            send_with_cmsg(
+                fd,
+                buf,
+                &remote_addr,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                info,
+            )
+        }
+    }
+}
+
+/// Room for the control message of `send_with_cmsg`, aligned for `cmsghdr`.
+const CMSG_BUF_WORDS: usize = 8;
+
+/// `sendmsg` of `buf` to `remote_addr` along with a single control message carrying `data`.
+fn send_with_cmsg<T: Copy>(
+    fd: BorrowedFd<'_>,
+    buf: &[u8],
+    remote_addr: &socket2::SockAddr,
+    level: libc::c_int,
+    ty: libc::c_int,
+    data: T,
+) -> io::Result<usize> {
+    let mut cmsg_buf = [0u64; CMSG_BUF_WORDS];
+    // SAFETY: `CMSG_SPACE` only does arithmetic.
+    let cmsg_space = unsafe { libc::CMSG_SPACE(mem::size_of::<T>() as u32) } as usize;
+    assert!(cmsg_space <= mem::size_of_val(&cmsg_buf));
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr().cast_mut().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: all-zero is a valid `msghdr`.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    // `sendmsg` only reads from the address and the buffer.
+    msg.msg_name = remote_addr.as_ptr().cast_mut().cast();
+    msg.msg_namelen = remote_addr.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = cmsg_space as _;
+    // SAFETY: `cmsg_buf` is aligned for `cmsghdr` and has room for a control message carrying a `T`.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), data);
+    }
+    // SAFETY: every pointer in `msg` is valid for the length next to it.
+    let len = unsafe { libc::sendmsg(fd.as_raw_fd(), &msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// Send every buffer of `bufs` as a datagram on the connected socket `fd` by a single `sendmmsg`.
+///
+/// Returns the number of datagrams sent, which is less than `bufs.len()` if the kernel stopped early.
+/// An error is only returned if not even the first datagram was sent.
+#[cfg(target_os = "linux")]
+pub fn send_batch(fd: BorrowedFd<'_>, bufs: &[&[u8]]) -> io::Result<usize> {
+    let mut iovs = bufs
+        .iter()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut msgs = iovs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: an all-zero `msghdr` is valid and carries no address or control messages.
+            let mut msg_hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+            msg_hdr.msg_iov = iov;
+            msg_hdr.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr,
+                msg_len: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    let vlen = libc::c_uint::try_from(msgs.len()).unwrap_or(libc::c_uint::MAX);
+    // SAFETY: every `mmsghdr` points at an `iovec` in `iovs`, which points at a buffer of `bufs`,
+    // and all of them outlive the call; `sendmmsg` only reads from the buffers.
+    let sent = unsafe { libc::sendmmsg(fd.as_raw_fd(), msgs.as_mut_ptr(), vlen, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+fn std_to_in_addr(ip: &Ipv4Addr) -> libc::in_addr {
+    // Convert from host byte order to big-endian.
+    libc::in_addr {
+        s_addr: u32::from(*ip).to_be(),
+    }
+}
+
+fn std_to_in6_addr(ip: &Ipv6Addr) -> libc::in6_addr {
+    libc::in6_addr {
+        s6_addr: ip.octets(),
+    }
+}